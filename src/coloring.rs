@@ -0,0 +1,521 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+
+use serde::Deserialize;
+
+use crate::mesh::Mesh;
+use crate::vec3::{self, Vec3};
+
+/// Positions are rounded to this many steps per unit when matching
+/// edges and sizes
+const PRECISION: f64 = 1e6;
+
+/// Tolerance for comparing positions of polygons mapped by a symmetry
+const TOLERANCE: f64 = 1e-9;
+
+/// A property of each tower that can choose its color
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ColorAttribute {
+    /// Area of the base polygon
+    Size,
+    /// Height of the tower from the bottom of the base to the top
+    Height,
+    /// Index of the seed the tower's face belongs to
+    Seed,
+    /// Distance from the origin to the centroid of the base in each copy
+    /// of the tiling. Copies of a tower get different colors, which needs
+    /// flattened instancing unless each tower is only in one copy.
+    Distance,
+    /// Towers whose faces are in the same orbit under the symmetries of
+    /// the tiling share a color
+    Orbit
+}
+
+impl ColorAttribute {
+    /// Whether the values are categories rather than quantities
+    pub fn is_discrete(&self) -> bool {
+        matches!(self, ColorAttribute::Seed | ColorAttribute::Orbit)
+    }
+}
+
+/// Write COLOR_0 vertex colors computed from a tower attribute. Viewers
+/// multiply these by the base color of the material, so a white material
+/// shows the colors as-is.
+#[derive(Deserialize)]
+pub struct VertexColors {
+    pub attribute: ColorAttribute,
+    /// Linear RGB colors. Discrete attributes cycle through the palette,
+    /// continuous ones are mapped to a gradient through the palette from
+    /// the smallest value to the largest.
+    #[serde(default = "default_palette")]
+    pub palette: Vec<Vec3>
+}
+
+fn default_palette() -> Vec<Vec3> {
+    vec![
+        (0.05, 0.05, 0.4),
+        (0.0, 0.35, 0.5),
+        (0.1, 0.6, 0.3),
+        (0.7, 0.75, 0.1),
+        (0.95, 0.5, 0.05)
+    ]
+}
+
+impl VertexColors {
+    /// Get a color for each tower from its attribute value. Discrete
+    /// values must be non-negative integers.
+    pub fn colors(&self, values: &[f64]) -> Vec<Vec3> {
+        if self.palette.is_empty() {
+            return vec![(1.0, 1.0, 1.0); values.len()];
+        }
+
+        if self.attribute.is_discrete() {
+            return values.iter()
+                .map(|value| self.palette[*value as usize % self.palette.len()])
+                .collect();
+        }
+
+        let min = values.iter().copied().fold(f64::INFINITY, f64::min);
+        let max = values.iter().copied().fold(-f64::INFINITY, f64::max);
+        values.iter()
+            .map(|value| {
+                let t = if max > min {
+                    (value - min) / (max - min)
+                } else {
+                    0.0
+                };
+                self.gradient(t)
+            })
+            .collect()
+    }
+
+    /// Interpolate through the palette with t from 0 to 1
+    fn gradient(&self, t: f64) -> Vec3 {
+        let last = self.palette.len() - 1;
+        if last == 0 {
+            return self.palette[0];
+        }
+        let position = t.clamp(0.0, 1.0) * last as f64;
+        let index = (position.floor() as usize).min(last - 1);
+        vec3::lerp(self.palette[index], self.palette[index + 1], position - index as f64)
+    }
+}
+
+/// How to assign materials to the faces of the tiling automatically
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ColoringMethod {
+    /// Faces with the same area share a material, from the smallest
+    /// area to the largest
+    Size,
+    /// Faces in the same orbit under the symmetries of the tiling share
+    /// a material
+    Orbit,
+    /// Faces that share an edge get different materials, including
+    /// across copies of the tiling
+    Graph
+}
+
+/// Assign a material to each face of the tiling instead of using the
+/// materials of the seed faces
+#[derive(Deserialize)]
+pub struct AutoMaterials {
+    pub method: ColoringMethod,
+    /// Indices of the materials to use, in order. Defaults to every
+    /// material.
+    pub palette: Option<Vec<usize>>
+}
+
+impl AutoMaterials {
+    /// Choose a material for each face, and get any warnings about the
+    /// result. translations are the lattice translations of the tiling in
+    /// world space.
+    pub fn assign(
+        &self,
+        faces: &[Vec<Vec3>],
+        translations: [Vec3; 2],
+        material_count: usize
+    ) -> (Vec<usize>, Vec<String>) {
+        let mut warnings = Vec::new();
+        let classes = match self.method {
+            ColoringMethod::Size => {
+                let areas: Vec<f64> = faces.iter()
+                    .map(|face| Mesh::compute_area(face))
+                    .collect();
+                size_classes(&areas)
+            },
+            ColoringMethod::Orbit => orbit_classes(faces, translations),
+            ColoringMethod::Graph => {
+                let adjacency = periodic_adjacency(faces, translations);
+                if adjacency.iter().any(|(a, b)| a == b) {
+                    warnings.push(String::from(
+                        "some faces share an edge with their own copies, \
+                        so neighboring faces can't always get different materials"
+                    ));
+                }
+                graph_coloring(faces.len(), &adjacency)
+            }
+        };
+
+        let palette = self.palette.clone()
+            .unwrap_or_else(|| (0..material_count).collect());
+        if palette.is_empty() {
+            return (vec![0; faces.len()], warnings);
+        }
+
+        let class_count = classes.iter().max().map_or(0, |class| class + 1);
+        if class_count > palette.len() {
+            warnings.push(format!(
+                "{} materials are needed but the palette only has {}, \
+                so some will repeat",
+                class_count,
+                palette.len()
+            ));
+        }
+        let materials = classes.iter()
+            .map(|class| palette[class % palette.len()])
+            .collect();
+        (materials, warnings)
+    }
+}
+
+/// Number the distinct areas from smallest to largest and get the
+/// number of each area
+pub fn size_classes(areas: &[f64]) -> Vec<usize> {
+    let key = |area: f64| (area * PRECISION).round() as i64;
+    let mut sizes: Vec<i64> = areas.iter().map(|area| key(*area)).collect();
+    sizes.sort();
+    sizes.dedup();
+    areas.iter()
+        .map(|area| sizes.binary_search(&key(*area)).unwrap())
+        .collect()
+}
+
+/// An isometry of the plane that mirrors y if mirror is set, rotates
+/// about z, then moves the point from to the point to
+#[derive(Clone, Copy)]
+struct Isometry {
+    mirror: bool,
+    rotation: f64,
+    from: Vec3,
+    to: Vec3
+}
+
+impl Isometry {
+    fn apply(&self, (x, y, z): Vec3) -> Vec3 {
+        let (fx, fy, _) = self.from;
+        let (tx, ty, _) = self.to;
+        let dx = x - fx;
+        let dy = if self.mirror { fy - y } else { y - fy };
+        let (sin, cos) = self.rotation.sin_cos();
+        (tx + cos * dx - sin * dy, ty + sin * dx + cos * dy, z)
+    }
+}
+
+/// Find every isometry that maps polygon a onto polygon b. Mirroring
+/// reverses the winding, so b's vertices are matched in reverse order.
+fn isometries_between(a: &[Vec3], b: &[Vec3]) -> Vec<Isometry> {
+    let n = a.len();
+    if n != b.len() || n == 0 {
+        return Vec::new();
+    }
+
+    let from = Mesh::compute_centroid(a);
+    let to = Mesh::compute_centroid(b);
+    let mut result = Vec::new();
+    for mirror in [false, true] {
+        for shift in 0..n {
+            let target = |i: usize| if mirror { (shift + n - i) % n } else { (shift + i) % n };
+            let unrotated = Isometry { mirror, rotation: 0.0, from, to: from };
+            let (ax, ay, _) = vec3::sub(unrotated.apply(a[0]), from);
+            let (bx, by, _) = vec3::sub(b[target(0)], to);
+            let isometry = Isometry {
+                rotation: (ax * by - ay * bx).atan2(ax * bx + ay * by),
+                to,
+                ..unrotated
+            };
+            let matches = (0..n).all(|i| {
+                vec3::length(vec3::sub(isometry.apply(a[i]), b[target(i)])) < TOLERANCE
+            });
+            if matches {
+                result.push(isometry);
+            }
+        }
+    }
+    result
+}
+
+/// Number the orbits of the polygons under the symmetries of the
+/// periodic tiling they form in order of first appearance, and get the
+/// orbit of each polygon. translations are the lattice translations that
+/// repeat the polygons to fill the plane.
+///
+/// Every symmetry maps the first polygon onto a copy of some congruent
+/// polygon, so after undoing that lattice translation it is one of the
+/// isometries between the first polygon and a polygon in the list. Each
+/// candidate is kept if it maps every polygon onto a copy of a polygon.
+pub fn orbit_classes(polygons: &[Vec<Vec3>], translations: [Vec3; 2]) -> Vec<usize> {
+    if polygons.is_empty() {
+        return Vec::new();
+    }
+
+    // Identify copies of a polygon by the position of their centroid
+    // within a cell of the lattice
+    let [(ax, ay, _), (bx, by, _)] = translations;
+    let determinant = ax * by - ay * bx;
+    let steps = PRECISION as i64;
+    let cell_key = |(x, y, _): Vec3| {
+        let s = (x * by - y * bx) / determinant;
+        let t = (ax * y - ay * x) / determinant;
+        (
+            ((s * PRECISION).round() as i64).rem_euclid(steps),
+            ((t * PRECISION).round() as i64).rem_euclid(steps)
+        )
+    };
+    let centroids: Vec<Vec3> = polygons.iter()
+        .map(|polygon| Mesh::compute_centroid(polygon))
+        .collect();
+    let by_cell: HashMap<(i64, i64), usize> = centroids.iter()
+        .enumerate()
+        .map(|(i, centroid)| (cell_key(*centroid), i))
+        .collect();
+
+    // The polygon that a copy of the image of a polygon is, if any
+    let find_image = |isometry: &Isometry, polygon: &[Vec3]| -> Option<usize> {
+        let image: Vec<Vec3> = polygon.iter().map(|p| isometry.apply(*p)).collect();
+        let centroid = Mesh::compute_centroid(&image);
+        let j = *by_cell.get(&cell_key(centroid))?;
+        let offset = vec3::sub(centroids[j], centroid);
+        let matches = image.len() == polygons[j].len() && image.iter().all(|p| {
+            let moved = vec3::add(*p, offset);
+            polygons[j].iter().any(|q| vec3::length(vec3::sub(moved, *q)) < TOLERANCE)
+        });
+        if matches {
+            Some(j)
+        } else {
+            None
+        }
+    };
+
+    let mut orbits: Vec<usize> = (0..polygons.len()).collect();
+    for target in polygons {
+        for isometry in isometries_between(&polygons[0], target) {
+            let images: Option<Vec<usize>> = polygons.iter()
+                .map(|polygon| find_image(&isometry, polygon))
+                .collect();
+            for (i, j) in images.into_iter().flatten().enumerate() {
+                // Merge the orbits of i and j, keeping the smaller label
+                let (keep, replace) = (orbits[i].min(orbits[j]), orbits[i].max(orbits[j]));
+                for orbit in orbits.iter_mut().filter(|orbit| **orbit == replace) {
+                    *orbit = keep;
+                }
+            }
+        }
+    }
+
+    // Renumber the labels in order of first appearance
+    let mut numbers = HashMap::new();
+    orbits.iter()
+        .map(|orbit| {
+            let next = numbers.len();
+            *numbers.entry(*orbit).or_insert(next)
+        })
+        .collect()
+}
+
+/// Find the pairs of polygons that share an edge, where one of them may
+/// be moved by a lattice translation. A polygon that shares an edge with
+/// its own copy is paired with itself.
+pub fn periodic_adjacency(polygons: &[Vec<Vec3>], translations: [Vec3; 2]) -> Vec<(usize, usize)> {
+    let key = |(x, y, _): Vec3| ((x * PRECISION).round() as i64, (y * PRECISION).round() as i64);
+    let mut edge_owners = HashMap::new();
+    for (i, polygon) in polygons.iter().enumerate() {
+        for (start, end) in edges(polygon) {
+            edge_owners.insert((key(start), key(end)), i);
+        }
+    }
+
+    let [a, b] = translations;
+    let mut result = Vec::new();
+    for (i, polygon) in polygons.iter().enumerate() {
+        for (start, end) in edges(polygon) {
+            for da in -1..=1 {
+                for db in -1..=1 {
+                    let offset = vec3::add(vec3::scale(a, da as f64), vec3::scale(b, db as f64));
+                    // A neighbor runs along the same edge the other way
+                    let twin = (key(vec3::add(end, offset)), key(vec3::add(start, offset)));
+                    if let Some(&j) = edge_owners.get(&twin) {
+                        result.push((i.min(j), i.max(j)));
+                    }
+                }
+            }
+        }
+    }
+    result.sort();
+    result.dedup();
+    result
+}
+
+/// The (start, end) positions of each edge of a polygon
+fn edges(polygon: &[Vec3]) -> impl Iterator<Item = (Vec3, Vec3)> + '_ {
+    let n = polygon.len();
+    (0..n).map(move |i| (polygon[i], polygon[(i + 1) % n]))
+}
+
+/// Color a graph so neighbors get different colors with the DSatur
+/// heuristic: repeatedly color the vertex with the most distinct colors
+/// among its neighbors, using the smallest color available. Self-loops
+/// are ignored.
+pub fn graph_coloring(count: usize, edges: &[(usize, usize)]) -> Vec<usize> {
+    let mut neighbors = vec![Vec::new(); count];
+    for &(a, b) in edges.iter().filter(|(a, b)| a != b) {
+        neighbors[a].push(b);
+        neighbors[b].push(a);
+    }
+
+    let mut colors: Vec<Option<usize>> = vec![None; count];
+    let neighbor_colors = |vertex: usize, colors: &[Option<usize>]| -> HashSet<usize> {
+        neighbors[vertex].iter()
+            .filter_map(|neighbor| colors[*neighbor])
+            .collect()
+    };
+    for _ in 0..count {
+        let vertex = (0..count)
+            .filter(|vertex| colors[*vertex].is_none())
+            .max_by_key(|vertex| (
+                neighbor_colors(*vertex, &colors).len(),
+                neighbors[*vertex].len(),
+                Reverse(*vertex)
+            ))
+            .unwrap();
+        let used = neighbor_colors(vertex, &colors);
+        colors[vertex] = (0..).find(|color| !used.contains(color));
+    }
+
+    colors.into_iter().map(Option::unwrap).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn black_to_white(attribute: ColorAttribute) -> VertexColors {
+        VertexColors {
+            attribute,
+            palette: vec![(0.0, 0.0, 0.0), (0.5, 0.5, 0.5), (1.0, 1.0, 1.0)]
+        }
+    }
+
+    #[test]
+    fn continuous_values_use_a_gradient() {
+        let colors = black_to_white(ColorAttribute::Height).colors(&[2.0, 4.0, 3.0, 3.5]);
+        assert_eq!(colors, vec![
+            (0.0, 0.0, 0.0),
+            (1.0, 1.0, 1.0),
+            (0.5, 0.5, 0.5),
+            (0.75, 0.75, 0.75)
+        ]);
+
+        let constant = black_to_white(ColorAttribute::Size).colors(&[1.0, 1.0]);
+        assert_eq!(constant, vec![(0.0, 0.0, 0.0); 2]);
+    }
+
+    #[test]
+    fn discrete_values_cycle() {
+        let colors = black_to_white(ColorAttribute::Orbit).colors(&[0.0, 1.0, 2.0, 3.0]);
+        assert_eq!(colors, vec![
+            (0.0, 0.0, 0.0),
+            (0.5, 0.5, 0.5),
+            (1.0, 1.0, 1.0),
+            (0.0, 0.0, 0.0)
+        ]);
+    }
+
+    fn square(x: f64, y: f64, size: f64) -> Vec<Vec3> {
+        vec![(x, y, 0.0), (x + size, y, 0.0), (x + size, y + size, 0.0), (x, y + size, 0.0)]
+    }
+
+    #[test]
+    fn sizes() {
+        let faces = [square(0.0, 0.0, 2.0), square(5.0, 5.0, 1.0), square(0.0, 0.0, 1.0)];
+        let areas: Vec<f64> = faces.iter().map(|face| Mesh::compute_area(face)).collect();
+        assert_eq!(size_classes(&areas), vec![1, 0, 0]);
+    }
+
+    #[test]
+    fn orbits_under_symmetries() {
+        // Rows of three squares and a 2x1 rectangle. The middle square
+        // is congruent to the others but no symmetry maps it to them.
+        let rectangle = vec![(3.0, 0.0, 0.0), (5.0, 0.0, 0.0), (5.0, 1.0, 0.0), (3.0, 1.0, 0.0)];
+        let faces = vec![
+            square(0.0, 0.0, 1.0),
+            square(1.0, 0.0, 1.0),
+            square(2.0, 0.0, 1.0),
+            rectangle
+        ];
+        let translations = [(5.0, 0.0, 0.0), (0.0, 1.0, 0.0)];
+        assert_eq!(orbit_classes(&faces, translations), vec![0, 1, 0, 2]);
+
+        let auto = AutoMaterials {
+            method: ColoringMethod::Orbit,
+            palette: None
+        };
+        let (materials, warnings) = auto.assign(&faces, translations, 2);
+        assert_eq!(materials, vec![0, 1, 0, 0]);
+        assert_eq!(warnings.len(), 1);
+
+        // Translations finer than the given lattice are symmetries too
+        let checkerboard = vec![
+            square(0.0, 0.0, 1.0),
+            square(1.0, 0.0, 1.0),
+            square(0.0, 1.0, 1.0),
+            square(1.0, 1.0, 1.0)
+        ];
+        let translations = [(2.0, 0.0, 0.0), (0.0, 2.0, 0.0)];
+        assert_eq!(orbit_classes(&checkerboard, translations), vec![0; 4]);
+    }
+
+    #[test]
+    fn mirrored_polygons_have_isometries() {
+        let triangle = vec![(0.0, 0.0, 0.0), (3.0, 0.0, 0.0), (0.0, 1.0, 0.0)];
+        // Mirrored across x = 0, then wound counterclockwise again
+        let mirrored = vec![(0.0, 0.0, 0.0), (0.0, 1.0, 0.0), (-3.0, 0.0, 0.0)];
+        let isometries = isometries_between(&triangle, &mirrored);
+        assert_eq!(isometries.len(), 1);
+        assert!(isometries[0].mirror);
+        assert!(isometries_between(&triangle, &triangle).iter().all(|isometry| !isometry.mirror));
+    }
+
+    #[test]
+    fn checkerboard_from_periodic_adjacency() {
+        // 2x2 squares repeated every 2 units
+        let faces = vec![
+            square(0.0, 0.0, 1.0),
+            square(1.0, 0.0, 1.0),
+            square(0.0, 1.0, 1.0),
+            square(1.0, 1.0, 1.0)
+        ];
+        let translations = [(2.0, 0.0, 0.0), (0.0, 2.0, 0.0)];
+        let adjacency = periodic_adjacency(&faces, translations);
+        assert_eq!(adjacency, vec![(0, 1), (0, 2), (1, 3), (2, 3)]);
+
+        let auto = AutoMaterials {
+            method: ColoringMethod::Graph,
+            palette: Some(vec![4, 7])
+        };
+        assert_eq!(auto.assign(&faces, translations, 8), (vec![4, 7, 7, 4], Vec::new()));
+
+        // A single square is next to its own copies
+        let single = periodic_adjacency(&faces[..1], [(1.0, 0.0, 0.0), (0.0, 1.0, 0.0)]);
+        assert_eq!(single, vec![(0, 0)]);
+    }
+
+    #[test]
+    fn odd_cycle_needs_three_colors() {
+        let edges = [(0, 1), (1, 2), (2, 3), (3, 4), (0, 4)];
+        let colors = graph_coloring(5, &edges);
+        for (a, b) in edges {
+            assert_ne!(colors[a], colors[b]);
+        }
+        assert_eq!(colors.iter().max(), Some(&2));
+    }
+}
@@ -0,0 +1,283 @@
+/// A formula in x and y such as "0.5 + 0.5 * sin(x / 3) * cos(y / 3)".
+///
+/// Supports numbers, x, y, pi, the operators + - * / ^ and parentheses,
+/// and the functions in FUNCTIONS.
+#[derive(Debug, PartialEq)]
+pub enum Expression {
+    Number(f64),
+    X,
+    Y,
+    Negate(Box<Expression>),
+    Binary(char, Box<Expression>, Box<Expression>),
+    Call(&'static str, Vec<Expression>)
+}
+
+/// Functions that can be called, with their number of arguments
+const FUNCTIONS: [(&str, usize); 11] = [
+    ("sin", 1),
+    ("cos", 1),
+    ("tan", 1),
+    ("sqrt", 1),
+    ("abs", 1),
+    ("exp", 1),
+    ("ln", 1),
+    ("floor", 1),
+    ("min", 2),
+    ("max", 2),
+    ("atan2", 2)
+];
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(f64),
+    Name(String),
+    Symbol(char)
+}
+
+impl Expression {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut parser = Parser {
+            tokens: tokenize(text)?,
+            position: 0
+        };
+        let expression = parser.sum()?;
+        match parser.peek() {
+            None => Ok(expression),
+            Some(token) => Err(format!("unexpected {:?}", token))
+        }
+    }
+
+    pub fn evaluate(&self, x: f64, y: f64) -> f64 {
+        match self {
+            Expression::Number(value) => *value,
+            Expression::X => x,
+            Expression::Y => y,
+            Expression::Negate(operand) => -operand.evaluate(x, y),
+            Expression::Binary(operator, left, right) => {
+                let a = left.evaluate(x, y);
+                let b = right.evaluate(x, y);
+                match operator {
+                    '+' => a + b,
+                    '-' => a - b,
+                    '*' => a * b,
+                    '/' => a / b,
+                    _ => a.powf(b)
+                }
+            },
+            Expression::Call(name, arguments) => {
+                let values: Vec<f64> = arguments.iter()
+                    .map(|argument| argument.evaluate(x, y))
+                    .collect();
+                match (*name, values.as_slice()) {
+                    ("sin", [a]) => a.sin(),
+                    ("cos", [a]) => a.cos(),
+                    ("tan", [a]) => a.tan(),
+                    ("sqrt", [a]) => a.sqrt(),
+                    ("abs", [a]) => a.abs(),
+                    ("exp", [a]) => a.exp(),
+                    ("ln", [a]) => a.ln(),
+                    ("floor", [a]) => a.floor(),
+                    ("min", [a, b]) => a.min(*b),
+                    ("max", [a, b]) => a.max(*b),
+                    ("atan2", [a, b]) => a.atan2(*b),
+                    _ => unreachable!("arguments are checked when parsing")
+                }
+            }
+        }
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_digit() || c == '.' {
+            let mut number = String::new();
+            while let Some(&c) = chars.peek() {
+                if !(c.is_ascii_digit() || c == '.') {
+                    break;
+                }
+                number.push(c);
+                chars.next();
+            }
+            let value = number.parse()
+                .map_err(|_| format!("invalid number {}", number))?;
+            tokens.push(Token::Number(value));
+        } else if c.is_ascii_alphabetic() {
+            let mut name = String::new();
+            while let Some(&c) = chars.peek() {
+                if !(c.is_ascii_alphanumeric() || c == '_') {
+                    break;
+                }
+                name.push(c);
+                chars.next();
+            }
+            tokens.push(Token::Name(name));
+        } else if "+-*/^(),".contains(c) {
+            tokens.push(Token::Symbol(c));
+            chars.next();
+        } else {
+            return Err(format!("unexpected character '{}'", c));
+        }
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent parser. ^ binds tightest and is right associative,
+/// so -x^2 is -(x^2).
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    /// Consume the symbol if it's next
+    fn accept(&mut self, symbol: char) -> bool {
+        if self.peek() == Some(&Token::Symbol(symbol)) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, symbol: char) -> Result<(), String> {
+        if self.accept(symbol) {
+            Ok(())
+        } else {
+            Err(format!("expected '{}'", symbol))
+        }
+    }
+
+    fn sum(&mut self) -> Result<Expression, String> {
+        let mut result = self.product()?;
+        loop {
+            let operator = if self.accept('+') {
+                '+'
+            } else if self.accept('-') {
+                '-'
+            } else {
+                return Ok(result);
+            };
+            result = Expression::Binary(operator, Box::new(result), Box::new(self.product()?));
+        }
+    }
+
+    fn product(&mut self) -> Result<Expression, String> {
+        let mut result = self.unary()?;
+        loop {
+            let operator = if self.accept('*') {
+                '*'
+            } else if self.accept('/') {
+                '/'
+            } else {
+                return Ok(result);
+            };
+            result = Expression::Binary(operator, Box::new(result), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expression, String> {
+        if self.accept('-') {
+            Ok(Expression::Negate(Box::new(self.unary()?)))
+        } else {
+            self.power()
+        }
+    }
+
+    fn power(&mut self) -> Result<Expression, String> {
+        let base = self.atom()?;
+        if self.accept('^') {
+            Ok(Expression::Binary('^', Box::new(base), Box::new(self.unary()?)))
+        } else {
+            Ok(base)
+        }
+    }
+
+    fn atom(&mut self) -> Result<Expression, String> {
+        match self.next() {
+            Some(Token::Number(value)) => Ok(Expression::Number(value)),
+            Some(Token::Symbol('(')) => {
+                let expression = self.sum()?;
+                self.expect(')')?;
+                Ok(expression)
+            },
+            Some(Token::Name(name)) => match name.as_str() {
+                "x" => Ok(Expression::X),
+                "y" => Ok(Expression::Y),
+                "pi" => Ok(Expression::Number(std::f64::consts::PI)),
+                _ => self.call(&name)
+            },
+            Some(token) => Err(format!("unexpected {:?}", token)),
+            None => Err("unexpected end of expression".to_string())
+        }
+    }
+
+    fn call(&mut self, name: &str) -> Result<Expression, String> {
+        let (function, arity) = FUNCTIONS.iter()
+            .find(|(function, _)| *function == name)
+            .ok_or_else(|| format!("unknown name {}", name))?;
+
+        self.expect('(')?;
+        let mut arguments = vec![self.sum()?];
+        while self.accept(',') {
+            arguments.push(self.sum()?);
+        }
+        self.expect(')')?;
+
+        if arguments.len() != *arity {
+            return Err(format!(
+                "{} takes {} arguments, got {}", function, arity, arguments.len()
+            ));
+        }
+        Ok(Expression::Call(function, arguments))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn evaluate(text: &str, x: f64, y: f64) -> f64 {
+        Expression::parse(text).unwrap().evaluate(x, y)
+    }
+
+    #[test]
+    fn precedence_and_associativity() {
+        assert_eq!(evaluate("1 + 2 * 3", 0.0, 0.0), 7.0);
+        assert_eq!(evaluate("(1 + 2) * 3", 0.0, 0.0), 9.0);
+        assert_eq!(evaluate("8 / 4 / 2", 0.0, 0.0), 1.0);
+        assert_eq!(evaluate("2 ^ 3 ^ 2", 0.0, 0.0), 512.0);
+        assert_eq!(evaluate("-x^2", 3.0, 0.0), -9.0);
+        assert_eq!(evaluate("x - -y", 1.0, 2.0), 3.0);
+    }
+
+    #[test]
+    fn functions_and_constants() {
+        assert_eq!(evaluate("max(x, y) + min(x, y)", 1.0, 4.0), 5.0);
+        assert!((evaluate("sin(pi / 2)", 0.0, 0.0) - 1.0).abs() < 1e-12);
+        assert_eq!(evaluate("sqrt(x*x + y*y)", 3.0, 4.0), 5.0);
+    }
+
+    #[test]
+    fn invalid_expressions() {
+        assert!(Expression::parse("1 +").is_err());
+        assert!(Expression::parse("(x").is_err());
+        assert!(Expression::parse("x y").is_err());
+        assert!(Expression::parse("z").is_err());
+        assert!(Expression::parse("sin(x, y)").is_err());
+        assert!(Expression::parse("x % 2").is_err());
+    }
+}
@@ -0,0 +1,486 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
+
+use serde_json::Value;
+
+use crate::mesh::Mesh;
+use crate::vec3::{self, Vec3};
+
+const GLB_MAGIC: &[u8; 4] = b"glTF";
+const GLB_HEADER_LENGTH: usize = 12;
+const CHUNK_HEADER_LENGTH: usize = 8;
+const CHUNK_JSON: &[u8; 4] = b"JSON";
+const CHUNK_BIN: &[u8; 4] = b"BIN\0";
+
+/// Positions are rounded to this many steps per unit when merging
+/// vertices
+const WELD_PRECISION: f64 = 1e6;
+
+#[derive(Debug)]
+pub enum GlbError {
+    Io(String, io::Error),
+    /// The file is not a GLB file this reader understands
+    Format(String)
+}
+
+impl fmt::Display for GlbError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GlbError::Io(path, error) => write!(f, "could not read {}: {}", path, error),
+            GlbError::Format(message) => write!(f, "invalid GLB: {}", message)
+        }
+    }
+}
+
+fn format_error(message: impl Into<String>) -> GlbError {
+    GlbError::Format(message.into())
+}
+
+/// The geometry of one or more GLB files, such as ones written by
+/// Gltf::save()
+pub struct GlbContents {
+    /// The triangles of every node in the default scene of each file, in
+    /// world space, as one mesh. Vertices at the same position within a
+    /// primitive are merged so faces are connected, and each face keeps
+    /// the material of its primitive.
+    pub mesh: Mesh,
+    /// The EXT_mesh_gpu_instancing translations of each instanced node,
+    /// as stored in the file. The copies are not added to the mesh.
+    pub instance_translations: Vec<Vec<Vec3>>,
+    // (from, to) vertices of every half edge in the mesh, see
+    // Reader::add_mesh()
+    directed_edges: HashSet<(usize, usize)>
+}
+
+impl GlbContents {
+    pub fn new() -> Self {
+        Self {
+            mesh: Mesh::new(),
+            instance_translations: Vec::new(),
+            directed_edges: HashSet::new()
+        }
+    }
+
+    /// Read a GLB file and add its geometry
+    pub fn add_file(&mut self, path: &str) -> Result<(), GlbError> {
+        let bytes = fs::read(path).map_err(|error| GlbError::Io(String::from(path), error))?;
+        self.add_glb(&bytes)
+    }
+
+    /// Parse a GLB file with an embedded binary buffer and add its
+    /// geometry. Only triangle primitives are supported, and sparse
+    /// accessors are not.
+    pub fn add_glb(&mut self, bytes: &[u8]) -> Result<(), GlbError> {
+        let (json, binary) = split_chunks(bytes)?;
+        let reader = Reader { json: &json, binary };
+
+        let scene = json["scene"].as_u64().unwrap_or(0) as usize;
+        let roots = reader.indices(&json["scenes"][scene]["nodes"]);
+        let mut stack: Vec<(usize, Vec<Transform>)> = roots.into_iter()
+            .map(|node| (node, Vec::new()))
+            .collect();
+        while let Some((node_index, parents)) = stack.pop() {
+            let node = &json["nodes"][node_index];
+            if node.is_null() {
+                return Err(format_error(format!("missing node {}", node_index)));
+            }
+
+            // The node's own transform is applied first
+            let mut transforms = vec![Transform::from_node(node)?];
+            transforms.extend(parents);
+
+            for child in reader.indices(&node["children"]) {
+                stack.push((child, transforms.clone()));
+            }
+
+            if let Some(mesh) = node["mesh"].as_u64() {
+                reader.add_mesh(mesh as usize, &transforms, &mut self.mesh, &mut self.directed_edges)?;
+            }
+
+            let instancing = &node["extensions"]["EXT_mesh_gpu_instancing"];
+            if let Some(translation) = instancing["attributes"]["TRANSLATION"].as_u64() {
+                let translations = reader.read_accessor(translation as usize)?
+                    .into_iter()
+                    .map(|t| (t[0], t[1], t[2]))
+                    .collect();
+                self.instance_translations.push(translations);
+            }
+        }
+
+        self.mesh.compute_face_normals()
+            .map_err(|error| format_error(error.to_string()))
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, GlbError> {
+    bytes.get(offset..offset + 4)
+        .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+        .ok_or_else(|| format_error("unexpected end of file"))
+}
+
+/// Check the header and get the JSON and BIN chunks
+fn split_chunks(bytes: &[u8]) -> Result<(Value, &[u8]), GlbError> {
+    if bytes.get(0..4) != Some(GLB_MAGIC) {
+        return Err(format_error("not a GLB file"));
+    }
+    let version = read_u32(bytes, 4)?;
+    if version != 2 {
+        return Err(format_error(format!("unsupported version {}", version)));
+    }
+    let length = read_u32(bytes, 8)? as usize;
+    if length > bytes.len() {
+        return Err(format_error("file is shorter than its header says"));
+    }
+
+    let mut json = None;
+    let mut binary: &[u8] = &[];
+    let mut offset = GLB_HEADER_LENGTH;
+    while offset < length {
+        let chunk_length = read_u32(bytes, offset)? as usize;
+        let chunk_type = bytes.get(offset + 4..offset + CHUNK_HEADER_LENGTH)
+            .ok_or_else(|| format_error("unexpected end of file"))?;
+        let start = offset + CHUNK_HEADER_LENGTH;
+        let data = bytes.get(start..start + chunk_length)
+            .ok_or_else(|| format_error("chunk runs past the end of the file"))?;
+        if chunk_type == CHUNK_JSON {
+            json = Some(serde_json::from_slice(data)
+                .map_err(|error| format_error(error.to_string()))?);
+        } else if chunk_type == CHUNK_BIN {
+            binary = data;
+        }
+        offset = start + chunk_length;
+    }
+
+    let json = json.ok_or_else(|| format_error("no JSON chunk"))?;
+    Ok((json, binary))
+}
+
+/// A node's translation, rotation and scale
+#[derive(Clone)]
+struct Transform {
+    translation: Vec3,
+    // (x, y, z, w) quaternion
+    rotation: [f64; 4],
+    scale: Vec3
+}
+
+impl Transform {
+    fn from_node(node: &Value) -> Result<Self, GlbError> {
+        if !node["matrix"].is_null() {
+            return Err(format_error("node matrices are not supported"));
+        }
+
+        let numbers = |value: &Value, default: &[f64]| -> Vec<f64> {
+            value.as_array()
+                .map(|array| array.iter().filter_map(Value::as_f64).collect())
+                .unwrap_or_else(|| default.to_vec())
+        };
+        let t = numbers(&node["translation"], &[0.0, 0.0, 0.0]);
+        let r = numbers(&node["rotation"], &[0.0, 0.0, 0.0, 1.0]);
+        let s = numbers(&node["scale"], &[1.0, 1.0, 1.0]);
+        if t.len() != 3 || r.len() != 4 || s.len() != 3 {
+            return Err(format_error("malformed node transform"));
+        }
+
+        Ok(Self {
+            translation: (t[0], t[1], t[2]),
+            rotation: [r[0], r[1], r[2], r[3]],
+            scale: (s[0], s[1], s[2])
+        })
+    }
+
+    fn apply(&self, point: Vec3) -> Vec3 {
+        let (x, y, z) = point;
+        let (sx, sy, sz) = self.scale;
+        let scaled = (sx * x, sy * y, sz * z);
+
+        // v + 2w (q x v) + 2 q x (q x v)
+        let [qx, qy, qz, w] = self.rotation;
+        let q = (qx, qy, qz);
+        let qv = vec3::cross(q, scaled);
+        let rotated = vec3::add(
+            scaled,
+            vec3::add(vec3::scale(qv, 2.0 * w), vec3::scale(vec3::cross(q, qv), 2.0))
+        );
+
+        vec3::add(rotated, self.translation)
+    }
+}
+
+struct Reader<'a> {
+    json: &'a Value,
+    binary: &'a [u8]
+}
+
+impl Reader<'_> {
+    fn indices(&self, value: &Value) -> Vec<usize> {
+        value.as_array()
+            .map(|array| array.iter().filter_map(Value::as_u64).map(|x| x as usize).collect())
+            .unwrap_or_default()
+    }
+
+    /// Add the triangles of each primitive of a mesh. Vertices are only
+    /// merged within a primitive, since separate primitives (or copies of
+    /// a tower) can overlap. A half edge can only have one twin, so a
+    /// triangle that repeats an edge already in the mesh in the same
+    /// direction gets its own vertices instead. directed_edges holds the
+    /// (from, to) vertices of every edge added so far.
+    fn add_mesh(
+        &self,
+        mesh_index: usize,
+        transforms: &[Transform],
+        mesh: &mut Mesh,
+        directed_edges: &mut HashSet<(usize, usize)>
+    ) -> Result<(), GlbError> {
+        let primitives = self.json["meshes"][mesh_index]["primitives"].as_array()
+            .ok_or_else(|| format_error(format!("mesh {} has no primitives", mesh_index)))?;
+
+        for primitive in primitives {
+            const GLTF_TRIANGLES: u64 = 4;
+            if primitive["mode"].as_u64().unwrap_or(GLTF_TRIANGLES) != GLTF_TRIANGLES {
+                return Err(format_error("only triangle primitives are supported"));
+            }
+
+            let positions = primitive["attributes"]["POSITION"].as_u64()
+                .ok_or_else(|| format_error("primitive has no POSITION"))?;
+            let mut welded = HashMap::new();
+            let vertices: Vec<usize> = self.read_accessor(positions as usize)?
+                .into_iter()
+                .map(|p| {
+                    let position = transforms.iter()
+                        .fold((p[0], p[1], p[2]), |point, transform| transform.apply(point));
+                    let (x, y, z) = position;
+                    let key = (
+                        (x * WELD_PRECISION).round() as i64,
+                        (y * WELD_PRECISION).round() as i64,
+                        (z * WELD_PRECISION).round() as i64
+                    );
+                    *welded.entry(key).or_insert_with(|| mesh.add_vertex(position))
+                })
+                .collect();
+
+            let indices: Vec<usize> = match primitive["indices"].as_u64() {
+                Some(indices) => self.read_accessor(indices as usize)?
+                    .into_iter()
+                    .map(|index| index[0] as usize)
+                    .collect(),
+                None => (0..vertices.len()).collect()
+            };
+            if indices.iter().any(|index| *index >= vertices.len()) {
+                return Err(format_error("index out of range"));
+            }
+
+            mesh.material = primitive["material"].as_u64().map(|x| x as usize);
+            for triangle in indices.chunks_exact(3) {
+                let mut corners = [vertices[triangle[0]], vertices[triangle[1]], vertices[triangle[2]]];
+                // Merging vertices can collapse tiny triangles
+                if corners[0] == corners[1] || corners[1] == corners[2] || corners[0] == corners[2] {
+                    continue;
+                }
+
+                let edges = |corners: [usize; 3]| [0, 1, 2].map(|i| (corners[i], corners[(i + 1) % 3]));
+                if edges(corners).iter().any(|edge| directed_edges.contains(edge)) {
+                    corners = corners.map(|v| mesh.add_vertex(mesh.vertices[v].position));
+                }
+                directed_edges.extend(edges(corners));
+                mesh.add_face(&corners);
+            }
+        }
+        mesh.material = None;
+
+        Ok(())
+    }
+
+    /// Read the elements of an accessor as floats, applying normalization
+    fn read_accessor(&self, index: usize) -> Result<Vec<Vec<f64>>, GlbError> {
+        let accessor = &self.json["accessors"][index];
+        if accessor.is_null() {
+            return Err(format_error(format!("missing accessor {}", index)));
+        }
+        if !accessor["sparse"].is_null() {
+            return Err(format_error("sparse accessors are not supported"));
+        }
+
+        let view_index = accessor["bufferView"].as_u64()
+            .ok_or_else(|| format_error("accessor has no buffer view"))?;
+        let view = &self.json["bufferViews"][view_index as usize];
+        let components = match accessor["type"].as_str() {
+            Some("SCALAR") => 1,
+            Some("VEC2") => 2,
+            Some("VEC3") => 3,
+            Some("VEC4") => 4,
+            other => return Err(format_error(format!("unsupported accessor type {:?}", other)))
+        };
+        let component_type = accessor["componentType"].as_u64().unwrap_or(0);
+        let (component_size, normalizer): (usize, f64) = match component_type {
+            5120 => (1, i8::MAX as f64),
+            5121 => (1, u8::MAX as f64),
+            5122 => (2, i16::MAX as f64),
+            5123 => (2, u16::MAX as f64),
+            5125 => (4, u32::MAX as f64),
+            5126 => (4, 1.0),
+            _ => return Err(format_error(format!("unknown component type {}", component_type)))
+        };
+        let normalized = accessor["normalized"].as_bool().unwrap_or(false);
+
+        let element_size = components * component_size;
+        let stride = view["byteStride"].as_u64().map_or(element_size, |x| x as usize);
+        let start = view["byteOffset"].as_u64().unwrap_or(0) as usize
+            + accessor["byteOffset"].as_u64().unwrap_or(0) as usize;
+        let count = accessor["count"].as_u64().unwrap_or(0) as usize;
+        if count > 0 && start + (count - 1) * stride + element_size > self.binary.len() {
+            return Err(format_error(format!("accessor {} runs past the buffer", index)));
+        }
+
+        let read = |offset: usize| -> f64 {
+            let bytes = &self.binary[offset..offset + component_size];
+            let value = match component_type {
+                5120 => bytes[0] as i8 as f64,
+                5121 => bytes[0] as f64,
+                5122 => i16::from_le_bytes([bytes[0], bytes[1]]) as f64,
+                5123 => u16::from_le_bytes([bytes[0], bytes[1]]) as f64,
+                5125 => u32::from_le_bytes(bytes.try_into().unwrap()) as f64,
+                _ => f32::from_le_bytes(bytes.try_into().unwrap()) as f64
+            };
+            if normalized {
+                (value / normalizer).max(-1.0)
+            } else {
+                value
+            }
+        };
+
+        Ok((0..count)
+            .map(|i| {
+                (0..components)
+                    .map(|c| read(start + i * stride + c * component_size))
+                    .collect()
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gltf::Gltf;
+    use crate::mesh::Triangles;
+    use crate::variation::Instance;
+
+    fn parse_glb(bytes: &[u8]) -> Result<GlbContents, GlbError> {
+        let mut contents = GlbContents::new();
+        contents.add_glb(bytes)?;
+        Ok(contents)
+    }
+
+    /// A unit square split into two triangles that don't share vertices
+    fn square() -> Triangles {
+        Triangles {
+            positions: vec![
+                (0.0, 0.0, 0.0),
+                (1.0, 0.0, 0.0),
+                (1.0, 1.0, 0.0),
+                (0.0, 0.0, 0.0),
+                (1.0, 1.0, 0.0),
+                (0.0, 1.0, 0.0)
+            ],
+            normals: vec![(0.0, 0.0, 1.0); 6],
+            indices: vec![0, 1, 2, 3, 4, 5],
+            ..Triangles::new()
+        }
+    }
+
+    fn round_trip(quantize: bool) -> GlbContents {
+        let mut gltf = Gltf::new();
+        gltf.set_quantization(quantize);
+        let mesh = gltf.add_mesh(vec![(2, square())]);
+        let node = Instance {
+            rotation: 90f64.to_radians(),
+            ..Instance::at((10.0, 0.0, 1.0))
+        };
+        gltf.add_node("square", mesh, Some(node), Some(vec![
+            Instance::at((0.0, 0.0, 0.0)),
+            Instance::at((3.0, 0.0, 0.0))
+        ]));
+        parse_glb(&gltf.to_bytes()).unwrap()
+    }
+
+    #[test]
+    fn round_trip_merges_vertices() {
+        let contents = round_trip(false);
+        let mesh = &contents.mesh;
+        assert_eq!(mesh.vertices.len(), 4);
+        assert_eq!(mesh.faces.len(), 2);
+        assert!(mesh.faces.iter().all(|face| face.material == Some(2)));
+        // The diagonal is shared
+        assert_eq!(mesh.half_edges.iter().filter(|edge| edge.twin.is_some()).count(), 2);
+
+        // Rotated a quarter turn about z and moved
+        let expected = [(10.0, 0.0, 1.0), (10.0, 1.0, 1.0), (9.0, 1.0, 1.0), (9.0, 0.0, 1.0)];
+        for (vertex, expected) in mesh.vertices.iter().zip(expected) {
+            assert!(vec3::length(vec3::sub(vertex.position, expected)) < 1e-6);
+        }
+        let normal = mesh.faces[0].normal.unwrap();
+        assert!(vec3::length(vec3::sub(normal, (0.0, 0.0, 1.0))) < 1e-9);
+
+        assert_eq!(contents.instance_translations, vec![vec![(0.0, 0.0, 0.0), (3.0, 0.0, 0.0)]]);
+    }
+
+    #[test]
+    fn round_trip_quantized() {
+        let exact = round_trip(false);
+        let quantized = round_trip(true);
+        assert_eq!(quantized.mesh.vertices.len(), 4);
+        for (a, b) in exact.mesh.vertices.iter().zip(quantized.mesh.vertices.iter()) {
+            assert!(vec3::length(vec3::sub(a.position, b.position)) < 1e-4);
+        }
+    }
+
+    #[test]
+    fn primitives_are_welded_separately() {
+        // Two materials sharing the diagonal, and a repeated triangle
+        let mut second = square();
+        second.positions.truncate(3);
+        second.normals.truncate(3);
+        second.indices = vec![0, 1, 2, 0, 1, 2];
+        let mut gltf = Gltf::new();
+        let mesh = gltf.add_mesh(vec![(0, square()), (1, second)]);
+        gltf.add_node("squares", mesh, None, None);
+        let contents = parse_glb(&gltf.to_bytes()).unwrap();
+        let mesh = &contents.mesh;
+
+        assert_eq!(mesh.faces.len(), 4);
+        assert_eq!(mesh.vertices.len(), 4 + 3 + 3);
+
+        // Every directed edge appears once, so twins pair up both ways
+        for (i, edge) in mesh.half_edges.iter().enumerate() {
+            if let Some(twin) = edge.twin {
+                assert_eq!(mesh.half_edges[twin].twin, Some(i));
+            }
+        }
+    }
+
+    #[test]
+    fn files_are_combined() {
+        let mut gltf = Gltf::new();
+        let mesh = gltf.add_mesh(vec![(0, square())]);
+        gltf.add_node("square", mesh, None, None);
+        let bytes = gltf.to_bytes();
+
+        let mut contents = parse_glb(&bytes).unwrap();
+        contents.add_glb(&bytes).unwrap();
+        let mesh = &contents.mesh;
+        // Vertices are only merged within a primitive of one file
+        assert_eq!(mesh.faces.len(), 4);
+        assert_eq!(mesh.vertices.len(), 4 + 4);
+        assert!(mesh.faces.iter().all(|face| face.normal.is_some()));
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(matches!(parse_glb(b"PK\x03\x04"), Err(GlbError::Format(_))));
+        assert!(matches!(parse_glb(b"glTF\x01\0\0\0\x0c\0\0\0"), Err(GlbError::Format(_))));
+        assert!(matches!(GlbContents::new().add_file("does/not/exist.glb"), Err(GlbError::Io(_, _))));
+    }
+}
@@ -1,42 +1,218 @@
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::prelude::*;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
 
 use chrono::{Datelike, Utc};
+use serde::Deserialize;
 use serde_json::json;
 
+use crate::coloring::VertexColors;
 use crate::tiling::Material;
-use crate::mesh::Mesh;
-use crate::vec3::Vec3;
+use crate::mesh::Triangles;
+use crate::variation::Instance;
+use crate::vec3::{self, Vec3};
 
-const GLTF_FLOAT: u32 = 5126;
+const GLTF_BYTE: u32 = 5120;
+const GLTF_UNSIGNED_BYTE: u32 = 5121;
+const GLTF_SHORT: u32 = 5122;
+const GLTF_UNSIGNED_SHORT: u32 = 5123;
 const GLTF_UNSIGNED_INT: u32 = 5125;
+const GLTF_FLOAT: u32 = 5126;
 const GLTF_TARGET_ARRAY_BUFFER: u32 = 34962;
 const GLTF_TARGET_ELEMENT_ARRAY_BUFFER: u32 = 34963;
 
+/// How the repeated copies of the tiling are stored
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+pub enum InstancingMode {
+    /// One copy of the geometry, repeated with EXT_mesh_gpu_instancing
+    #[default]
+    Gpu,
+    /// Bake every copy into the geometry so any glTF 2.0 viewer can
+    /// render it
+    Flattened
+}
+
+/// How the towers are split into glTF nodes
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+pub enum NodeLayout {
+    /// As few nodes as possible
+    #[default]
+    Merged,
+    /// One node per tower, named after its seed, face and profile and
+    /// translated to the centroid of its base so tools can select and
+    /// animate individual towers
+    PerTower
+}
+
+/// File format for the output
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+pub enum OutputFormat {
+    /// Binary glTF with the buffer embedded
+    #[default]
+    Glb,
+    /// JSON .gltf file with the buffer in a .bin file next to it
+    GltfWithBin,
+    /// JSON .gltf file with the buffer embedded as a base64 data URI
+    GltfEmbedded
+}
+
+#[derive(Deserialize)]
+pub struct ExportOptions {
+    /// The file extension is replaced to match the format
+    #[serde(default)]
+    pub format: OutputFormat,
+    #[serde(default)]
+    pub instancing: InstancingMode,
+    #[serde(default)]
+    pub nodes: NodeLayout,
+    /// Merge the towers into one primitive per material
+    #[serde(default)]
+    pub merge_materials: bool,
+    /// Store one mesh for each group of congruent towers and place the
+    /// copies with instancing or node transforms. Only used with GPU
+    /// instancing.
+    #[serde(default)]
+    pub deduplicate: bool,
+    /// Write feature IDs and a property table describing each tower and
+    /// material with EXT_mesh_features and EXT_structural_metadata
+    #[serde(default)]
+    pub metadata: bool,
+    /// Write texture coordinates even if no material has textures
+    #[serde(default)]
+    pub uvs: bool,
+    /// World units per texture repeat
+    #[serde(default = "default_uv_scale")]
+    pub uv_scale: f64,
+    /// Store positions as 16-bit and normals as 8-bit integers with
+    /// KHR_mesh_quantization. Viewers without the extension can't load
+    /// the file.
+    #[serde(default)]
+    pub quantize: bool,
+    /// Color the towers by an attribute with COLOR_0
+    pub vertex_colors: Option<VertexColors>
+}
+
+fn default_uv_scale() -> f64 {
+    1.0
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        Self {
+            format: OutputFormat::default(),
+            instancing: InstancingMode::default(),
+            nodes: NodeLayout::default(),
+            merge_materials: false,
+            deduplicate: false,
+            metadata: false,
+            uvs: false,
+            uv_scale: default_uv_scale(),
+            quantize: false,
+            vertex_colors: None
+        }
+    }
+}
+
+/// A column of a property table for EXT_structural_metadata
+pub enum PropertyValues {
+    UInt32(Vec<u32>),
+    Float32(Vec<f32>),
+    String(Vec<String>)
+}
+
+impl PropertyValues {
+    fn len(&self) -> usize {
+        match self {
+            PropertyValues::UInt32(values) => values.len(),
+            PropertyValues::Float32(values) => values.len(),
+            PropertyValues::String(values) => values.len()
+        }
+    }
+
+    /// The class property definition for the schema
+    fn schema_json(&self) -> serde_json::Value {
+        match self {
+            PropertyValues::UInt32(_) => json!({
+                "type": "SCALAR",
+                "componentType": "UINT32"
+            }),
+            PropertyValues::Float32(_) => json!({
+                "type": "SCALAR",
+                "componentType": "FLOAT32"
+            }),
+            PropertyValues::String(_) => json!({
+                "type": "STRING"
+            })
+        }
+    }
+}
+
+/// A property table and the class describing its rows
+pub struct PropertyTable {
+    class: String,
+    count: usize,
+    // (name, property schema, property table JSON with buffer views)
+    properties: Vec<(String, serde_json::Value, serde_json::Value)>
+}
+
+impl PropertyTable {
+    fn to_json(&self) -> serde_json::Value {
+        let class_properties: serde_json::Map<String, serde_json::Value> = self.properties.iter()
+            .map(|(name, schema, _)| (name.clone(), schema.clone()))
+            .collect();
+        let table_properties: serde_json::Map<String, serde_json::Value> = self.properties.iter()
+            .map(|(name, _, values)| (name.clone(), values.clone()))
+            .collect();
+
+        json!({
+            "schema": {
+                "id": "tower_tiling",
+                "classes": {
+                    self.class.clone(): {
+                        "properties": class_properties
+                    }
+                }
+            },
+            "propertyTables": [
+                {
+                    "class": self.class,
+                    "count": self.count,
+                    "properties": table_properties
+                }
+            ]
+        })
+    }
+}
+
 pub struct BufferView {
     name: String,
     byte_offset: usize,
     byte_length: usize,
-    target: u32
-
-    // no padding needed since everything is a vec3 or u32
+    // Property table views have no target
+    target: Option<u32>,
+    // Only set when vertex attributes are padded
+    byte_stride: Option<usize>
 }
 
 impl BufferView {
-    pub fn after_offset(&self) -> usize {
-        self.byte_offset + self.byte_length
-    }
-
     pub fn to_json(&self) -> serde_json::Value {
-        json!({
+        let mut result = json!({
             "name": self.name,
             // In this case we're always using the embedded buffer
             "buffer": 0,
             "byteOffset": self.byte_offset,
-            "byteLength": self.byte_length,
-            "target": self.target
-        })
+            "byteLength": self.byte_length
+        });
+        if let Some(target) = self.target {
+            result["target"] = json!(target);
+        }
+        if let Some(byte_stride) = self.byte_stride {
+            result["byteStride"] = json!(byte_stride);
+        }
+
+        result
     }
 }
 
@@ -47,7 +223,9 @@ pub struct Accessor {
     component_type: u32,
     count: usize,
     min: Option<[f64; 3]>,
-    max: Option<[f64; 3]>
+    max: Option<[f64; 3]>,
+    // Integer components map to [-1, 1] or [0, 1]
+    normalized: bool
 }
 
 impl Accessor {
@@ -64,6 +242,9 @@ impl Accessor {
             result["min"] = json!(min);
             result["max"] = json!(max);
         }
+        if self.normalized {
+            result["normalized"] = json!(true);
+        }
 
         result
     }
@@ -72,37 +253,147 @@ impl Accessor {
 pub struct Primitive {
     material: usize,
     indices: usize,
-    attributes: HashMap<String, usize>
+    attributes: HashMap<String, usize>,
+    // Number of distinct feature IDs in _FEATURE_ID_0, if present
+    feature_count: Option<usize>
 }
 
 impl Primitive {
     pub fn to_json(&self) -> serde_json::Value {
-        json!({
+        let mut result = json!({
             "material": self.material,
             "attributes": self.attributes,
             "indices": self.indices
-        })
+        });
+        if let Some(feature_count) = self.feature_count {
+            result["extensions"] = json!({
+                "EXT_mesh_features": {
+                    "featureIds": [
+                        {
+                            "featureCount": feature_count,
+                            "attribute": 0,
+                            "propertyTable": 0
+                        }
+                    ]
+                }
+            });
+        }
+
+        result
     }
 }
 
 pub struct Instances {
     translation: usize,
+    // The rest are only included when the instances vary
+    rotation: Option<usize>,
+    scale: Option<usize>,
+    feature_id: Option<usize>,
+    // Number of distinct feature IDs in _FEATURE_ID_0, if present
+    feature_count: Option<usize>
 }
 
 impl Instances {
     pub fn to_json(&self) -> serde_json::Value {
+        let mut attributes = json!({
+            "TRANSLATION": self.translation
+        });
+        if let Some(rotation) = self.rotation {
+            attributes["ROTATION"] = json!(rotation);
+        }
+        if let Some(scale) = self.scale {
+            attributes["SCALE"] = json!(scale);
+        }
+        if let Some(feature_id) = self.feature_id {
+            attributes["_FEATURE_ID_0"] = json!(feature_id);
+        }
+
         json!({
-            "attributes": {
-                "TRANSLATION": self.translation
-            }
+            "attributes": attributes
         })
     }
 }
 
+pub struct Node {
+    name: String,
+    mesh: usize,
+    transform: Option<Instance>,
+    instances: Option<Instances>
+}
+
+impl Node {
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut result = json!({
+            "mesh": self.mesh,
+            "name": self.name
+        });
+        if let Some(transform) = &self.transform {
+            let (x, y, z) = transform.translation;
+            result["translation"] = json!([x, y, z]);
+            if transform.rotation != 0.0 {
+                result["rotation"] = json!(transform.quaternion());
+            }
+            if transform.scale != (1.0, 1.0, 1.0) {
+                let (sx, sy, sz) = transform.scale;
+                result["scale"] = json!([sx, sy, sz]);
+            }
+        }
+        if let Some(instances) = &self.instances {
+            result["extensions"] = json!({
+                "EXT_mesh_gpu_instancing": instances.to_json()
+            });
+            if let Some(feature_count) = instances.feature_count {
+                result["extensions"]["EXT_instance_features"] = json!({
+                    "featureIds": [
+                        {
+                            "featureCount": feature_count,
+                            "attribute": 0
+                        }
+                    ]
+                });
+            }
+        }
+
+        result
+    }
+}
+
+#[derive(Debug)]
+pub enum GltfError {
+    Io(String, io::Error),
+    /// Textures must be PNG or JPEG images
+    ImageFormat(String),
+    /// An output file could not be written
+    Write(String, io::Error)
+}
+
+impl fmt::Display for GltfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GltfError::Io(path, error) =>
+                write!(f, "could not read texture {}: {}", path, error),
+            GltfError::ImageFormat(path) =>
+                write!(f, "texture {} must be a PNG or JPEG image", path),
+            GltfError::Write(path, error) =>
+                write!(f, "could not write {}: {}", path, error)
+        }
+    }
+}
+
 pub struct Gltf {
     materials: Vec<Material>,
-    instances: Instances,
-    primitives: Vec<Primitive>,
+    // Texture file paths and the buffer view and MIME type of each image.
+    // Each image gets one texture.
+    texture_paths: Vec<String>,
+    images: Vec<(usize, String)>,
+    nodes: Vec<Node>,
+    meshes: Vec<Vec<Primitive>>,
+    property_table: Option<PropertyTable>,
+    // Whether to quantize positions and normals, and the (offset, scale)
+    // that turns the quantized positions of each mesh back into
+    // positions
+    quantize: bool,
+    dequantization: Vec<Option<(Vec3, f64)>>,
     accessors: Vec<Accessor>,
     buffer_views: Vec<BufferView>,
     buffer_data: Vec<u8>
@@ -110,45 +401,121 @@ pub struct Gltf {
 
 impl Gltf {
     pub fn new() -> Self {
-        let instances = Instances {
-            translation: 0
-        };
-
         Self {
             materials: Vec::new(),
-            instances,
-            primitives: Vec::new(),
+            texture_paths: Vec::new(),
+            images: Vec::new(),
+            nodes: Vec::new(),
+            meshes: Vec::new(),
+            property_table: None,
+            quantize: false,
+            dequantization: Vec::new(),
             accessors: Vec::new(),
             buffer_views: Vec::new(),
             buffer_data: Vec::new()
         }
     }
 
-    pub fn add_materials(&mut self, materials: Vec<Material>) {
-        self.materials = materials;
+    /// Quantize the meshes added after this with KHR_mesh_quantization
+    pub fn set_quantization(&mut self, quantize: bool) {
+        self.quantize = quantize;
     }
 
-    fn add_buffer_view(&mut self, name: &str, mut data: Vec<u8>, is_indices: bool) -> usize {
-        let index = self.buffer_views.len();
+    /// Add the materials, embedding any texture images in the binary
+    /// buffer
+    pub fn add_materials(&mut self, materials: Vec<Material>) -> Result<(), GltfError> {
+        for material in materials.iter() {
+            for path in material.texture_paths() {
+                if !self.texture_paths.iter().any(|existing| existing == path) {
+                    self.add_image(path)?;
+                }
+            }
+        }
+        self.materials = materials;
+        Ok(())
+    }
 
-        // Compute the offset for this buffer view
-        let byte_offset = if index == 0 {
-            0
+    fn add_image(&mut self, path: &str) -> Result<(), GltfError> {
+        let lower = path.to_lowercase();
+        let mime_type = if lower.ends_with(".png") {
+            "image/png"
+        } else if lower.ends_with(".jpg") || lower.ends_with(".jpeg") {
+            "image/jpeg"
         } else {
-            self.buffer_views[index - 1].after_offset()
+            return Err(GltfError::ImageFormat(String::from(path)));
         };
+        let data = fs::read(path)
+            .map_err(|error| GltfError::Io(String::from(path), error))?;
 
-        let target = if is_indices {
-            GLTF_TARGET_ELEMENT_ARRAY_BUFFER
+        let buffer_view = self.push_buffer_view(path, data, None, 4);
+        self.texture_paths.push(String::from(path));
+        self.images.push((buffer_view, String::from(mime_type)));
+        Ok(())
+    }
+
+    fn texture_index(&self, path: &str) -> usize {
+        self.texture_paths.iter()
+            .position(|existing| existing == path)
+            .expect("Texture was not added")
+    }
+
+    /// Size in bytes of a glTF component type
+    fn component_size(component_type: u32) -> usize {
+        match component_type {
+            GLTF_BYTE | GLTF_UNSIGNED_BYTE => 1,
+            GLTF_SHORT | GLTF_UNSIGNED_SHORT => 2,
+            GLTF_UNSIGNED_INT | GLTF_FLOAT => 4,
+            _ => panic!("Unknown component type {}", component_type)
+        }
+    }
+
+    /// Add a view of tightly packed vertex attributes or indices.
+    /// Accessors must start at a multiple of their component size, and
+    /// vertex attributes at a multiple of 4 bytes.
+    fn add_buffer_view(
+        &mut self,
+        name: &str,
+        data: Vec<u8>,
+        is_indices: bool,
+        component_type: u32
+    ) -> usize {
+        let component_size = Self::component_size(component_type);
+        let (target, alignment) = if is_indices {
+            (GLTF_TARGET_ELEMENT_ARRAY_BUFFER, component_size)
         } else {
-            GLTF_TARGET_ARRAY_BUFFER
+            (GLTF_TARGET_ARRAY_BUFFER, component_size.max(4))
         };
 
+        self.push_buffer_view(name, data, Some(target), alignment)
+    }
+
+    /// Add a view of vertex attributes padded to byte_stride, which must
+    /// be a multiple of 4
+    fn add_vertex_buffer_view(&mut self, name: &str, data: Vec<u8>, byte_stride: usize) -> usize {
+        let index = self.push_buffer_view(name, data, Some(GLTF_TARGET_ARRAY_BUFFER), 4);
+        self.buffer_views[index].byte_stride = Some(byte_stride);
+        index
+    }
+
+    fn push_buffer_view(
+        &mut self,
+        name: &str,
+        mut data: Vec<u8>,
+        target: Option<u32>,
+        alignment: usize
+    ) -> usize {
+        let index = self.buffer_views.len();
+
+        // Pad the buffer so the view starts at a multiple of alignment
+        let padding = (alignment - self.buffer_data.len() % alignment) % alignment;
+        self.buffer_data.extend(std::iter::repeat_n(0, padding));
+
         let buffer_view = BufferView {
             name: String::from(name),
-            byte_offset,
+            byte_offset: self.buffer_data.len(),
             byte_length: data.len(),
-            target
+            target,
+            byte_stride: None
         };
 
         self.buffer_views.push(buffer_view);
@@ -158,6 +525,60 @@ impl Gltf {
         index
     }
 
+    /// Add a property table with one row per feature. Feature IDs in
+    /// primitives refer to the rows of this table.
+    pub fn add_property_table(&mut self, class: &str, columns: Vec<(&str, PropertyValues)>) {
+        // EXT_structural_metadata requires 8-byte aligned buffer views
+        const METADATA_ALIGNMENT: usize = 8;
+
+        let count = columns.first().map(|(_, values)| values.len()).unwrap_or(0);
+        let mut properties = Vec::new();
+        for (name, values) in columns {
+            let schema = values.schema_json();
+            let view_name = format!("Property {}", name);
+            let table_json = match values {
+                PropertyValues::UInt32(values) => {
+                    let data = values.iter().flat_map(|x| x.to_le_bytes()).collect();
+                    let view = self.push_buffer_view(&view_name, data, None, METADATA_ALIGNMENT);
+                    json!({ "values": view })
+                },
+                PropertyValues::Float32(values) => {
+                    let data = values.iter().flat_map(|x| x.to_le_bytes()).collect();
+                    let view = self.push_buffer_view(&view_name, data, None, METADATA_ALIGNMENT);
+                    json!({ "values": view })
+                },
+                PropertyValues::String(values) => {
+                    // Concatenated UTF-8 strings, with count + 1 offsets
+                    // marking where each one starts and ends
+                    let mut data = Vec::new();
+                    let mut offsets: Vec<u32> = vec![0];
+                    for value in values.iter() {
+                        data.extend_from_slice(value.as_bytes());
+                        offsets.push(data.len() as u32);
+                    }
+                    let offset_data = offsets.iter().flat_map(|x| x.to_le_bytes()).collect();
+
+                    let view = self.push_buffer_view(&view_name, data, None, METADATA_ALIGNMENT);
+                    let offset_view = self.push_buffer_view(
+                        &format!("{} offsets", view_name), offset_data, None, METADATA_ALIGNMENT
+                    );
+                    json!({
+                        "values": view,
+                        "stringOffsets": offset_view,
+                        "stringOffsetType": "UINT32"
+                    })
+                }
+            };
+            properties.push((String::from(name), schema, table_json));
+        }
+
+        self.property_table = Some(PropertyTable {
+            class: String::from(class),
+            count,
+            properties
+        });
+    }
+
     fn add_accesor(&mut self, accessor: Accessor) -> usize {
         let index = self.accessors.len();
         self.accessors.push(accessor);
@@ -176,11 +597,11 @@ impl Gltf {
         for (x, y, z) in vectors.iter() {
             x_min = x_min.min(*x);
             y_min = y_min.min(*y);
-            z_min = y_min.min(*z);
+            z_min = z_min.min(*z);
 
             x_max = x_max.max(*x);
             y_max = y_max.max(*y);
-            z_max = y_max.max(*z);
+            z_max = z_max.max(*z);
         }
 
         (
@@ -204,27 +625,150 @@ impl Gltf {
         result
     }
 
+    fn pack_vec4s(vectors: Vec<[f64; 4]>) -> Vec<u8> {
+        let mut result = Vec::new();
+
+        for vector in vectors {
+            for component in vector {
+                result.extend_from_slice(&(component as f32).to_le_bytes());
+            }
+        }
+
+        result
+    }
+
     fn add_position_accessor(&mut self, positions: Vec<Vec3>) -> usize {
         let count = positions.len();
         let (min, max) = Self::get_min_max(&positions);
         let buffer_view_data = Self::pack_vec3s(positions);
-        let buffer_view = self.add_buffer_view("Position", buffer_view_data, false);
+        let buffer_view = self.add_buffer_view("Position", buffer_view_data, false, GLTF_FLOAT);
 
         self.add_accesor(Accessor {
             name: String::from("Position"),
-            buffer_view: buffer_view,
+            buffer_view,
             accessor_type: String::from("VEC3"),
             component_type: GLTF_FLOAT,
             count,
             min: Some(min),
-            max: Some(max)
+            max: Some(max),
+            normalized: false
+        })
+    }
+
+    /// Find the (offset, scale) that fits the positions into signed
+    /// 16-bit integers
+    fn quantization(positions: &[Vec3]) -> (Vec3, f64) {
+        let (min, max) = Self::get_min_max(positions);
+        let offset = (
+            0.5 * (min[0] + max[0]),
+            0.5 * (min[1] + max[1]),
+            0.5 * (min[2] + max[2])
+        );
+        let extent = positions.iter()
+            .map(|position| {
+                let (x, y, z) = vec3::sub(*position, offset);
+                x.abs().max(y.abs()).max(z.abs())
+            })
+            .fold(0.0, f64::max);
+        let scale = if extent > 0.0 {
+            extent / i16::MAX as f64
+        } else {
+            1.0
+        };
+
+        (offset, scale)
+    }
+
+    /// Store positions as signed 16-bit integers, padded to 8 bytes per
+    /// vertex
+    fn add_quantized_position_accessor(
+        &mut self,
+        positions: Vec<Vec3>,
+        (offset, scale): (Vec3, f64)
+    ) -> usize {
+        let count = positions.len();
+        let quantized: Vec<[i16; 3]> = positions.iter()
+            .map(|position| {
+                let (x, y, z) = vec3::scale(vec3::sub(*position, offset), 1.0 / scale);
+                [x.round() as i16, y.round() as i16, z.round() as i16]
+            })
+            .collect();
+        let mut quantized_min = [i16::MAX; 3];
+        let mut quantized_max = [i16::MIN; 3];
+        let mut buffer_view_data = Vec::new();
+        for components in quantized {
+            for (i, component) in components.into_iter().enumerate() {
+                quantized_min[i] = quantized_min[i].min(component);
+                quantized_max[i] = quantized_max[i].max(component);
+                buffer_view_data.extend_from_slice(&component.to_le_bytes());
+            }
+            buffer_view_data.extend_from_slice(&[0, 0]);
+        }
+        let buffer_view = self.add_vertex_buffer_view("Position", buffer_view_data, 8);
+
+        self.add_accesor(Accessor {
+            name: String::from("Position"),
+            buffer_view,
+            accessor_type: String::from("VEC3"),
+            component_type: GLTF_SHORT,
+            count,
+            min: Some(quantized_min.map(f64::from)),
+            max: Some(quantized_max.map(f64::from)),
+            normalized: false
+        })
+    }
+
+    /// Store normals as normalized signed bytes, padded to 4 bytes per
+    /// vertex
+    fn add_quantized_normal_accessor(&mut self, normals: Vec<Vec3>) -> usize {
+        let count = normals.len();
+        let mut buffer_view_data = Vec::new();
+        for (x, y, z) in normals {
+            for component in [x, y, z] {
+                let quantized = (component * i8::MAX as f64).round() as i8;
+                buffer_view_data.extend_from_slice(&quantized.to_le_bytes());
+            }
+            buffer_view_data.push(0);
+        }
+        let buffer_view = self.add_vertex_buffer_view("Normals", buffer_view_data, 4);
+
+        self.add_accesor(Accessor {
+            name: String::from("Normals"),
+            buffer_view,
+            accessor_type: String::from("VEC3"),
+            component_type: GLTF_BYTE,
+            count,
+            min: None,
+            max: None,
+            normalized: true
+        })
+    }
+
+    fn add_uv_accessor(&mut self, uvs: Vec<(f64, f64)>) -> usize {
+        let count = uvs.len();
+        let mut buffer_view_data: Vec<u8> = Vec::new();
+        for (u, v) in uvs {
+            buffer_view_data.extend_from_slice(&(u as f32).to_le_bytes());
+            buffer_view_data.extend_from_slice(&(v as f32).to_le_bytes());
+        }
+        let buffer_view = self.add_buffer_view("Texture coordinates", buffer_view_data, false, GLTF_FLOAT);
+
+        self.add_accesor(Accessor {
+            name: String::from("Texture coordinates"),
+            buffer_view,
+            accessor_type: String::from("VEC2"),
+            component_type: GLTF_FLOAT,
+            count,
+            min: None,
+            max: None,
+            normalized: false
         })
     }
 
     fn add_normal_accessor(&mut self, normals: Vec<Vec3>) -> usize {
         let count = normals.len();
         let buffer_view_data = Self::pack_vec3s(normals);
-        let buffer_view = self.add_buffer_view("Normals", buffer_view_data, false);
+        let buffer_view = self.add_buffer_view("Normals", buffer_view_data, false, GLTF_FLOAT);
 
         self.add_accesor(Accessor {
             name: String::from("Normals"),
@@ -233,122 +777,373 @@ impl Gltf {
             component_type: GLTF_FLOAT,
             count,
             min: None,
-            max: None
+            max: None,
+            normalized: false
         })
     }
 
-    fn add_indices_accessor(&mut self, indices: Vec<u32>) -> usize {
+    fn add_color_accessor(&mut self, colors: Vec<Vec3>) -> usize {
+        let count = colors.len();
+        let buffer_view_data = Self::pack_vec3s(colors);
+        let buffer_view = self.add_buffer_view("Colors", buffer_view_data, false, GLTF_FLOAT);
+
+        self.add_accesor(Accessor {
+            name: String::from("Colors"),
+            buffer_view,
+            accessor_type: String::from("VEC3"),
+            component_type: GLTF_FLOAT,
+            count,
+            min: None,
+            max: None,
+            normalized: false
+        })
+    }
+
+    fn add_feature_id_accessor(&mut self, feature_ids: Vec<u32>) -> usize {
+        let count = feature_ids.len();
+        let mut buffer_view_data: Vec<u8> = Vec::new();
+        for feature_id in feature_ids {
+            buffer_view_data.extend_from_slice(&(feature_id as f32).to_le_bytes());
+        }
+        let buffer_view = self.add_buffer_view("Feature IDs", buffer_view_data, false, GLTF_FLOAT);
+
+        self.add_accesor(Accessor {
+            name: String::from("Feature IDs"),
+            buffer_view,
+            accessor_type: String::from("SCALAR"),
+            component_type: GLTF_FLOAT,
+            count,
+            min: None,
+            max: None,
+            normalized: false
+        })
+    }
+
+    /// Store indices as 16-bit integers when there are few enough
+    /// vertices. The largest value of each type is reserved, so this
+    /// allows up to 65535 vertices.
+    fn add_indices_accessor(&mut self, indices: Vec<u32>, vertex_count: usize) -> usize {
         let count = indices.len();
+        let use_short = vertex_count <= u16::MAX as usize;
+        let component_type = if use_short {
+            GLTF_UNSIGNED_SHORT
+        } else {
+            GLTF_UNSIGNED_INT
+        };
         let mut buffer_view_data: Vec<u8> = Vec::new();
         for index in indices {
-            let index_u32 = index as u32;
-            buffer_view_data.extend_from_slice(&index_u32.to_le_bytes())
+            if use_short {
+                buffer_view_data.extend_from_slice(&(index as u16).to_le_bytes())
+            } else {
+                buffer_view_data.extend_from_slice(&index.to_le_bytes())
+            }
         }
-        let buffer_view = self.add_buffer_view("Indices", buffer_view_data, true);
+        let buffer_view = self.add_buffer_view("Indices", buffer_view_data, true, component_type);
 
         self.add_accesor(Accessor {
             name: String::from("Indices"),
             buffer_view,
             accessor_type: String::from("SCALAR"),
-            component_type: GLTF_UNSIGNED_INT,
+            component_type,
             count,
             min: None,
-            max: None
+            max: None,
+            normalized: false
         })
     }
 
-    pub fn add_instances(&mut self, translations: Vec<Vec3>) {
-        let count = translations.len();
-        let buffer_view_data = Self::pack_vec3s(translations);
-        let buffer_view = self.add_buffer_view("Instance TRANSLATION", buffer_view_data, false);
+    /// Add a node for a mesh, optionally transformed and repeated with
+    /// GPU instancing
+    pub fn add_node(
+        &mut self,
+        name: &str,
+        mesh: usize,
+        transform: Option<Instance>,
+        instances: Option<Vec<Instance>>
+    ) {
+        let (transform, instances) = match self.dequantization[mesh] {
+            Some((offset, scale)) => Self::dequantize(transform, instances, offset, scale),
+            None => (transform, instances)
+        };
+        let instances = instances.map(|instances| self.add_instances(instances));
+        self.nodes.push(Node {
+            name: String::from(name),
+            mesh,
+            transform,
+            instances
+        });
+    }
+
+    /// Fold the transform from quantized positions into the node's
+    /// transform. The instances are applied before the node transform, so
+    /// they are adjusted to end up in the same place.
+    fn dequantize(
+        transform: Option<Instance>,
+        instances: Option<Vec<Instance>>,
+        offset: Vec3,
+        scale: f64
+    ) -> (Option<Instance>, Option<Vec<Instance>>) {
+        let node = transform.unwrap_or_else(|| Instance::at((0.0, 0.0, 0.0)));
+        let (sx, sy, sz) = node.scale;
+        let transform = Instance {
+            translation: node.transform_point(offset),
+            scale: (sx * scale, sy * scale, sz * scale),
+            ..node
+        };
+
+        let instances = instances.map(|instances| {
+            instances.iter()
+                .map(|instance| {
+                    let relative = instance.relative_to(offset);
+                    Instance {
+                        translation: vec3::scale(relative.translation, 1.0 / scale),
+                        ..relative
+                    }
+                })
+                .collect()
+        });
+
+        (Some(transform), instances)
+    }
+
+    fn add_instances(&mut self, instances: Vec<Instance>) -> Instances {
+        let count = instances.len();
+        let translations = instances.iter()
+            .map(|instance| instance.translation)
+            .collect();
+        let translation = self.add_instance_accessor(
+            "TRANSLATION", "VEC3", Self::pack_vec3s(translations), count
+        );
 
-        let accessor = self.add_accesor(Accessor {
-            name: String::from("Instance TRANSLATION"),
+        let rotation = if instances.iter().any(|instance| instance.rotation != 0.0) {
+            let quaternions = instances.iter()
+                .map(|instance| instance.quaternion())
+                .collect();
+            Some(self.add_instance_accessor(
+                "ROTATION", "VEC4", Self::pack_vec4s(quaternions), count
+            ))
+        } else {
+            None
+        };
+
+        let scale = if instances.iter().any(|instance| instance.scale != (1.0, 1.0, 1.0)) {
+            let scales = instances.iter()
+                .map(|instance| instance.scale)
+                .collect();
+            Some(self.add_instance_accessor(
+                "SCALE", "VEC3", Self::pack_vec3s(scales), count
+            ))
+        } else {
+            None
+        };
+
+        let feature_ids: Option<Vec<u32>> = instances.iter()
+            .map(|instance| instance.feature_id)
+            .collect();
+        let feature_count = feature_ids.as_ref().map(|feature_ids| {
+            let mut distinct = feature_ids.clone();
+            distinct.sort_unstable();
+            distinct.dedup();
+            distinct.len()
+        });
+        let feature_id = feature_ids.map(|feature_ids| {
+            let mut buffer_view_data = Vec::new();
+            for feature_id in feature_ids {
+                buffer_view_data.extend_from_slice(&(feature_id as f32).to_le_bytes());
+            }
+            self.add_instance_accessor("_FEATURE_ID_0", "SCALAR", buffer_view_data, count)
+        });
+
+        Instances {
+            translation,
+            rotation,
+            scale,
+            feature_id,
+            feature_count
+        }
+    }
+
+    fn add_instance_accessor(
+        &mut self,
+        attribute: &str,
+        accessor_type: &str,
+        buffer_view_data: Vec<u8>,
+        count: usize
+    ) -> usize {
+        let name = format!("Instance {}", attribute);
+        let buffer_view = self.add_buffer_view(&name, buffer_view_data, false, GLTF_FLOAT);
+
+        self.add_accesor(Accessor {
+            name,
             buffer_view,
-            accessor_type: String::from("VEC3"),
+            accessor_type: String::from(accessor_type),
             component_type: GLTF_FLOAT,
             count,
             min: None,
-            max: None
-        });
-
-        self.instances = Instances {
-            translation: accessor
-        }
+            max: None,
+            normalized: false
+        })
     }
 
-    pub fn add_primitive(&mut self, mesh: &Mesh, material_id: usize) {
-        let (positions, normals, indices) = mesh.triangulate();
+    /// Add a mesh with a primitive for each (material, triangles) pair
+    pub fn add_mesh(&mut self, primitives: Vec<(usize, Triangles)>) -> usize {
+        // Every primitive shares one offset and scale so the node
+        // transform can undo the quantization
+        let dequantization = if self.quantize {
+            let all_positions: Vec<Vec3> = primitives.iter()
+                .flat_map(|(_, triangles)| triangles.positions.iter().copied())
+                .collect();
+            Some(Self::quantization(&all_positions))
+        } else {
+            None
+        };
 
-        let position_accessor = self.add_position_accessor(positions);
-        let normal_accessor = self.add_normal_accessor(normals);
-        let indices_accessor = self.add_indices_accessor(indices);
+        let primitives = primitives.into_iter()
+            .map(|(material, triangles)| self.add_primitive(triangles, material, dequantization))
+            .collect();
+        self.meshes.push(primitives);
+        self.dequantization.push(dequantization);
+        self.meshes.len() - 1
+    }
+
+    fn add_primitive(
+        &mut self,
+        triangles: Triangles,
+        material_id: usize,
+        dequantization: Option<(Vec3, f64)>
+    ) -> Primitive {
+        let vertex_count = triangles.positions.len();
+        let (position_accessor, normal_accessor) = match dequantization {
+            Some(dequantization) => (
+                self.add_quantized_position_accessor(triangles.positions, dequantization),
+                self.add_quantized_normal_accessor(triangles.normals)
+            ),
+            None => (
+                self.add_position_accessor(triangles.positions),
+                self.add_normal_accessor(triangles.normals)
+            )
+        };
+        let indices_accessor = self.add_indices_accessor(triangles.indices, vertex_count);
 
         let mut attributes = HashMap::new();
         attributes.insert(String::from("POSITION"), position_accessor);
         attributes.insert(String::from("NORMAL"), normal_accessor);
+        if !triangles.uvs.is_empty() {
+            let uv_accessor = self.add_uv_accessor(triangles.uvs);
+            attributes.insert(String::from("TEXCOORD_0"), uv_accessor);
+        }
+        if !triangles.colors.is_empty() {
+            let color_accessor = self.add_color_accessor(triangles.colors);
+            attributes.insert(String::from("COLOR_0"), color_accessor);
+        }
+
+        let mut feature_count = None;
+        if !triangles.feature_ids.is_empty() {
+            let mut distinct = triangles.feature_ids.clone();
+            distinct.sort();
+            distinct.dedup();
+            feature_count = Some(distinct.len());
+
+            let feature_accessor = self.add_feature_id_accessor(triangles.feature_ids);
+            attributes.insert(String::from("_FEATURE_ID_0"), feature_accessor);
+        }
 
-        let primitive = Primitive {
+        Primitive {
             material: material_id,
             indices: indices_accessor,
-            attributes
-        };
-        self.primitives.push(primitive);
+            attributes,
+            feature_count
+        }
+    }
+
+    pub fn save(&self, fname: &str) -> Result<(), GltfError> {
+        Self::write_file(Path::new(fname), self.to_bytes())
     }
 
-    pub fn save(&self, fname: &str) {
+    /// Write a .gltf file in the given format, replacing the extension of
+    /// fname to match
+    pub fn save_as(&self, fname: &str, format: OutputFormat) -> Result<(), GltfError> {
+        let path = Path::new(fname);
+        match format {
+            OutputFormat::Glb => self.save(&path.with_extension("glb").to_string_lossy()),
+            OutputFormat::GltfWithBin => self.save_gltf(&path.with_extension("gltf"), false),
+            OutputFormat::GltfEmbedded => self.save_gltf(&path.with_extension("gltf"), true)
+        }
+    }
+
+    /// Write pretty-printed glTF JSON. The buffer is either embedded as a
+    /// base64 data URI or written to a .bin file with the same name.
+    fn save_gltf(&self, path: &Path, embed_buffer: bool) -> Result<(), GltfError> {
+        let mut json = self.to_json();
+        if !self.buffer_data.is_empty() {
+            let uri = if embed_buffer {
+                format!("data:application/octet-stream;base64,{}", base64_encode(&self.buffer_data))
+            } else {
+                let bin_path = path.with_extension("bin");
+                Self::write_file(&bin_path, &self.buffer_data)?;
+                // Relative to the .gltf file
+                bin_path.file_name().unwrap().to_string_lossy().into_owned()
+            };
+            json["buffers"][0]["uri"] = json!(uri);
+        }
+
+        let text = serde_json::to_string_pretty(&json).expect("Could not serialize JSON");
+        Self::write_file(path, text)
+    }
+
+    fn write_file(path: &Path, contents: impl AsRef<[u8]>) -> Result<(), GltfError> {
+        fs::write(path, contents)
+            .map_err(|error| GltfError::Write(path.to_string_lossy().into_owned(), error))
+    }
+
+    /// Encode as a GLB file. Both chunks are padded to a multiple of 4
+    /// bytes, and the BIN chunk is left out if there is no binary data.
+    pub fn to_bytes(&self) -> Vec<u8> {
         let json_bytes: Vec<u8> = serde_json::ser::to_vec(&self.to_json())
             .expect("Could not serialize JSON");
         let json_length = json_bytes.len() as u32;
         let json_padding_length = Self::get_padding_length(json_length);
-        let json_padding = Self::make_padding(json_padding_length, b' ');
         let json_chunk_length = json_length + json_padding_length;
 
-        let binary_chunk_length = self.buffer_data.len() as u32;
+        let binary_length = self.buffer_data.len() as u32;
+        let binary_padding_length = Self::get_padding_length(binary_length);
+        let binary_chunk_length = binary_length + binary_padding_length;
 
         const HEADER_LENGTH: u32 = 12;
         const CHUNK_HEADER_LENGTH: u32 = 8;
-        let total_length =
-            HEADER_LENGTH +
-            CHUNK_HEADER_LENGTH +
-            json_chunk_length +
-            CHUNK_HEADER_LENGTH +
-            binary_chunk_length;
+        let mut total_length = HEADER_LENGTH + CHUNK_HEADER_LENGTH + json_chunk_length;
+        if binary_length > 0 {
+            total_length += CHUNK_HEADER_LENGTH + binary_chunk_length;
+        }
 
-        let mut file = File::create(fname).expect("Could not create file");
+        let mut result = Vec::with_capacity(total_length as usize);
 
         // GLB header
         const GLTF_VERSION: u32 = 2;
-        file.write_all(b"glTF").expect("Could not write magic");
-        file.write_all(&GLTF_VERSION.to_le_bytes())
-            .expect("could not write version");
-        file.write_all(&total_length.to_le_bytes())
-            .expect("Could not write glTF length");
-        
-        // JSON chunk
-        file.write_all(&json_chunk_length.to_le_bytes())
-            .expect("Could not write JSON chunk length");
-        file.write_all(b"JSON").expect("Could not write JSON chunk magic");
-        file.write_all(&json_bytes).expect("Could not write JSON data");
-        file.write_all(&json_padding).expect("Could not write JSON padding");
+        result.extend_from_slice(b"glTF");
+        result.extend_from_slice(&GLTF_VERSION.to_le_bytes());
+        result.extend_from_slice(&total_length.to_le_bytes());
+
+        // JSON chunk, padded with spaces
+        result.extend_from_slice(&json_chunk_length.to_le_bytes());
+        result.extend_from_slice(b"JSON");
+        result.extend_from_slice(&json_bytes);
+        result.extend(Self::make_padding(json_padding_length, b' '));
 
-        // Binary chunk
-        file.write_all(&binary_chunk_length.to_le_bytes())
-            .expect("Could not write BIN chunk length");
-        file.write_all(b"BIN\0").expect("Could not write BIN chunk magic");
-        file.write_all(&self.buffer_data).expect("Could not write binary buffer");
+        // Binary chunk, padded with zeros
+        if binary_length > 0 {
+            result.extend_from_slice(&binary_chunk_length.to_le_bytes());
+            result.extend_from_slice(b"BIN\0");
+            result.extend_from_slice(&self.buffer_data);
+            result.extend(Self::make_padding(binary_padding_length, 0));
+        }
 
-        // All of the buffer data is a multiple of 4 so no padding should
-        // be needed
-        assert!(self.buffer_data.len() % 4 == 0, "Padding needed!");
+        result
     }
 
     fn get_padding_length(length: u32) -> u32 {
         const GLB_ALIGNMENT: u32 = 4;
-        // modulo but go from [1, GLB_ALIGNMENT] instead of 
-        // [0, GLB_ALIGNMENT - 1]
-        let leftover = (length - 1) % GLB_ALIGNMENT + 1;
-        GLB_ALIGNMENT - leftover
+        (GLB_ALIGNMENT - length % GLB_ALIGNMENT) % GLB_ALIGNMENT
     }
 
     fn make_padding(length: u32, padding_char: u8) -> Vec<u8> {
@@ -359,10 +1154,21 @@ impl Gltf {
         let copyright = format!("© {} Peter Gagliardi", Utc::now().year());
 
         let material_json: Vec<serde_json::Value> = self.materials.iter()
-            .map(|x| x.to_json())
+            .map(|x| x.to_json(|path| self.texture_index(path)))
             .collect();
         
-        let primitive_json: Vec<serde_json::Value> = self.primitives.iter()
+        let mesh_json: Vec<serde_json::Value> = self.meshes.iter()
+            .map(|primitives| {
+                let primitive_json: Vec<serde_json::Value> = primitives.iter()
+                    .map(|x| x.to_json())
+                    .collect();
+                json!({
+                    "primitives": primitive_json
+                })
+            })
+            .collect();
+
+        let node_json: Vec<serde_json::Value> = self.nodes.iter()
             .map(|x| x.to_json())
             .collect();
         
@@ -374,43 +1180,571 @@ impl Gltf {
             .map(|x| x.to_json())
             .collect();
 
-        json!({
+        let mut extensions_used = Vec::new();
+        if self.nodes.iter().any(|node| node.instances.is_some()) {
+            extensions_used.push("EXT_mesh_gpu_instancing");
+        }
+        let has_instance_features = self.nodes.iter()
+            .filter_map(|node| node.instances.as_ref())
+            .any(|instances| instances.feature_count.is_some());
+        if has_instance_features {
+            extensions_used.push("EXT_instance_features");
+        }
+        let has_features = self.meshes.iter()
+            .flatten()
+            .any(|primitive| primitive.feature_count.is_some());
+        if has_features {
+            extensions_used.push("EXT_mesh_features");
+        }
+        if self.property_table.is_some() {
+            extensions_used.push("EXT_structural_metadata");
+        }
+        for material in self.materials.iter() {
+            for extension in material.extensions_used() {
+                if !extensions_used.contains(&extension) {
+                    extensions_used.push(extension);
+                }
+            }
+        }
+        let quantized = self.dequantization.iter().any(|x| x.is_some());
+        if quantized {
+            extensions_used.push("KHR_mesh_quantization");
+        }
+
+        let mut result = json!({
             "asset": {
                 "version": "2.0",
                 "copyright": copyright,
                 "generator": "Tower tiling generator from https://github.com/ptrgags/tower-tilings"
             },
-            "extensionsUsed": [
-                "EXT_mesh_gpu_instancing"
-            ],
             "scene": 0,
-            "scenes": [
+            "scenes": [{}]
+        });
+
+        // The schema doesn't allow empty arrays, so leave them out
+        if !self.nodes.is_empty() {
+            result["scenes"][0]["nodes"] = json!((0..self.nodes.len()).collect::<Vec<usize>>());
+        }
+        let arrays = [
+            ("nodes", node_json),
+            ("materials", material_json),
+            ("meshes", mesh_json),
+            ("accessors", accessor_json),
+            ("bufferViews", buffer_view_json)
+        ];
+        for (key, values) in arrays {
+            if !values.is_empty() {
+                result[key] = json!(values);
+            }
+        }
+
+        // A buffer must have at least one byte
+        if !self.buffer_data.is_empty() {
+            result["buffers"] = json!([
                 {
-                    "nodes": [0]
+                    "byteLength": self.buffer_data.len()
                 }
-            ],
-            "nodes": [
-                {
-                    "mesh": 0,
-                    "name": "Tower Tiling",
-                    "extensions": {
-                        "EXT_mesh_gpu_instancing": self.instances.to_json()
-                    }
+            ]);
+        }
+
+        if !extensions_used.is_empty() {
+            result["extensionsUsed"] = json!(extensions_used);
+        }
+        if quantized {
+            result["extensionsRequired"] = json!(["KHR_mesh_quantization"]);
+        }
+        if !self.images.is_empty() {
+            let image_json: Vec<serde_json::Value> = self.images.iter()
+                .map(|(buffer_view, mime_type)| json!({
+                    "bufferView": buffer_view,
+                    "mimeType": mime_type
+                }))
+                .collect();
+            let texture_json: Vec<serde_json::Value> = (0..self.images.len())
+                .map(|i| json!({
+                    "sampler": 0,
+                    "source": i
+                }))
+                .collect();
+            result["images"] = json!(image_json);
+            result["textures"] = json!(texture_json);
+            // Generated texture coordinates go past 1 so the textures
+            // should repeat
+            const GLTF_LINEAR: u32 = 9729;
+            const GLTF_LINEAR_MIPMAP_LINEAR: u32 = 9987;
+            const GLTF_REPEAT: u32 = 10497;
+            result["samplers"] = json!([{
+                "magFilter": GLTF_LINEAR,
+                "minFilter": GLTF_LINEAR_MIPMAP_LINEAR,
+                "wrapS": GLTF_REPEAT,
+                "wrapT": GLTF_REPEAT
+            }]);
+        }
+        if let Some(property_table) = &self.property_table {
+            result["extensions"] = json!({
+                "EXT_structural_metadata": property_table.to_json()
+            });
+        }
+
+        result
+    }
+}
+
+/// Encode bytes as standard base64 with padding
+fn base64_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] =
+        b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut result = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        // Pack up to 3 bytes into 24 bits, then split into 6-bit digits
+        let bits = chunk.iter()
+            .enumerate()
+            .fold(0u32, |bits, (i, byte)| bits | (*byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                let digit = (bits >> (18 - 6 * i)) & 0x3f;
+                result.push(ALPHABET[digit as usize] as char);
+            } else {
+                result.push('=');
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_u32(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn property_table_layout() {
+        let mut gltf = Gltf::new();
+        gltf.add_property_table("tower", vec![
+            ("profile", PropertyValues::String(vec![
+                String::from("abc"),
+                String::new(),
+                String::from("de")
+            ])),
+            ("seed", PropertyValues::UInt32(vec![4, 5, 6]))
+        ]);
+
+        let json = gltf.to_json();
+        let metadata = &json["extensions"]["EXT_structural_metadata"];
+        assert_eq!(metadata["schema"]["classes"]["tower"]["properties"]["profile"]["type"], "STRING");
+        let table = &metadata["propertyTables"][0];
+        assert_eq!(table["count"], 3);
+        assert_eq!(json["extensionsUsed"], json!(["EXT_structural_metadata"]));
+
+        // Every view starts on an 8-byte boundary
+        for view in gltf.buffer_views.iter() {
+            assert_eq!(view.byte_offset % 8, 0);
+            assert!(view.target.is_none());
+        }
+
+        let strings = &gltf.buffer_views[0];
+        assert_eq!(&gltf.buffer_data[strings.byte_offset..][..strings.byte_length], b"abcde");
+        let offsets = &gltf.buffer_views[1];
+        let offset_values: Vec<u32> = (0..4)
+            .map(|i| read_u32(&gltf.buffer_data, offsets.byte_offset + 4 * i))
+            .collect();
+        assert_eq!(offset_values, vec![0, 3, 3, 5]);
+        assert_eq!(read_u32(&gltf.buffer_data, gltf.buffer_views[2].byte_offset + 8), 6);
+    }
+
+    #[test]
+    fn feature_ids_are_attributes() {
+        let mut gltf = Gltf::new();
+        let triangles = Triangles {
+            positions: vec![(0.0, 0.0, 0.0), (1.0, 0.0, 0.0), (0.0, 1.0, 0.0)],
+            normals: vec![(0.0, 0.0, 1.0); 3],
+            indices: vec![0, 1, 2],
+            uvs: Vec::new(),
+            feature_ids: vec![2, 2, 5],
+            colors: vec![(1.0, 0.0, 0.0); 3]
+        };
+        let mesh = gltf.add_mesh(vec![(0, triangles)]);
+        gltf.add_node("test", mesh, None, None);
+
+        let json = gltf.to_json();
+        let primitive = &json["meshes"][0]["primitives"][0];
+        assert!(primitive["attributes"]["_FEATURE_ID_0"].is_number());
+        assert!(primitive["attributes"]["COLOR_0"].is_number());
+        let feature_ids = &primitive["extensions"]["EXT_mesh_features"]["featureIds"][0];
+        assert_eq!(feature_ids["featureCount"], 2);
+        assert_eq!(feature_ids["propertyTable"], 0);
+    }
+
+    #[test]
+    fn instance_feature_ids_use_extension() {
+        let mut gltf = Gltf::new();
+        let triangle = Triangles {
+            positions: vec![(0.0, 0.0, 0.0), (1.0, 0.0, 0.0), (0.0, 1.0, 0.0)],
+            normals: vec![(0.0, 0.0, 1.0); 3],
+            indices: vec![0, 1, 2],
+            uvs: Vec::new(),
+            feature_ids: Vec::new(),
+            colors: Vec::new()
+        };
+        let mesh = gltf.add_mesh(vec![(0, triangle)]);
+        let instances = [3, 1, 3]
+            .map(|feature_id| Instance {
+                feature_id: Some(feature_id),
+                ..Instance::at((0.0, 0.0, 0.0))
+            })
+            .to_vec();
+        gltf.add_node("test", mesh, None, Some(instances));
+
+        let json = gltf.to_json();
+        let extensions = &json["nodes"][0]["extensions"];
+        assert!(extensions["EXT_mesh_gpu_instancing"]["attributes"]["_FEATURE_ID_0"].is_number());
+        let feature_ids = &extensions["EXT_instance_features"]["featureIds"][0];
+        assert_eq!(feature_ids["featureCount"], 2);
+        assert_eq!(feature_ids["attribute"], 0);
+        let used = json["extensionsUsed"].as_array().unwrap();
+        assert!(used.contains(&json!("EXT_instance_features")));
+    }
+
+    #[test]
+    fn bad_textures_are_errors() {
+        let material = |path: &str| -> Material {
+            serde_json::from_value(json!({
+                "base_color": [1, 1, 1],
+                "metallic": 0,
+                "roughness": 1,
+                "base_color_texture": path
+            })).unwrap()
+        };
+
+        let mut gltf = Gltf::new();
+        let result = gltf.add_materials(vec![material("input/missing-texture.png")]);
+        assert!(matches!(result, Err(GltfError::Io(..))));
+        let result = gltf.add_materials(vec![material("input/test-tiling.json")]);
+        assert!(matches!(result, Err(GltfError::ImageFormat(..))));
+    }
+
+    #[test]
+    fn unwritable_files_are_errors() {
+        let gltf = Gltf::new();
+        let result = gltf.save("output/missing-directory/towers.glb");
+        assert!(matches!(result, Err(GltfError::Write(..))));
+    }
+
+    fn read_i16(data: &[u8], offset: usize) -> i16 {
+        i16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn triangle() -> Triangles {
+        Triangles {
+            positions: vec![(1.0, 2.0, 0.0), (4.0, 2.0, 0.5), (1.0, 3.0, 2.0)],
+            normals: vec![(0.0, 0.0, 1.0); 3],
+            indices: vec![0, 1, 2],
+            ..Triangles::new()
+        }
+    }
+
+    #[test]
+    fn small_meshes_use_short_indices() {
+        let mut gltf = Gltf::new();
+        gltf.add_mesh(vec![(0, triangle())]);
+        let indices = &gltf.accessors[2];
+        assert_eq!(indices.component_type, GLTF_UNSIGNED_SHORT);
+        assert_eq!(gltf.buffer_views[indices.buffer_view].byte_length, 6);
+    }
+
+    #[test]
+    fn quantized_positions_keep_world_position() {
+        let mut gltf = Gltf::new();
+        gltf.set_quantization(true);
+        let mesh = gltf.add_mesh(vec![(0, triangle())]);
+        let node = Instance {
+            rotation: 0.3,
+            ..Instance::at((10.0, 0.0, 0.0))
+        };
+        let instance = Instance {
+            rotation: 1.1,
+            scale: (2.0, 2.0, 0.5),
+            ..Instance::at((-3.0, 4.0, 1.0))
+        };
+        gltf.add_node("test", mesh, Some(node.clone()), Some(vec![instance.clone()]));
+
+        let json = gltf.to_json();
+        assert_eq!(json["extensionsRequired"], json!(["KHR_mesh_quantization"]));
+        let positions = &gltf.accessors[0];
+        assert_eq!(positions.component_type, GLTF_SHORT);
+        let normals = &gltf.accessors[1];
+        assert_eq!(normals.component_type, GLTF_BYTE);
+        assert!(normals.normalized);
+        let normal_view = &gltf.buffer_views[normals.buffer_view];
+        assert_eq!(&gltf.buffer_data[normal_view.byte_offset..][..4], &[0, 0, 127, 0]);
+
+        // Read back the quantized positions and the transforms written to
+        // the node
+        let view = &gltf.buffer_views[positions.buffer_view];
+        assert_eq!(view.byte_stride, Some(8));
+        let written_node = gltf.nodes[0].transform.clone().unwrap();
+        let translation_view = &gltf.buffer_views[gltf.accessors[3].buffer_view];
+        let read_f32 = |i: usize| {
+            let bits = read_u32(&gltf.buffer_data, translation_view.byte_offset + 4 * i);
+            f32::from_bits(bits) as f64
+        };
+        let written_instance = Instance {
+            translation: (read_f32(0), read_f32(1), read_f32(2)),
+            ..instance.clone()
+        };
+
+        for (i, position) in triangle().positions.into_iter().enumerate() {
+            let start = view.byte_offset + 8 * i;
+            let quantized = (
+                read_i16(&gltf.buffer_data, start) as f64,
+                read_i16(&gltf.buffer_data, start + 2) as f64,
+                read_i16(&gltf.buffer_data, start + 4) as f64
+            );
+            let expected = node.transform_point(instance.transform_point(position));
+            let actual = written_node.transform_point(written_instance.transform_point(quantized));
+            assert!(vec3::length(vec3::sub(actual, expected)) < 1e-3);
+        }
+    }
+
+    /// Split a GLB file into its JSON and binary chunks, checking the
+    /// header and chunk layout
+    fn parse_glb(bytes: &[u8]) -> (serde_json::Value, Vec<u8>) {
+        assert_eq!(&bytes[0..4], b"glTF");
+        assert_eq!(read_u32(bytes, 4), 2);
+        assert_eq!(read_u32(bytes, 8) as usize, bytes.len());
+
+        let json_length = read_u32(bytes, 12) as usize;
+        assert_eq!(&bytes[16..20], b"JSON");
+        assert_eq!(json_length % 4, 0);
+        let json = serde_json::from_slice(&bytes[20..20 + json_length]).unwrap();
+
+        let mut binary = Vec::new();
+        let binary_start = 20 + json_length;
+        if binary_start < bytes.len() {
+            let binary_length = read_u32(bytes, binary_start) as usize;
+            assert_eq!(&bytes[binary_start + 4..binary_start + 8], b"BIN\0");
+            assert_eq!(binary_length % 4, 0);
+            assert_eq!(binary_start + 8 + binary_length, bytes.len());
+            binary = bytes[binary_start + 8..].to_vec();
+        }
+
+        (json, binary)
+    }
+
+    fn component_count(accessor_type: &str) -> usize {
+        match accessor_type {
+            "SCALAR" => 1,
+            "VEC2" => 2,
+            "VEC3" => 3,
+            "VEC4" => 4,
+            _ => panic!("unexpected type {}", accessor_type)
+        }
+    }
+
+    fn read_component(data: &[u8], offset: usize, component_type: u32) -> f64 {
+        let bytes = &data[offset..offset + Gltf::component_size(component_type)];
+        match component_type {
+            GLTF_BYTE => bytes[0] as i8 as f64,
+            GLTF_UNSIGNED_BYTE => bytes[0] as f64,
+            GLTF_SHORT => i16::from_le_bytes(bytes.try_into().unwrap()) as f64,
+            GLTF_UNSIGNED_SHORT => u16::from_le_bytes(bytes.try_into().unwrap()) as f64,
+            GLTF_UNSIGNED_INT => u32::from_le_bytes(bytes.try_into().unwrap()) as f64,
+            GLTF_FLOAT => f32::from_le_bytes(bytes.try_into().unwrap()) as f64,
+            _ => panic!("unexpected component type {}", component_type)
+        }
+    }
+
+    /// Read every element of an accessor
+    fn read_accessor(json: &serde_json::Value, binary: &[u8], index: usize) -> Vec<Vec<f64>> {
+        let accessor = &json["accessors"][index];
+        let view = &json["bufferViews"][accessor["bufferView"].as_u64().unwrap() as usize];
+        let component_type = accessor["componentType"].as_u64().unwrap() as u32;
+        let components = component_count(accessor["type"].as_str().unwrap());
+        let component_size = Gltf::component_size(component_type);
+        let element_size = components * component_size;
+        let stride = view["byteStride"].as_u64().map_or(element_size, |x| x as usize);
+        let start = view["byteOffset"].as_u64().unwrap() as usize;
+        (0..accessor["count"].as_u64().unwrap() as usize)
+            .map(|i| {
+                (0..components)
+                    .map(|c| read_component(binary, start + i * stride + c * component_size, component_type))
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Check the parts of the glTF spec the writer is responsible for:
+    /// buffer views inside the buffer, aligned accessors, accurate
+    /// POSITION bounds and indices that point at existing vertices.
+    fn validate(bytes: &[u8]) -> serde_json::Value {
+        let (json, binary) = parse_glb(bytes);
+
+        // Every array in the schema has minItems 1
+        let mut stack = vec![&json];
+        while let Some(value) = stack.pop() {
+            match value {
+                serde_json::Value::Array(items) => {
+                    assert!(!items.is_empty(), "empty array in {}", json);
+                    stack.extend(items);
+                },
+                serde_json::Value::Object(fields) => stack.extend(fields.values()),
+                _ => {}
+            }
+        }
+
+        let buffer_length = json["buffers"][0]["byteLength"].as_u64().unwrap_or(0) as usize;
+        assert!(buffer_length <= binary.len() && binary.len() < buffer_length + 4);
+        for view in json["bufferViews"].as_array().cloned().unwrap_or_default() {
+            let offset = view["byteOffset"].as_u64().unwrap() as usize;
+            let length = view["byteLength"].as_u64().unwrap() as usize;
+            assert!(offset + length <= buffer_length);
+            if let Some(stride) = view["byteStride"].as_u64() {
+                assert!(stride % 4 == 0 && (4..=252).contains(&stride));
+            }
+        }
+
+        for accessor in json["accessors"].as_array().cloned().unwrap_or_default() {
+            let view = &json["bufferViews"][accessor["bufferView"].as_u64().unwrap() as usize];
+            let offset = view["byteOffset"].as_u64().unwrap() as usize;
+            let length = view["byteLength"].as_u64().unwrap() as usize;
+            let component_size = Gltf::component_size(accessor["componentType"].as_u64().unwrap() as u32);
+            let element_size = component_count(accessor["type"].as_str().unwrap()) * component_size;
+            let stride = view["byteStride"].as_u64().map_or(element_size, |x| x as usize);
+            let count = accessor["count"].as_u64().unwrap() as usize;
+
+            assert_eq!(offset % component_size, 0);
+            if view["target"] == GLTF_TARGET_ARRAY_BUFFER {
+                assert_eq!(offset % 4, 0);
+            }
+            assert!(count > 0 && (count - 1) * stride + element_size <= length);
+        }
+
+        for mesh in json["meshes"].as_array().cloned().unwrap_or_default() {
+            for primitive in mesh["primitives"].as_array().unwrap() {
+                let position_index = primitive["attributes"]["POSITION"].as_u64().unwrap() as usize;
+                let positions = read_accessor(&json, &binary, position_index);
+                let accessor = &json["accessors"][position_index];
+                for axis in 0..3 {
+                    let values = positions.iter().map(|position| position[axis]);
+                    let min = values.clone().fold(f64::INFINITY, f64::min);
+                    let max = values.fold(-f64::INFINITY, f64::max);
+                    assert_eq!(accessor["min"][axis].as_f64().unwrap() as f32, min as f32);
+                    assert_eq!(accessor["max"][axis].as_f64().unwrap() as f32, max as f32);
                 }
-            ],
-            "materials": material_json,
-            "meshes": [
-                {
-                    "primitives": primitive_json
+
+                let indices_index = primitive["indices"].as_u64().unwrap() as usize;
+                let component_type = json["accessors"][indices_index]["componentType"].as_u64().unwrap() as u32;
+                assert!(component_type == GLTF_UNSIGNED_SHORT || component_type == GLTF_UNSIGNED_INT);
+                for index in read_accessor(&json, &binary, indices_index) {
+                    assert!((index[0] as usize) < positions.len());
                 }
-            ],
-            "accessors": accessor_json,
-            "bufferViews": buffer_view_json,
-            "buffers": [
-                {
-                    "byteLength": self.buffer_data.len()
+            }
+        }
+
+        let used = json["extensionsUsed"].as_array().cloned().unwrap_or_default();
+        for required in json["extensionsRequired"].as_array().cloned().unwrap_or_default() {
+            assert!(used.contains(&required));
+        }
+
+        json
+    }
+
+    #[test]
+    fn position_bounds_use_each_axis() {
+        let (min, max) = Gltf::get_min_max(&[(0.0, 5.0, -2.0), (1.0, -1.0, 3.0)]);
+        assert_eq!(min, [0.0, -1.0, -2.0]);
+        assert_eq!(max, [1.0, 5.0, 3.0]);
+    }
+
+    #[test]
+    fn empty_glb_has_no_binary_chunk() {
+        let json = validate(&Gltf::new().to_bytes());
+        for key in ["buffers", "bufferViews", "accessors", "meshes", "materials", "nodes"] {
+            assert!(json.get(key).is_none(), "{} should be left out", key);
+        }
+        assert!(json["scenes"][0].get("nodes").is_none());
+    }
+
+    #[test]
+    fn written_glb_is_valid() {
+        for quantize in [false, true] {
+            let mut gltf = Gltf::new();
+            gltf.set_quantization(quantize);
+            // One triangle with 16-bit indices leaves the buffer unaligned
+            let mut tall = triangle();
+            tall.positions[2].2 = 7.0;
+            tall.uvs = vec![(0.0, 0.0), (1.0, 0.0), (0.0, 1.0)];
+            tall.colors = vec![(1.0, 1.0, 1.0); 3];
+            let mesh = gltf.add_mesh(vec![(0, triangle()), (1, tall)]);
+            gltf.add_node("instanced", mesh, None, Some(vec![
+                Instance::at((0.0, 0.0, 0.0)),
+                Instance {
+                    rotation: 0.5,
+                    scale: (1.0, 1.0, 2.0),
+                    feature_id: Some(1),
+                    ..Instance::at((5.0, 0.0, 0.0))
                 }
-            ]
-        })
+            ]));
+            let mesh = gltf.add_mesh(vec![(0, triangle())]);
+            gltf.add_node("placed", mesh, Some(Instance::at((1.0, 1.0, 0.0))), None);
+            gltf.add_property_table("tower", vec![
+                ("profile", PropertyValues::String(vec![String::from("odd")]))
+            ]);
+
+            let json = validate(&gltf.to_bytes());
+            assert_eq!(json["meshes"].as_array().unwrap().len(), 2);
+            assert_eq!(json["nodes"][0]["name"], "instanced");
+        }
+    }
+
+    #[test]
+    fn base64_test_vectors() {
+        // From RFC 4648
+        let cases = [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy")
+        ];
+        for (input, expected) in cases {
+            assert_eq!(base64_encode(input.as_bytes()), expected);
+        }
+        assert_eq!(base64_encode(&[0xfb, 0xff]), "+/8=");
+    }
+
+    #[test]
+    fn text_gltf_output() {
+        let mut gltf = Gltf::new();
+        let mesh = gltf.add_mesh(vec![(0, triangle())]);
+        gltf.add_node("test", mesh, None, None);
+
+        let directory = std::env::temp_dir().join(format!("tower-tilings-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let fname = directory.join("towers.glb");
+        let fname = fname.to_str().unwrap();
+
+        gltf.save_as(fname, OutputFormat::GltfWithBin).unwrap();
+        let text = fs::read_to_string(directory.join("towers.gltf")).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(json["buffers"][0]["uri"], "towers.bin");
+        assert_eq!(fs::read(directory.join("towers.bin")).unwrap(), gltf.buffer_data);
+        assert_eq!(json["accessors"], gltf.to_json()["accessors"]);
+
+        gltf.save_as(fname, OutputFormat::GltfEmbedded).unwrap();
+        let text = fs::read_to_string(directory.join("towers.gltf")).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&text).unwrap();
+        let uri = json["buffers"][0]["uri"].as_str().unwrap();
+        let encoded = uri.strip_prefix("data:application/octet-stream;base64,").unwrap();
+        assert_eq!(encoded, base64_encode(&gltf.buffer_data));
+        assert_eq!(encoded.len(), gltf.buffer_data.len().div_ceil(3) * 4);
+
+        fs::remove_dir_all(&directory).unwrap();
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,411 @@
+use std::fmt;
+use std::fs;
+use std::io;
+
+use serde::Deserialize;
+
+use crate::expression::Expression;
+use crate::vec3::Vec3;
+
+/// A scalar field over the xy-plane with values from 0 to 1
+#[derive(Deserialize)]
+pub enum HeightField {
+    /// 1 at center, falling off linearly to 0 at radius
+    Radial {
+        center: (f64, f64),
+        radius: f64
+    },
+    /// Smooth value noise. scale is the size of a noise cell in world
+    /// units, each octave adds detail at half the size and half the
+    /// strength.
+    Noise {
+        scale: f64,
+        #[serde(default)]
+        seed: u32,
+        octaves: Option<usize>
+    },
+    /// A grayscale PGM image stretched over the rectangle from origin
+    /// to origin + size. The top row of the image is the far y edge.
+    /// Positions outside the rectangle use the nearest edge pixel.
+    Image {
+        path: String,
+        origin: (f64, f64),
+        size: (f64, f64)
+    },
+    /// A formula in x and y, clamped to 0 to 1
+    Expression(String)
+}
+
+/// Scale the normal steps of each tower by a height field evaluated at
+/// the centroid of its base. Field values of 0 and 1 map to min and max.
+/// Both must be positive so every tower keeps some height.
+#[derive(Deserialize)]
+pub struct HeightModulation {
+    pub field: HeightField,
+    #[serde(default = "default_min")]
+    pub min: f64,
+    #[serde(default = "default_max")]
+    pub max: f64
+}
+
+fn default_min() -> f64 {
+    0.25
+}
+
+fn default_max() -> f64 {
+    1.0
+}
+
+#[derive(Debug)]
+pub enum HeightFieldError {
+    Io(String, io::Error),
+    /// The heightmap is not a valid PGM image
+    Pgm(String),
+    /// The expression could not be parsed
+    Expression(String),
+    /// min must be positive and no more than max
+    Range(f64, f64)
+}
+
+impl fmt::Display for HeightFieldError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeightFieldError::Io(path, error) =>
+                write!(f, "could not read heightmap {}: {}", path, error),
+            HeightFieldError::Pgm(message) =>
+                write!(f, "invalid PGM heightmap: {}", message),
+            HeightFieldError::Expression(message) =>
+                write!(f, "invalid height expression: {}", message),
+            HeightFieldError::Range(min, max) => write!(
+                f,
+                "height factors must satisfy 0 < min <= max, got min {} and max {}",
+                min,
+                max
+            )
+        }
+    }
+}
+
+/// A height modulation ready to evaluate, with any image loaded and any
+/// expression parsed.
+pub struct HeightSampler<'a> {
+    modulation: &'a HeightModulation,
+    image: Option<Heightmap>,
+    expression: Option<Expression>
+}
+
+impl HeightModulation {
+    pub fn sampler(&self) -> Result<HeightSampler<'_>, HeightFieldError> {
+        if self.min <= 0.0 || self.max < self.min {
+            return Err(HeightFieldError::Range(self.min, self.max));
+        }
+
+        let mut image = None;
+        let mut expression = None;
+        match &self.field {
+            HeightField::Image { path, .. } => {
+                let bytes = fs::read(path)
+                    .map_err(|error| HeightFieldError::Io(path.clone(), error))?;
+                image = Some(Heightmap::parse(&bytes)?);
+            },
+            HeightField::Expression(text) => {
+                expression = Some(Expression::parse(text).map_err(HeightFieldError::Expression)?);
+            },
+            _ => {}
+        }
+        Ok(HeightSampler {
+            modulation: self,
+            image,
+            expression
+        })
+    }
+}
+
+impl<'a> HeightSampler<'a> {
+    /// Get the factor for the normal steps of a tower with the given
+    /// centroid.
+    pub fn factor(&self, centroid: Vec3) -> f64 {
+        let (x, y, _) = centroid;
+        let value = match &self.modulation.field {
+            HeightField::Radial { center, radius } => {
+                let (cx, cy) = center;
+                let distance = (x - cx).hypot(y - cy);
+                (1.0 - distance / radius).clamp(0.0, 1.0)
+            },
+            HeightField::Noise { scale, seed, octaves } =>
+                fractal_noise(x / scale, y / scale, *seed, octaves.unwrap_or(1)),
+            HeightField::Image { origin, size, .. } => {
+                let (ox, oy) = origin;
+                let (width, height) = size;
+                let image = self.image.as_ref().unwrap();
+                image.sample((x - ox) / width, (y - oy) / height)
+            },
+            HeightField::Expression(_) => {
+                let expression = self.expression.as_ref().unwrap();
+                let value = expression.evaluate(x, y);
+                // NaN from something like sqrt(-1) counts as 0
+                if value.is_nan() {
+                    0.0
+                } else {
+                    value.clamp(0.0, 1.0)
+                }
+            }
+        };
+
+        let HeightModulation { min, max, .. } = self.modulation;
+        min + value * (max - min)
+    }
+}
+
+/// Hash a lattice point to a value from 0 to 1
+fn lattice_value(ix: i64, iy: i64, seed: u32) -> f64 {
+    let mut h = (ix as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15)
+        ^ (iy as u64).wrapping_mul(0xC2B2_AE3D_27D4_EB4F)
+        ^ (seed as u64).wrapping_mul(0x1656_67B1_9E37_79F9);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xFF51_AFD7_ED55_8CCD);
+    h ^= h >> 33;
+    (h >> 11) as f64 / (1u64 << 53) as f64
+}
+
+fn smoothstep(t: f64) -> f64 {
+    t * t * (3.0 - 2.0 * t)
+}
+
+fn value_noise(x: f64, y: f64, seed: u32) -> f64 {
+    let ix = x.floor();
+    let iy = y.floor();
+    let tx = smoothstep(x - ix);
+    let ty = smoothstep(y - iy);
+    let ix = ix as i64;
+    let iy = iy as i64;
+
+    let a = lattice_value(ix, iy, seed);
+    let b = lattice_value(ix + 1, iy, seed);
+    let c = lattice_value(ix, iy + 1, seed);
+    let d = lattice_value(ix + 1, iy + 1, seed);
+    let bottom = a + tx * (b - a);
+    let top = c + tx * (d - c);
+    bottom + ty * (top - bottom)
+}
+
+/// Sum octaves of value noise, normalized back to 0 to 1
+fn fractal_noise(x: f64, y: f64, seed: u32, octaves: usize) -> f64 {
+    let mut total = 0.0;
+    let mut weight = 1.0;
+    let mut total_weight = 0.0;
+    let mut frequency = 1.0;
+    for octave in 0..octaves.max(1) {
+        let octave_seed = seed.wrapping_add(octave as u32);
+        total += weight * value_noise(x * frequency, y * frequency, octave_seed);
+        total_weight += weight;
+        weight *= 0.5;
+        frequency *= 2.0;
+    }
+    total / total_weight
+}
+
+/// Grayscale image with values from 0 to 1, stored top row first
+struct Heightmap {
+    width: usize,
+    height: usize,
+    values: Vec<f64>
+}
+
+impl Heightmap {
+    /// Parse a binary (P5) or ASCII (P2) PGM image
+    fn parse(bytes: &[u8]) -> Result<Self, HeightFieldError> {
+        let mut reader = PgmReader { bytes, position: 0 };
+        let magic = reader.token()?;
+        let width = reader.number()?;
+        let height = reader.number()?;
+        let max_value = reader.number()?;
+        if width == 0 || height == 0 || max_value == 0 || max_value > 65535 {
+            return Err(HeightFieldError::Pgm(format!(
+                "bad header {}x{} with max value {}", width, height, max_value
+            )));
+        }
+
+        let count = width * height;
+        let raw: Vec<usize> = match magic.as_str() {
+            "P2" => (0..count)
+                .map(|_| reader.number())
+                .collect::<Result<_, _>>()?,
+            "P5" => {
+                // A single whitespace character separates the header
+                // from the pixels
+                let start = reader.position + 1;
+                let bytes_per_pixel = if max_value < 256 { 1 } else { 2 };
+                let end = start + count * bytes_per_pixel;
+                if end > bytes.len() {
+                    return Err(HeightFieldError::Pgm("not enough pixels".to_string()));
+                }
+                bytes[start..end]
+                    .chunks(bytes_per_pixel)
+                    .map(|pixel| pixel.iter().fold(0, |value, b| value * 256 + *b as usize))
+                    .collect()
+            },
+            _ => return Err(HeightFieldError::Pgm(format!("unsupported format {}", magic)))
+        };
+
+        Ok(Self {
+            width,
+            height,
+            values: raw.iter()
+                .map(|value| (*value).min(max_value) as f64 / max_value as f64)
+                .collect()
+        })
+    }
+
+    fn pixel(&self, column: usize, row: usize) -> f64 {
+        self.values[row * self.width + column]
+    }
+
+    /// Bilinear sample with u, v from 0 to 1, v pointing up the image
+    fn sample(&self, u: f64, v: f64) -> f64 {
+        // pixel centers are at half-integer coordinates
+        let x = (u * self.width as f64 - 0.5).clamp(0.0, (self.width - 1) as f64);
+        let y = ((1.0 - v) * self.height as f64 - 0.5).clamp(0.0, (self.height - 1) as f64);
+        let x0 = x.floor() as usize;
+        let y0 = y.floor() as usize;
+        let x1 = (x0 + 1).min(self.width - 1);
+        let y1 = (y0 + 1).min(self.height - 1);
+        let tx = x - x0 as f64;
+        let ty = y - y0 as f64;
+
+        let top = self.pixel(x0, y0) + tx * (self.pixel(x1, y0) - self.pixel(x0, y0));
+        let bottom = self.pixel(x0, y1) + tx * (self.pixel(x1, y1) - self.pixel(x0, y1));
+        top + ty * (bottom - top)
+    }
+}
+
+/// Reads whitespace-separated header tokens, skipping # comments
+struct PgmReader<'a> {
+    bytes: &'a [u8],
+    position: usize
+}
+
+impl<'a> PgmReader<'a> {
+    fn token(&mut self) -> Result<String, HeightFieldError> {
+        loop {
+            match self.bytes.get(self.position) {
+                Some(b'#') => {
+                    while !matches!(self.bytes.get(self.position), Some(b'\n') | None) {
+                        self.position += 1;
+                    }
+                },
+                Some(b) if b.is_ascii_whitespace() => self.position += 1,
+                Some(_) => break,
+                None => return Err(HeightFieldError::Pgm("unexpected end of file".to_string()))
+            }
+        }
+
+        let start = self.position;
+        while matches!(self.bytes.get(self.position), Some(b) if !b.is_ascii_whitespace()) {
+            self.position += 1;
+        }
+        Ok(String::from_utf8_lossy(&self.bytes[start..self.position]).into_owned())
+    }
+
+    fn number(&mut self) -> Result<usize, HeightFieldError> {
+        let token = self.token()?;
+        token.parse()
+            .map_err(|_| HeightFieldError::Pgm(format!("expected a number, got {}", token)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn modulation(field: HeightField) -> HeightModulation {
+        HeightModulation {
+            field,
+            min: 1.0,
+            max: 3.0
+        }
+    }
+
+    #[test]
+    fn radial_falls_off_from_center() {
+        let modulation = modulation(HeightField::Radial {
+            center: (1.0, 0.0),
+            radius: 2.0
+        });
+        let sampler = modulation.sampler().unwrap();
+        assert_eq!(sampler.factor((1.0, 0.0, 0.0)), 3.0);
+        assert_eq!(sampler.factor((1.0, 1.0, 0.0)), 2.0);
+        assert_eq!(sampler.factor((5.0, 0.0, 0.0)), 1.0);
+    }
+
+    #[test]
+    fn towers_outside_the_field_keep_min_height() {
+        let modulation: HeightModulation = serde_json::from_str(
+            r#"{"field": {"Radial": {"center": [100, 100], "radius": 1}}}"#
+        ).unwrap();
+        let sampler = modulation.sampler().unwrap();
+        assert_eq!(sampler.factor((0.0, 0.0, 0.0)), 0.25);
+    }
+
+    #[test]
+    fn rejects_bad_ranges() {
+        for (min, max) in [(0.0, 1.0), (-1.0, 1.0), (2.0, 1.0)] {
+            let modulation = HeightModulation {
+                min,
+                max,
+                ..modulation(HeightField::Radial { center: (0.0, 0.0), radius: 1.0 })
+            };
+            assert!(matches!(modulation.sampler(), Err(HeightFieldError::Range(..))));
+        }
+    }
+
+    #[test]
+    fn expression_is_clamped() {
+        let expression = modulation(HeightField::Expression("x / 4 + y".to_string()));
+        let sampler = expression.sampler().unwrap();
+        assert_eq!(sampler.factor((2.0, 0.0, 0.0)), 2.0);
+        assert_eq!(sampler.factor((2.0, 5.0, 0.0)), 3.0);
+        assert_eq!(sampler.factor((-2.0, 0.0, 0.0)), 1.0);
+
+        let invalid = modulation(HeightField::Expression("x +".to_string()));
+        assert!(matches!(invalid.sampler(), Err(HeightFieldError::Expression(_))));
+    }
+
+    #[test]
+    fn noise_is_repeatable_and_in_range() {
+        let modulation = modulation(HeightField::Noise {
+            scale: 0.7,
+            seed: 3,
+            octaves: Some(3)
+        });
+        let sampler = modulation.sampler().unwrap();
+        for i in -20..20 {
+            let point = (i as f64 * 0.37, i as f64 * -0.21, 0.0);
+            let factor = sampler.factor(point);
+            assert!((1.0..=3.0).contains(&factor));
+            assert_eq!(factor, sampler.factor(point));
+        }
+    }
+
+    #[test]
+    fn parse_ascii_and_binary_pgm() {
+        let ascii = Heightmap::parse(b"P2\n# comment\n2 2\n4\n0 4\n2 4\n").unwrap();
+        assert_eq!(ascii.values, vec![0.0, 1.0, 0.5, 1.0]);
+
+        let mut binary = b"P5 2 1 255\n".to_vec();
+        binary.extend_from_slice(&[255, 0]);
+        let binary = Heightmap::parse(&binary).unwrap();
+        assert_eq!(binary.values, vec![1.0, 0.0]);
+
+        assert!(Heightmap::parse(b"P6 1 1 255\n\0\0\0").is_err());
+        assert!(Heightmap::parse(b"P5 2 2 255\n\0").is_err());
+    }
+
+    #[test]
+    fn image_samples_with_top_row_at_far_edge() {
+        let image = Heightmap::parse(b"P2 1 2 1\n1\n0\n").unwrap();
+        assert_eq!(image.sample(0.5, 1.0), 1.0);
+        assert_eq!(image.sample(0.5, 0.0), 0.0);
+        assert_eq!(image.sample(0.5, 0.5), 0.5);
+    }
+}
@@ -1,24 +1,87 @@
+mod coloring;
+mod expression;
+mod glb;
 mod gltf;
+mod height;
 mod mesh;
+mod profile;
+mod region;
+mod skeleton;
 mod tiling;
 mod tiling_mesh;
 mod towers;
+mod variation;
 mod vec3;
 
 use std::fs::File;
 use std::io::BufReader;
 
+use crate::glb::GlbContents;
 use crate::tiling::IntegerTiling;
 use crate::tiling_mesh::TilingMesh;
 
 fn main() {
-    let file = File::open("input/test-tiling.json").unwrap();
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("combine") {
+        combine(&args[2..]);
+        return;
+    }
+
+    // Other examples can be passed as the first argument
+    let input = args.get(1).map_or("input/test-tiling.json", String::as_str);
+    let file = File::open(input).unwrap();
     let reader = BufReader::new(file);
     
     let tiling: IntegerTiling = serde_json::from_reader(reader).unwrap();
     let mut towers = TilingMesh::new(tiling);
     towers.compute_mesh();
     towers.save_base("output/test-base.obj");
-    towers.make_towers();
-    towers.save_towers("output/test-towers.glb", 3);
+    match towers.make_towers(3) {
+        Ok(warnings) => print_warnings(&warnings),
+        Err(error) => {
+            eprintln!("Could not generate towers: {}", error);
+            std::process::exit(1);
+        }
+    }
+    match towers.save_towers("output/test-towers.glb") {
+        Ok(warnings) => print_warnings(&warnings),
+        Err(error) => {
+            eprintln!("Could not save towers: {}", error);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn print_warnings(warnings: &[String]) {
+    for warning in warnings {
+        eprintln!("Warning: {}", warning);
+    }
+}
+
+/// Combine previously exported GLB files into one OBJ file. Usage:
+/// integer-tilings combine <output.obj> <input.glb>...
+fn combine(args: &[String]) {
+    let (output, inputs) = match args.split_first() {
+        Some((output, inputs)) if !inputs.is_empty() => (output, inputs),
+        _ => {
+            eprintln!("Usage: integer-tilings combine <output.obj> <input.glb>...");
+            std::process::exit(1);
+        }
+    };
+
+    let mut contents = GlbContents::new();
+    for input in inputs {
+        let instanced_nodes = contents.instance_translations.len();
+        if let Err(error) = contents.add_file(input) {
+            eprintln!("Could not combine towers: {}", error);
+            std::process::exit(1);
+        }
+        if contents.instance_translations.len() > instanced_nodes {
+            print_warnings(&[format!(
+                "{} uses GPU instancing, only the original of each instanced node is combined",
+                input
+            )]);
+        }
+    }
+    contents.mesh.save_obj(output);
 }
@@ -1,12 +1,23 @@
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::fmt;
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::LineWriter;
 
-use crate::vec3::Vec3;
+use serde::Deserialize;
+
+use crate::profile::{InsetMode, ProfilePoint, ProfileScale};
+use crate::skeleton;
+use crate::vec3::{self, Vec3};
+
+/// Tolerance for comparing positions of congruent polygons
+const CONGRUENCE_TOLERANCE: f64 = 1e-9;
 
 pub struct Vertex {
     pub position: Vec3,
     pub half_edge: Option<usize>,
+    #[allow(dead_code)]
     pub deleted: bool
 }
 
@@ -43,13 +54,129 @@ impl HalfEdge {
 pub struct Face {
     pub half_edge: usize,
     pub normal: Option<Vec3>,
+    pub deleted: bool,
+    /// Material index, or None to use the default material for the mesh
+    pub material: Option<usize>
 }
 
 impl Face {
     pub fn new(half_edge: usize) -> Self {
         Self {
             half_edge,
-            normal: None
+            normal: None,
+            deleted: false,
+            material: None
+        }
+    }
+}
+
+/// How normals are assigned when triangulating a mesh
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+pub enum Shading {
+    /// Every face uses its face normal
+    #[default]
+    Flat,
+    /// Normals are averaged over neighboring faces unless the angle
+    /// between the faces (in degrees) is greater than the crease angle.
+    Smooth {
+        crease_angle: f64
+    }
+}
+
+/// How the top of a tower is closed off
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+pub enum CapStyle {
+    /// Keep the flat top face
+    #[default]
+    Flat,
+    /// Remove the top face, leaving the tower hollow
+    Open,
+    /// Triangles meeting at a point above the centroid
+    Pyramid {
+        height: f64
+    },
+    /// A roof where each side rises from one edge of the top face at the
+    /// given pitch in degrees, meeting along the straight skeleton
+    Hip {
+        pitch: f64
+    },
+    /// Rings of quads that curve up to a point above the centroid
+    Dome {
+        height: f64,
+        rings: usize
+    }
+}
+
+/// How texture coordinates are generated, see Mesh::corner_uvs()
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct UvMapping {
+    /// World units per texture repeat
+    pub scale: f64,
+    /// Walls are unwrapped around the line through center in the
+    /// direction of axis, which must be a unit vector
+    pub center: Vec3,
+    pub axis: Vec3
+}
+
+/// Triangulated mesh data for exporting
+#[derive(Clone)]
+pub struct Triangles {
+    pub positions: Vec<Vec3>,
+    pub normals: Vec<Vec3>,
+    pub indices: Vec<u32>,
+    /// Either empty or a texture coordinate for each vertex
+    pub uvs: Vec<(f64, f64)>,
+    /// Either empty or the feature ID of each vertex
+    pub feature_ids: Vec<u32>,
+    /// Either empty or a linear RGB color for each vertex
+    pub colors: Vec<Vec3>
+}
+
+impl Triangles {
+    pub fn new() -> Self {
+        Self {
+            positions: Vec::new(),
+            normals: Vec::new(),
+            indices: Vec::new(),
+            uvs: Vec::new(),
+            feature_ids: Vec::new(),
+            colors: Vec::new()
+        }
+    }
+
+    /// Add the triangles from other, offsetting its indices to point
+    /// past the vertices already here.
+    pub fn append(&mut self, other: Triangles) {
+        let offset = self.positions.len() as u32;
+        self.positions.extend(other.positions);
+        self.normals.extend(other.normals);
+        self.indices.extend(other.indices.iter().map(|index| index + offset));
+        self.uvs.extend(other.uvs);
+        self.feature_ids.extend(other.feature_ids);
+        self.colors.extend(other.colors);
+    }
+}
+
+#[derive(Debug)]
+pub enum MeshError {
+    /// The face has zero area (or non-finite positions) so it has no normal
+    DegenerateFace(usize),
+    /// Insets must move the vertices part of the way to the centroid
+    InsetAmount(f64),
+    /// No point of the face can see all of it, so a roof over it would
+    /// intersect itself
+    NotStarShaped(usize)
+}
+
+impl fmt::Display for MeshError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MeshError::DegenerateFace(face) => 
+                write!(f, "face {} is degenerate (it has zero area)", face),
+            MeshError::InsetAmount(amount) =>
+                write!(f, "inset amount must be between 0 and 1, got {}", amount),
+            MeshError::NotStarShaped(face) =>
+                write!(f, "face {} is not star-shaped, so its roof would intersect itself", face)
         }
     }
 }
@@ -58,6 +185,10 @@ pub struct Mesh {
     pub vertices: Vec<Vertex>,
     pub half_edges: Vec<HalfEdge>,
     pub faces: Vec<Face>,
+    /// Material for new faces, similar to usemtl in OBJ files
+    pub material: Option<usize>,
+    // (from_vertex, to_vertex) -> half edge, used for finding twins
+    edge_lookup: HashMap<(usize, usize), usize>
 }
 
 impl Mesh {
@@ -65,7 +196,9 @@ impl Mesh {
         Self {
             vertices: Vec::new(),
             half_edges: Vec::new(),
-            faces: Vec::new()
+            faces: Vec::new(),
+            material: None,
+            edge_lookup: HashMap::new()
         }
     }
 
@@ -84,21 +217,29 @@ impl Mesh {
         let n = vertices.len();
 
         // Create edges for the sides of the faces, one per vertex of the face
-        for i in 0..n {
+        for (i, &vert_index) in vertices.iter().enumerate() {
             // Index of the edge to be created
             let half_edge_index = self.half_edges.len();
             new_edges.push(half_edge_index);
             
             // Create the half edge rooted at the current vertex
-            let vert_index = vertices[i];
             let half_edge = HalfEdge::new(vert_index);
             self.half_edges.push(half_edge);
             
             // If this is the first half edge attached to the vertex,
             // point to this half edge
-            if let None = self.vertices[vert_index].half_edge {
-                self.vertices[i].half_edge = Some(half_edge_index);
+            if self.vertices[vert_index].half_edge.is_none() {
+                self.vertices[vert_index].half_edge = Some(half_edge_index);
+            }
+
+            // If a neighboring face already has the opposite half edge,
+            // link the two as twins
+            let to_index = vertices[(i + 1) % n];
+            if let Some(&twin) = self.edge_lookup.get(&(to_index, vert_index)) {
+                self.half_edges[twin].twin = Some(half_edge_index);
+                self.half_edges[half_edge_index].twin = Some(twin);
             }
+            self.edge_lookup.insert((vert_index, to_index), half_edge_index);
         }
 
         // Connect the edges into a loop
@@ -112,61 +253,98 @@ impl Mesh {
         // Create the face
         let first_edge_index = new_edges[0];
         let face_index = self.faces.len();
-        let face = Face::new(first_edge_index);
+        let mut face = Face::new(first_edge_index);
+        face.material = self.material;
         self.faces.push(face);
 
         // All the edges should point to the face
-        for i in 0..n {
-            let index = new_edges[i];
+        for index in new_edges {
             self.half_edges[index].face = Some(face_index)
         }
 
         index
     }
 
-    pub fn compute_face_normals(&mut self) {
-        for face in self.faces.iter_mut() {
+    /// Remove a face from the mesh. The face is only marked as deleted so
+    /// face indices stay stable. Its half edges are detached from their
+    /// twins so new faces can be attached along the boundary.
+    pub fn delete_face(&mut self, face: usize) {
+        let edges: Vec<usize> = self.face_edge_iter(face).collect();
+        for edge in edges {
+            let from = self.half_edges[edge].from_vertex;
+            let to = self.half_edges[self.half_edges[edge].next.unwrap()].from_vertex;
+            if self.edge_lookup.get(&(from, to)) == Some(&edge) {
+                self.edge_lookup.remove(&(from, to));
+            }
+
+            if let Some(twin) = self.half_edges[edge].twin {
+                self.half_edges[twin].twin = None;
+            }
+            self.half_edges[edge].twin = None;
+            self.half_edges[edge].face = None;
+        }
+
+        self.faces[face].deleted = true;
+    }
+
+    pub fn compute_face_normals(&mut self) -> Result<(), MeshError> {
+        for i in 0..self.faces.len() {
+            let face = &self.faces[i];
             // Normal was already computed
-            if let Some(_) = face.normal {
+            if face.deleted || face.normal.is_some() {
                 continue;
             }
 
-            // This assumes the face is a coplanar n-gon
-            // so the normal of the triangle spanned by the first
-            // 3 edges will give the normal
-            let edge1 = &self.half_edges[face.half_edge];
-            let edge2 = &self.half_edges[edge1.next.unwrap()];
-            let edge3 = &self.half_edges[edge2.next.unwrap()];
-
-            let (ax, ay, az) = &self.vertices[edge1.from_vertex].position;
-            let (bx, by, bz) = &self.vertices[edge2.from_vertex].position;
-            let (cx, cy, cz) = &self.vertices[edge3.from_vertex].position;
+            let positions = self.get_face_positions(i);
+            let normal = Self::compute_polygon_normal(&positions)
+                .ok_or(MeshError::DegenerateFace(i))?;
+            self.faces[i].normal = Some(normal);
+        }
 
-            let (bax, bay, baz) = (ax - bx, ay - by, az - bz);
-            let (bcx, bcy, bcz) = (cx - bx, cy - by, cz - bz);
+        Ok(())
+    }
 
-            // compute the cross product bc x ba
-            // |  x   y   z  |
-            // | bcx bcy bcz |
-            // | bax bay baz |
-            let nx = bcy * baz - bcz * bay;
-            let ny = bcz * bax - bcx * baz;
-            let nz = bcx * bay - bcy * bax;
+    /// Compute the unit normal of a polygon with Newell's method. This
+    /// uses every edge of the polygon, so it works even when some
+    /// vertices are collinear, and it averages out small deviations from
+    /// planarity. Returns None if the polygon has no area
+    pub fn compute_polygon_normal(positions: &[Vec3]) -> Option<Vec3> {
+        let n = positions.len();
+        let mut normal = (0.0, 0.0, 0.0);
+        let mut perimeter = 0.0;
+        for i in 0..n {
+            let current = positions[i];
+            let next = positions[(i + 1) % n];
+            let (x1, y1, z1) = current;
+            let (x2, y2, z2) = next;
+            normal = vec3::add(normal, (
+                (y1 - y2) * (z1 + z2),
+                (z1 - z2) * (x1 + x2),
+                (x1 - x2) * (y1 + y2)
+            ));
+            perimeter += vec3::length(vec3::sub(next, current));
+        }
 
-            let length = (nx * nx + ny * ny + nz * nz).sqrt();
-            face.normal = Some((nx / length, ny / length, nz / length));
+        // The length of Newell's normal is twice the area of the polygon.
+        // Compare it to the perimeter so the tolerance is scale-invariant
+        const AREA_TOLERANCE: f64 = 1e-10;
+        let length = vec3::length(normal);
+        if !length.is_finite() || length <= AREA_TOLERANCE * perimeter * perimeter {
+            return None;
         }
+
+        Some(vec3::scale(normal, 1.0 / length))
     }
 
-    pub fn all_vertices(&self) -> std::slice::Iter<Vertex> {
+    pub fn all_vertices(&self) -> std::slice::Iter<'_, Vertex> {
         self.vertices.iter()
     }
 
-    pub fn all_faces(&self) -> std::slice::Iter<Face> {
+    pub fn all_faces(&self) -> std::slice::Iter<'_, Face> {
         self.faces.iter()
     }
 
-    pub fn face_edge_iter(&self, face: usize) -> FaceEdgeIter {
+    pub fn face_edge_iter(&self, face: usize) -> FaceEdgeIter<'_> {
         FaceEdgeIter::new(self, face)
     }
 
@@ -196,6 +374,9 @@ impl Mesh {
                 self.add_vertex(extrude_position)
             })
             .collect();
+
+        // The original face is now inside the solid
+        self.delete_face(face_index);
         
         // Create new faces for the sides
         // new1 -- new2
@@ -208,7 +389,6 @@ impl Mesh {
             let new1 = new_vertices[i];
             let new2 = new_vertices[(i + 1) % n];
 
-            // TODO: This should check for existing edges
             self.add_face(&[old1, old2, new2, new1]);
         }
 
@@ -216,7 +396,21 @@ impl Mesh {
         self.add_face(&new_vertices)
     }
 
-    fn compute_centroid(positions: &[Vec3]) -> Vec3 {
+    /// Compute the area of a planar polygon in any orientation
+    pub fn compute_area(positions: &[Vec3]) -> f64 {
+        let n = positions.len();
+        let origin = positions.first().copied().unwrap_or((0.0, 0.0, 0.0));
+        let double_area = (1..n.saturating_sub(1))
+            .map(|i| {
+                let a = vec3::sub(positions[i], origin);
+                let b = vec3::sub(positions[i + 1], origin);
+                vec3::cross(a, b)
+            })
+            .fold((0.0, 0.0, 0.0), vec3::add);
+        0.5 * vec3::length(double_area)
+    }
+
+    pub fn compute_centroid(positions: &[Vec3]) -> Vec3 {
         let mut cx = 0.0;
         let mut cy = 0.0;
         let mut cz = 0.0;
@@ -230,7 +424,119 @@ impl Mesh {
         (cx / n, cy / n, cz / n)
     }
 
-    pub fn extrude_profile(&mut self, face: usize, profile: &[(i32, i32)]) -> usize {
+    /// Find the rotation about the z-axis that maps polygon a onto polygon
+    /// b, both relative to their centroids. The vertices can start at
+    /// different corners but must wind the same way.
+    pub fn find_rotation(a: &[Vec3], b: &[Vec3]) -> Option<f64> {
+        let n = a.len();
+        if n != b.len() || n == 0 {
+            return None;
+        }
+
+        let (ax, ay, _) = a[0];
+        (0..n).find_map(|shift| {
+            let (bx, by, _) = b[shift];
+            let rotation = (ax * by - ay * bx).atan2(ax * bx + ay * by);
+            let (sin, cos) = rotation.sin_cos();
+            let matches = (0..n).all(|i| {
+                let (x, y, z) = a[i];
+                let rotated = (cos * x - sin * y, sin * x + cos * y, z);
+                vec3::length(vec3::sub(rotated, b[(i + shift) % n])) < CONGRUENCE_TOLERANCE
+            });
+            if matches {
+                Some(rotation)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Move the vertices of a polygon inwards by amount, see InsetMode for
+    /// what amount means in each mode
+    pub fn inset_positions(
+        positions: &[Vec3],
+        normal: Vec3,
+        mode: InsetMode,
+        amount: f64
+    ) -> Vec<Vec3> {
+        match mode {
+            InsetMode::Proportional => {
+                let centroid = Self::compute_centroid(positions);
+                positions.iter()
+                    .map(|position| vec3::lerp(*position, centroid, amount))
+                    .collect()
+            },
+            InsetMode::Skeleton => skeleton::offset_polygon(positions, normal, amount)
+        }
+    }
+
+    /// Check that an inset copy of a polygon is still a simple polygon
+    /// that winds the same way around normal, and that none of its edges
+    /// flipped direction.
+    pub fn inset_keeps_shape(original: &[Vec3], inset: &[Vec3], normal: Vec3) -> bool {
+        let n = original.len();
+        let edges_kept = (0..n).all(|i| {
+            let j = (i + 1) % n;
+            let original_edge = vec3::sub(original[j], original[i]);
+            let inset_edge = vec3::sub(inset[j], inset[i]);
+            vec3::dot(original_edge, inset_edge) > 0.0
+        });
+
+        let winding = |positions: &[Vec3]| {
+            let double_area = (0..n)
+                .map(|i| vec3::cross(positions[i], positions[(i + 1) % n]))
+                .fold((0.0, 0.0, 0.0), vec3::add);
+            vec3::dot(double_area, normal)
+        };
+        let same_winding = winding(original) * winding(inset) > 0.0;
+
+        edges_kept && same_winding && Self::is_simple(&Self::project_to_plane(inset, normal))
+    }
+
+    /// Check that no two edges of a polygon cross, other than neighbors
+    /// meeting at their shared vertex
+    fn is_simple(points: &[(f64, f64)]) -> bool {
+        let n = points.len();
+        let orientation = |a: (f64, f64), b: (f64, f64), c: (f64, f64)| {
+            let (ax, ay) = a;
+            let (bx, by) = b;
+            let (cx, cy) = c;
+            ((bx - ax) * (cy - ay) - (by - ay) * (cx - ax)).signum()
+        };
+        let crosses = |i: usize, j: usize| {
+            let (a, b) = (points[i], points[(i + 1) % n]);
+            let (c, d) = (points[j], points[(j + 1) % n]);
+            let (abc, abd) = (orientation(a, b, c), orientation(a, b, d));
+            let (cda, cdb) = (orientation(c, d, a), orientation(c, d, b));
+            if abc == 0.0 && abd == 0.0 {
+                // Collinear edges only cross if they overlap
+                let overlaps = |axis: fn((f64, f64)) -> f64| {
+                    axis(a).max(axis(b)) >= axis(c).min(axis(d))
+                        && axis(c).max(axis(d)) >= axis(a).min(axis(b))
+                };
+                return overlaps(|p| p.0) && overlaps(|p| p.1);
+            }
+            abc * abd <= 0.0 && cda * cdb <= 0.0
+        };
+
+        (0..n).all(|i| {
+            ((i + 2)..n)
+                .filter(|&j| (j + 1) % n != i)
+                .all(|j| !crosses(i, j))
+        })
+    }
+
+    /// Extrude a face along a profile, given as absolute (center, normal)
+    /// points in units of profile steps. See ProfileShape::sample()
+    /// step_materials optionally sets the material for the walls leading
+    /// up to each point of the profile.
+    pub fn extrude_profile(
+        &mut self,
+        face: usize,
+        profile: &[ProfilePoint],
+        scale: &ProfileScale,
+        step_materials: &[Option<usize>]
+    ) -> usize {
         let (nx, ny, nz) = self.faces[face].normal.unwrap();
         
         // get the original vertices
@@ -241,49 +547,44 @@ impl Mesh {
             .map(|v| self.vertices[*v].position)
             .collect();
 
-        // compute the direction towards the center of the face, this
-        // will be used for every extrusion
-        let (cx, cy, cz) = Self::compute_centroid(&old_positions);
-        let center_directions: Vec<Vec3> = old_positions.iter()
-            .map(|(x, y, z)| (cx - x, cy - y, cz - z))
-            .collect();
-
         // Using a custom coordiate system (center, normal)
-        // where center is the direction from the current face vertex to the
-        // centroid horizontally (center_directions above), and normal is
-        // a height offset. The profile is given in relative offsets
-        const CENTER_STEP: f64 = 1.0 / 8.0;
-        const NORMAL_STEP: f64 = 0.1;
-        let mut profile_c: i32 = 0;
-        let mut profile_n: i32 = 0;
+        // where center moves the walls inwards (see InsetMode), and normal
+        // is a height offset.
         let mut all_vertices: Vec<Vec<usize>> = vec![old_vertices];
-        for (center_offset, normal_offset) in profile {
-            profile_c += center_offset;
-            profile_n += normal_offset;
+        for (profile_c, profile_n) in profile {
+            let dc = profile_c * scale.center_step;
+            let dn = profile_n * scale.normal_step;
 
-            let dc = profile_c as f64 * CENTER_STEP;
-            let dn = profile_n as f64 * NORMAL_STEP;
+            let inset_positions = Self::inset_positions(
+                &old_positions, (nx, ny, nz), scale.inset_mode, dc);
 
-            let layer_vertices: Vec<usize> = old_positions.iter()
-                .enumerate()
-                .map(|(i, (x, y, z))| {
-                    let (cx, cy, cz) = center_directions[i];
+            let layer_vertices: Vec<usize> = inset_positions.iter()
+                .map(|(x, y, z)| {
                     let position = (
-                        x + dc * cx + dn * nx,
-                        y + dc * cy + dn * ny,
-                        z + dc * cz + dn * nz
+                        x + dn * nx,
+                        y + dn * ny,
+                        z + dn * nz
                     );
                     self.add_vertex(position)
                 })
                 .collect();
             
-                all_vertices.push(layer_vertices);
+            all_vertices.push(layer_vertices);
         }
 
+        // The original face is replaced by the extruded portion
+        self.delete_face(face);
+
         // Create the sides of the extruded portion
-        for i in 0..(all_vertices.len() - 1) {
-            let current_layer = &all_vertices[i];
-            let next_layer = &all_vertices[i + 1];
+        let default_material = self.material;
+        for (step, layers) in all_vertices.windows(2).enumerate() {
+            self.material = step_materials.get(step)
+                .copied()
+                .flatten()
+                .or(default_material);
+
+            let current_layer = &layers[0];
+            let next_layer = &layers[1];
 
             let n = current_layer.len();
             for j in 0..n {
@@ -295,59 +596,728 @@ impl Mesh {
             }
         }
 
+        self.material = default_material;
+
         // create a new face on the top
 
         let top = &all_vertices[all_vertices.len() - 1];
         self.add_face(top)
     }
 
-    pub fn triangulate(&self) -> (Vec<Vec3>, Vec<Vec3>, Vec<u32>) {
+    /// Shrink a face into a border ring of quads around a smaller copy
+    /// of the face. amount is the fraction of the way each vertex moves
+    /// towards the centroid, so it must be in (0, 1). The original face is
+    /// deleted, and the index of the inner face is returned.
+    pub fn inset(&mut self, face: usize, amount: f64) -> Result<usize, MeshError> {
+        // 0 makes zero-area quads, 1 or more collapses or flips the face
+        if !(amount > 0.0 && amount < 1.0) {
+            return Err(MeshError::InsetAmount(amount));
+        }
+
+        let old_vertices: Vec<usize> = self.face_edge_iter(face)
+            .map(|e| self.half_edges[e].from_vertex)
+            .collect();
+        let old_positions: Vec<Vec3> = old_vertices.iter()
+            .map(|v| self.vertices[*v].position)
+            .collect();
+        let centroid = Self::compute_centroid(&old_positions);
+
+        let new_vertices: Vec<usize> = old_positions.iter()
+            .map(|position| {
+                let inset_position = vec3::lerp(*position, centroid, amount);
+                self.add_vertex(inset_position)
+            })
+            .collect();
+
+        self.delete_face(face);
+
+        // Create the border ring
+        // new1 -- new2
+        //  |       |
+        // old1 -- old2
+        let n = old_vertices.len();
+        for i in 0..n {
+            let old1 = old_vertices[i];
+            let old2 = old_vertices[(i + 1) % n];
+            let new1 = new_vertices[i];
+            let new2 = new_vertices[(i + 1) % n];
+            self.add_face(&[old1, old2, new2, new1]);
+        }
+
+        Ok(self.add_face(&new_vertices))
+    }
+
+    /// Chamfer the edges around the boundary of a face. The face is inset
+    /// by amount (see inset()), then the original boundary is pushed back
+    /// along the face normal by depth. This replaces the sharp edges
+    /// with a slanted ring of quads, and the neighboring faces are shortened
+    /// to match. Face normals must be computed first.
+    /// The index of the new inner face is returned.
+    pub fn bevel(&mut self, face: usize, amount: f64, depth: f64) -> Result<usize, MeshError> {
+        let normal = self.faces[face].normal.unwrap();
+        let boundary: Vec<usize> = self.face_edge_iter(face)
+            .map(|e| self.half_edges[e].from_vertex)
+            .collect();
+
+        // The inner ring stays in the plane of the original face
+        let inner_face = self.inset(face, amount)?;
+
+        let push_back = vec3::scale(normal, -depth);
+        for &vertex in boundary.iter() {
+            let position = self.vertices[vertex].position;
+            self.vertices[vertex].position = vec3::add(position, push_back);
+        }
+
+        // Any face touching the boundary has moved, so its normal must be
+        // recomputed. Moving both ends of an edge by the same amount keeps
+        // the neighboring quads planar.
+        for i in 0..self.faces.len() {
+            if self.faces[i].deleted {
+                continue;
+            }
+
+            let touches_boundary = self.face_edge_iter(i)
+                .any(|e| boundary.contains(&self.half_edges[e].from_vertex));
+            if touches_boundary {
+                self.faces[i].normal = None;
+            }
+        }
+        self.faces[inner_face].normal = Some(normal);
+
+        Ok(inner_face)
+    }
+
+    /// Replace a face with a cap, see CapStyle. Face normals must be
+    /// computed first.
+    pub fn add_cap(&mut self, face: usize, style: CapStyle) -> Result<(), MeshError> {
+        let normal = self.faces[face].normal.unwrap();
+        let vertices: Vec<usize> = self.face_edge_iter(face)
+            .map(|e| self.half_edges[e].from_vertex)
+            .collect();
+        let positions: Vec<Vec3> = vertices.iter()
+            .map(|v| self.vertices[*v].position)
+            .collect();
+        let centroid = Self::compute_centroid(&positions);
+        let n = vertices.len();
+
+        match style {
+            CapStyle::Flat => {},
+            CapStyle::Open => self.delete_face(face),
+            CapStyle::Pyramid { height } => {
+                self.delete_face(face);
+                self.add_apex(&vertices, vec3::add(centroid, vec3::scale(normal, height)));
+            },
+            CapStyle::Hip { pitch } => {
+                let slope = pitch.to_radians().tan();
+                let roof = match skeleton::hip_roof(&positions, normal, slope) {
+                    Some(roof) => roof,
+                    None => {
+                        // Fall back to a pyramid as tall as the roof is
+                        // at the first skeleton event. The apex must see
+                        // every edge, or the triangles would overlap.
+                        let center = Self::kernel_center(&positions, normal)
+                            .ok_or(MeshError::NotStarShaped(face))?;
+                        let height = slope * skeleton::first_event(&positions, normal);
+                        self.delete_face(face);
+                        self.add_apex(&vertices, vec3::add(center, vec3::scale(normal, height)));
+                        return Ok(());
+                    }
+                };
+                self.delete_face(face);
+                let nodes: Vec<usize> = roof.nodes.iter()
+                    .map(|position| self.add_vertex(*position))
+                    .collect();
+                for roof_face in roof.faces {
+                    let face_vertices: Vec<usize> = roof_face.iter()
+                        .map(|&i| if i < n { vertices[i] } else { nodes[i - n] })
+                        .collect();
+                    self.add_face(&face_vertices);
+                }
+            },
+            CapStyle::Dome { height, rings } => {
+                self.delete_face(face);
+
+                // Each ring is a scaled copy of the face, following a
+                // quarter ellipse up to the apex
+                let rings = rings.max(1);
+                let mut previous_ring = vertices;
+                for k in 1..rings {
+                    let angle = (k as f64) / (rings as f64) * std::f64::consts::FRAC_PI_2;
+                    let lift = vec3::scale(normal, height * angle.sin());
+                    let ring: Vec<usize> = positions.iter()
+                        .map(|position| {
+                            let shrunk = vec3::lerp(centroid, *position, angle.cos());
+                            self.add_vertex(vec3::add(shrunk, lift))
+                        })
+                        .collect();
+                    for i in 0..n {
+                        let j = (i + 1) % n;
+                        self.add_face(&[previous_ring[i], previous_ring[j], ring[j], ring[i]]);
+                    }
+                    previous_ring = ring;
+                }
+
+                self.add_apex(&previous_ring, vec3::add(centroid, vec3::scale(normal, height)));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get a point strictly inside the kernel of a polygon, the region
+    /// that can see every edge. This is the intersection of the inner
+    /// half-planes of the edges, found by clipping a bounding square with
+    /// each one. None if the kernel is empty or has no area.
+    fn kernel_center(positions: &[Vec3], normal: Vec3) -> Option<Vec3> {
+        let origin = Self::compute_centroid(positions);
+        let u = vec3::normalize(vec3::sub(positions[1], positions[0]));
+        let v = vec3::cross(normal, u);
+        // Counterclockwise coordinates in the plane of the polygon
+        let points: Vec<(f64, f64)> = positions.iter()
+            .map(|position| {
+                let offset = vec3::sub(*position, origin);
+                (vec3::dot(offset, u), vec3::dot(offset, v))
+            })
+            .collect();
+
+        let size = points.iter()
+            .map(|(x, y)| x.abs().max(y.abs()))
+            .fold(0.0, f64::max) * 2.0;
+        let mut kernel = vec![(-size, -size), (size, -size), (size, size), (-size, size)];
+        let n = points.len();
+        for i in 0..n {
+            let (ax, ay) = points[i];
+            let (bx, by) = points[(i + 1) % n];
+            let side = |(x, y): (f64, f64)| (bx - ax) * (y - ay) - (by - ay) * (x - ax);
+
+            // Sutherland-Hodgman clipping against one half-plane
+            let mut clipped = Vec::new();
+            for j in 0..kernel.len() {
+                let p = kernel[j];
+                let q = kernel[(j + 1) % kernel.len()];
+                let (sp, sq) = (side(p), side(q));
+                if sp >= 0.0 {
+                    clipped.push(p);
+                }
+                if (sp >= 0.0) != (sq >= 0.0) {
+                    let t = sp / (sp - sq);
+                    clipped.push((p.0 + t * (q.0 - p.0), p.1 + t * (q.1 - p.1)));
+                }
+            }
+            kernel = clipped;
+        }
+
+        let m = kernel.len();
+        let double_area: f64 = (0..m)
+            .map(|i| {
+                let (ax, ay) = kernel[i];
+                let (bx, by) = kernel[(i + 1) % m];
+                ax * by - bx * ay
+            })
+            .sum();
+        if m < 3 || double_area <= 1e-9 * size * size {
+            return None;
+        }
+
+        let (x, y) = kernel.iter()
+            .fold((0.0, 0.0), |(sx, sy), (x, y)| (sx + x, sy + y));
+        let (x, y) = (x / m as f64, y / m as f64);
+        Some(vec3::add(origin, vec3::add(vec3::scale(u, x), vec3::scale(v, y))))
+    }
+
+    /// Close a ring of vertices with a fan of triangles to a new vertex
+    fn add_apex(&mut self, ring: &[usize], position: Vec3) {
+        let apex = self.add_vertex(position);
+        let n = ring.len();
+        for i in 0..n {
+            self.add_face(&[ring[i], ring[(i + 1) % n], apex]);
+        }
+    }
+
+    /// Apply one iteration of Catmull-Clark subdivision, producing a new
+    /// mesh made entirely of quads. Boundary edges (edges without a twin)
+    /// are treated as creases so open meshes keep their outline.
+    pub fn subdivide(&self) -> Mesh {
+        let live_faces: Vec<usize> = (0..self.faces.len())
+            .filter(|f| !self.faces[*f].deleted)
+            .collect();
+
+        // Face points are the centroids of each face
+        let mut face_points: HashMap<usize, Vec3> = HashMap::new();
+        for &face in live_faces.iter() {
+            let positions = self.get_face_positions(face);
+            face_points.insert(face, Self::compute_centroid(&positions));
+        }
+
+        // Gather the undirected edges, keyed by (min vertex, max vertex),
+        // along with the faces on either side. Also gather the neighbors
+        // of each vertex.
+        let mut edge_faces: HashMap<(usize, usize), Vec<usize>> = HashMap::new();
+        let mut vertex_faces: HashMap<usize, Vec<usize>> = HashMap::new();
+        let mut vertex_edges: HashMap<usize, Vec<(usize, usize)>> = HashMap::new();
+        for &face in live_faces.iter() {
+            for e in self.face_edge_iter(face) {
+                let from = self.half_edges[e].from_vertex;
+                let to = self.half_edges[self.half_edges[e].next.unwrap()].from_vertex;
+                let key = (from.min(to), from.max(to));
+
+                let faces = edge_faces.entry(key).or_default();
+                if faces.is_empty() {
+                    vertex_edges.entry(from).or_default().push(key);
+                    vertex_edges.entry(to).or_default().push(key);
+                }
+                faces.push(face);
+                vertex_faces.entry(from).or_default().push(face);
+            }
+        }
+
+        let mut result = Mesh::new();
+        result.material = self.material;
+
+        let mut face_vertices: HashMap<usize, usize> = HashMap::new();
+        for &face in live_faces.iter() {
+            face_vertices.insert(face, result.add_vertex(face_points[&face]));
+        }
+
+        let mut edge_vertices: HashMap<(usize, usize), usize> = HashMap::new();
+        for (key, faces) in edge_faces.iter() {
+            let (a, b) = *key;
+            let midpoint = vec3::lerp(
+                self.vertices[a].position, self.vertices[b].position, 0.5);
+
+            let edge_point = if faces.len() == 2 {
+                let face_midpoint = vec3::lerp(
+                    face_points[&faces[0]], face_points[&faces[1]], 0.5);
+                vec3::lerp(midpoint, face_midpoint, 0.5)
+            } else {
+                midpoint
+            };
+            edge_vertices.insert(*key, result.add_vertex(edge_point));
+        }
+
+        let mut vertex_points: HashMap<usize, usize> = HashMap::new();
+        for (&vertex, edges) in vertex_edges.iter() {
+            let position = self.vertices[vertex].position;
+            let boundary_neighbors: Vec<Vec3> = edges.iter()
+                .filter(|key| edge_faces[key].len() == 1)
+                .map(|&(a, b)| if a == vertex { b } else { a })
+                .map(|v| self.vertices[v].position)
+                .collect();
+
+            let vertex_point = if boundary_neighbors.len() == 2 {
+                // Boundary rule: 3/4 of the vertex plus 1/8 of each
+                // neighbor along the boundary
+                let neighbors = vec3::add(boundary_neighbors[0], boundary_neighbors[1]);
+                vec3::add(vec3::scale(position, 0.75), vec3::scale(neighbors, 0.125))
+            } else if !boundary_neighbors.is_empty() {
+                // Corner of a non-manifold boundary, leave it alone
+                position
+            } else {
+                // Interior rule: (F + 2R + (n - 3)P) / n
+                let faces = &vertex_faces[&vertex];
+                let face_average = Self::compute_centroid(&faces.iter()
+                    .map(|f| face_points[f])
+                    .collect::<Vec<Vec3>>());
+                let edge_average = Self::compute_centroid(&edges.iter()
+                    .map(|&(a, b)| vec3::lerp(
+                        self.vertices[a].position, self.vertices[b].position, 0.5))
+                    .collect::<Vec<Vec3>>());
+                let n = edges.len() as f64;
+                let sum = vec3::add(
+                    vec3::add(face_average, vec3::scale(edge_average, 2.0)),
+                    vec3::scale(position, n - 3.0));
+                vec3::scale(sum, 1.0 / n)
+            };
+            vertex_points.insert(vertex, result.add_vertex(vertex_point));
+        }
+
+        // Each n-gon becomes n quads
+        //   previous edge -- face point
+        //        |              |
+        //     vertex    --   next edge
+        for &face in live_faces.iter() {
+            let vertices: Vec<usize> = self.face_edge_iter(face)
+                .map(|e| self.half_edges[e].from_vertex)
+                .collect();
+            let n = vertices.len();
+            let edge_point = |i: usize| {
+                let a = vertices[i % n];
+                let b = vertices[(i + 1) % n];
+                edge_vertices[&(a.min(b), a.max(b))]
+            };
+
+            for i in 0..n {
+                let quad = result.add_face(&[
+                    vertex_points[&vertices[i]],
+                    edge_point(i),
+                    face_vertices[&face],
+                    edge_point(i + n - 1)
+                ]);
+                result.faces[quad].material = self.faces[face].material;
+            }
+        }
+
+        result
+    }
+
+    /// Triangulate the mesh, grouping faces by material. Faces without
+    /// a material use default_material. The groups are sorted by
+    /// material index. Normals are computed over the whole mesh so
+    /// smooth shading continues across material boundaries. If uvs is
+    /// set, texture coordinates are generated, see corner_uvs()
+    pub fn triangulate_by_material(
+        &self,
+        shading: Shading,
+        default_material: usize,
+        uvs: Option<UvMapping>
+    ) -> Vec<(usize, Triangles)> {
+        let corner_normals = self.corner_normals(shading);
+        let corner_uvs = uvs.map(|mapping| self.corner_uvs(mapping));
+
+        let mut materials: Vec<usize> = self.faces.iter()
+            .filter(|face| !face.deleted)
+            .map(|face| face.material.unwrap_or(default_material))
+            .collect();
+        materials.sort();
+        materials.dedup();
+
+        materials.into_iter()
+            .map(|material| {
+                let triangles = self.triangulate_faces(&corner_normals, corner_uvs.as_ref(), |face| {
+                    face.material.unwrap_or(default_material) == material
+                });
+                (material, triangles)
+            })
+            .collect()
+    }
+
+    fn corner_normals(&self, shading: Shading) -> HashMap<usize, Vec<Vec3>> {
+        match shading {
+            Shading::Flat => self.flat_corner_normals(),
+            Shading::Smooth { crease_angle } => 
+                self.smooth_corner_normals(crease_angle)
+        }
+    }
+
+    /// Texture coordinates for the corners of each face. Faces facing
+    /// mostly along the axis are projected onto the plane through the
+    /// center. Walls are unwrapped around the axis like a cylinder, using
+    /// the average distance of the wall vertices from the axis as the
+    /// radius, so the texture is only undistorted on walls that stay
+    /// close to a cylinder. v points down the texture, so it decreases
+    /// along the axis.
+    fn corner_uvs(&self, mapping: UvMapping) -> HashMap<usize, Vec<(f64, f64)>> {
+        let UvMapping { scale, center, axis } = mapping;
+        // Directions of u = 0 and of a quarter turn around the axis. For
+        // the z-axis these are x and y.
+        let (ax, _, _) = axis;
+        let reference = if ax.abs() < 0.9 { (1.0, 0.0, 0.0) } else { (0.0, 1.0, 0.0) };
+        let e1 = vec3::normalize(vec3::sub(reference, vec3::scale(axis, vec3::dot(reference, axis))));
+        let e2 = vec3::cross(axis, e1);
+        // (first, second, height) coordinates relative to the axis
+        let local = |position: Vec3| {
+            let offset = vec3::sub(position, center);
+            (vec3::dot(offset, e1), vec3::dot(offset, e2), vec3::dot(offset, axis))
+        };
+        let is_wall = |face: &Face| vec3::dot(face.normal.unwrap(), axis).abs() < 0.5;
+
+        let mut wall_vertices: Vec<usize> = (0..self.faces.len())
+            .filter(|&i| !self.faces[i].deleted && is_wall(&self.faces[i]))
+            .flat_map(|i| self.face_edge_iter(i).map(|e| self.half_edges[e].from_vertex))
+            .collect();
+        wall_vertices.sort();
+        wall_vertices.dedup();
+        let radius = wall_vertices.iter()
+            .map(|v| {
+                let (a, b, _) = local(self.vertices[*v].position);
+                a.hypot(b)
+            })
+            .sum::<f64>() / wall_vertices.len().max(1) as f64;
+
+        let mut result = HashMap::new();
+        for (i, face) in self.faces.iter().enumerate() {
+            if face.deleted {
+                continue;
+            }
+
+            let positions: Vec<Vec3> = self.get_face_positions(i).into_iter()
+                .map(local)
+                .collect();
+            let uvs = if !is_wall(face) {
+                positions.iter()
+                    .map(|(a, b, _)| (a / scale, -b / scale))
+                    .collect()
+            } else {
+                // Unwrap the angles relative to the first corner so faces
+                // that cross the seam at angle pi don't wrap around
+                let (a0, b0, _) = positions[0];
+                let start_angle = b0.atan2(a0);
+                positions.iter()
+                    .map(|(a, b, height)| {
+                        let offset = (b.atan2(*a) - start_angle + PI).rem_euclid(2.0 * PI) - PI;
+                        ((start_angle + offset) * radius / scale, -height / scale)
+                    })
+                    .collect()
+            };
+            result.insert(i, uvs);
+        }
+        result
+    }
+
+    fn triangulate_faces(
+        &self,
+        corner_normals: &HashMap<usize, Vec<Vec3>>,
+        corner_uvs: Option<&HashMap<usize, Vec<(f64, f64)>>>,
+        include_face: impl Fn(&Face) -> bool
+    ) -> Triangles {
         let mut positions = Vec::new();
         let mut normals = Vec::new();
+        let mut uvs = Vec::new();
         let mut indices = Vec::new();
 
-        let mut vertex_count: usize = 0;
+        // Corners that share a vertex, a normal and texture coordinates
+        // can share an output vertex. Values are rounded so tiny floating
+        // point differences don't prevent sharing.
+        const PRECISION: f64 = 1e6;
+        let mut output_vertices: HashMap<(usize, [i64; 5]), u32> = HashMap::new();
+
         for (i, face) in self.faces.iter().enumerate() {
-            let normal = face.normal.unwrap();
+            if face.deleted || !include_face(face) {
+                continue;
+            }
 
-            let ngon_positions: Vec<Vec3> = self.face_edge_iter(i)
+            let face_vertices: Vec<usize> = self.face_edge_iter(i)
                 .map(|e| self.half_edges[e].from_vertex)
-                .map(|v| self.vertices[v].position)
+                .collect();
+            let ngon_positions: Vec<Vec3> = face_vertices.iter()
+                .map(|v| self.vertices[*v].position)
                 .collect();
 
-            let n: usize = ngon_positions.len();
+            let face_uvs = corner_uvs.map(|corner_uvs| &corner_uvs[&i]);
+            let face_indices: Vec<u32> = face_vertices.iter()
+                .zip(corner_normals[&i].iter())
+                .enumerate()
+                .map(|(corner, (&vertex, &normal))| {
+                    let (nx, ny, nz) = normal;
+                    let uv = face_uvs.map(|face_uvs| face_uvs[corner]);
+                    let (u, v) = uv.unwrap_or((0.0, 0.0));
+                    let key = (vertex, [
+                        (nx * PRECISION).round() as i64,
+                        (ny * PRECISION).round() as i64,
+                        (nz * PRECISION).round() as i64,
+                        (u * PRECISION).round() as i64,
+                        (v * PRECISION).round() as i64
+                    ]);
+                    *output_vertices.entry(key).or_insert_with(|| {
+                        positions.push(self.vertices[vertex].position);
+                        normals.push(normal);
+                        if let Some(uv) = uv {
+                            uvs.push(uv);
+                        }
+                        (positions.len() - 1) as u32
+                    })
+                })
+                .collect();
 
-            for j in 0..n {
-                // Since the normals differ, we have to duplicate the positions
-                // here
-                positions.push(ngon_positions[j]);
-                normals.push(normal);
+            let normal = face.normal.unwrap();
+            for offset in Self::triangulate_polygon(&ngon_positions, normal) {
+                indices.push(face_indices[offset]);
             }
+        }
 
-            for offset in Self::triangulate_ngon(n) {
-                indices.push((vertex_count + offset) as u32);
+        Triangles {
+            positions,
+            normals,
+            indices,
+            uvs,
+            feature_ids: Vec::new(),
+            colors: Vec::new()
+        }
+    }
+
+    /// For flat shading, every corner of a face uses the face normal
+    fn flat_corner_normals(&self) -> HashMap<usize, Vec<Vec3>> {
+        let mut result = HashMap::new();
+        for (i, face) in self.faces.iter().enumerate() {
+            if face.deleted {
+                continue;
             }
-            vertex_count += n;
+
+            let n = self.face_edge_iter(i).count();
+            result.insert(i, vec![face.normal.unwrap(); n]);
         }
+        result
+    }
 
-        (positions, normals, indices)
+    /// For smooth shading, each corner of a face averages the normals
+    /// of the faces around the vertex, weighted by the angle of each
+    /// face at the vertex. Faces that meet the current face at more than
+    /// the crease angle are left out so sharp edges stay sharp.
+    fn smooth_corner_normals(&self, crease_angle: f64) -> HashMap<usize, Vec<Vec3>> {
+        // vertex -> list of (face normal, angle of the face at this vertex)
+        let mut vertex_faces: HashMap<usize, Vec<(Vec3, f64)>> = HashMap::new();
+        for (i, face) in self.faces.iter().enumerate() {
+            if face.deleted {
+                continue;
+            }
+
+            let normal = face.normal.unwrap();
+            let positions = self.get_face_positions(i);
+            let n = positions.len();
+            for (j, e) in self.face_edge_iter(i).enumerate() {
+                let vertex = self.half_edges[e].from_vertex;
+                let to_previous = vec3::sub(positions[(j + n - 1) % n], positions[j]);
+                let to_next = vec3::sub(positions[(j + 1) % n], positions[j]);
+                let cos_angle = vec3::dot(to_previous, to_next) / 
+                    (vec3::length(to_previous) * vec3::length(to_next));
+                let angle = cos_angle.clamp(-1.0, 1.0).acos();
+
+                vertex_faces.entry(vertex).or_default().push((normal, angle));
+            }
+        }
+
+        let min_cos = crease_angle.to_radians().cos();
+        let mut result = HashMap::new();
+        for (i, face) in self.faces.iter().enumerate() {
+            if face.deleted {
+                continue;
+            }
+
+            let face_normal = face.normal.unwrap();
+            let normals: Vec<Vec3> = self.face_edge_iter(i)
+                .map(|e| {
+                    let vertex = self.half_edges[e].from_vertex;
+                    let sum = vertex_faces[&vertex].iter()
+                        .filter(|(normal, _)| vec3::dot(*normal, face_normal) >= min_cos)
+                        .fold((0.0, 0.0, 0.0), |sum, (normal, angle)| {
+                            vec3::add(sum, vec3::scale(*normal, *angle))
+                        });
+
+                    // Zero-angle corners can cancel out, fall back to the
+                    // face normal
+                    if vec3::length(sum) > 0.0 {
+                        vec3::normalize(sum)
+                    } else {
+                        face_normal
+                    }
+                })
+                .collect();
+            result.insert(i, normals);
+        }
+
+        result
     }
 
-    pub fn triangulate_ngon(n: usize) -> Vec<usize> {
-        let mut result = Vec::new();
+    /// Triangulate a simple planar polygon (convex or not) by ear
+    /// clipping. The polygon is projected onto the coordinate plane that
+    /// is most perpendicular to the normal. The result is a list of
+    /// triangles as offsets into positions, with the same winding order as
+    /// the original polygon
+    pub fn triangulate_polygon(positions: &[Vec3], normal: Vec3) -> Vec<usize> {
+        let points = Self::project_to_plane(positions, normal);
+        let n = points.len();
+
+        // Shoelace formula to find the winding order of the polygon in
+        // the projected coordinates. Ears must turn the same way.
+        let mut double_area = 0.0;
+        for i in 0..n {
+            let (ax, ay) = points[i];
+            let (bx, by) = points[(i + 1) % n];
+            double_area += ax * by - bx * ay;
+        }
+        let orientation = if double_area < 0.0 { -1.0 } else { 1.0 };
+
+        // Tolerance scaled to the size of the polygon
+        let epsilon = 1e-12 * double_area.abs().max(1e-12);
 
-        let first = 0;
-        for i in 0..(n - 2) {
-            let second = i + 1;
-            let third = i + 2;
-            result.push(first);
-            result.push(second);
-            result.push(third);
+        let turn = |a: usize, b: usize, c: usize| {
+            let (ax, ay) = points[a];
+            let (bx, by) = points[b];
+            let (cx, cy) = points[c];
+            orientation * ((bx - ax) * (cy - by) - (by - ay) * (cx - bx))
+        };
+
+        let mut remaining: Vec<usize> = (0..n).collect();
+        let mut result = Vec::new();
+        while remaining.len() > 3 {
+            let m = remaining.len();
+            let corner = |i: usize| (
+                remaining[(i + m - 1) % m],
+                remaining[i],
+                remaining[(i + 1) % m]
+            );
+
+            // Count the other vertices inside the triangle at a corner.
+            // Vertices that coincide with a corner of the triangle (e.g.
+            // from a slit in the polygon) are ignored
+            let inside_count = |i: usize| {
+                let (a, b, c) = corner(i);
+                remaining.iter()
+                    .filter(|&&v| v != a && v != b && v != c)
+                    .filter(|&&v| {
+                        points[v] != points[a] &&
+                        points[v] != points[b] &&
+                        points[v] != points[c]
+                    })
+                    .filter(|&&v| {
+                        turn(a, b, v) >= -epsilon &&
+                        turn(b, c, v) >= -epsilon &&
+                        turn(c, a, v) >= -epsilon
+                    })
+                    .count()
+            };
+            let is_convex = |i: usize| {
+                let (a, b, c) = corner(i);
+                turn(a, b, c) >= -epsilon
+            };
+            let is_ear = |i: usize| {
+                let (a, b, c) = corner(i);
+                turn(a, b, c) > epsilon && inside_count(i) == 0
+            };
+
+            // If there are no proper ears left, the remaining polygon is
+            // degenerate (e.g. collinear vertices) or not simple. Clip the
+            // convex or flat vertex with the fewest vertices inside its
+            // triangle, since a reflex vertex would make a flipped triangle.
+            let ear = (0..m).find(|&i| is_ear(i))
+                .or_else(|| (0..m).filter(|&i| is_convex(i)).min_by_key(|&i| inside_count(i)));
+            let ear = match ear {
+                Some(ear) => ear,
+                // Every triangle left would be flipped
+                None => return result
+            };
+
+            let (a, b, c) = corner(ear);
+            result.extend_from_slice(&[a, b, c]);
+            remaining.remove(ear);
+        }
+        if let [a, b, c] = remaining[..] {
+            if turn(a, b, c) >= -epsilon {
+                result.extend_from_slice(&remaining);
+            }
         }
 
         result
     }
 
+    /// Project positions onto the coordinate plane most perpendicular to
+    /// the normal
+    fn project_to_plane(positions: &[Vec3], normal: Vec3) -> Vec<(f64, f64)> {
+        let (nx, ny, nz) = normal;
+        let (ax, ay, az) = (nx.abs(), ny.abs(), nz.abs());
+        positions.iter()
+            .map(|&(x, y, z)| {
+                if az >= ax && az >= ay {
+                    (x, y)
+                } else if ay >= ax {
+                    (z, x)
+                } else {
+                    (y, z)
+                }
+            })
+            .collect()
+    }
+
     pub fn save_obj(&self, fname: &str) {
         let file = File::create(fname).expect("could not open file");
         let mut file = LineWriter::new(file);
@@ -359,7 +1329,11 @@ impl Mesh {
                 .expect("could not write vertex line");
         }
 
-        for (i, _) in self.all_faces().enumerate() {
+        for (i, face) in self.all_faces().enumerate() {
+            if face.deleted {
+                continue;
+            }
+
             let indices = self.face_edge_iter(i)
                 .map(|i| self.half_edges[i].from_vertex);
             let obj_indices = indices
@@ -474,4 +1448,617 @@ mod tests {
         assert_eq!(mesh.faces[0].half_edge, 0);
         assert!(mesh.faces[0].normal.is_none());
     }
-}
\ No newline at end of file
+
+    fn make_square(mesh: &mut Mesh) -> usize {
+        let a = mesh.add_vertex((0.0, 0.0, 0.0));
+        let b = mesh.add_vertex((1.0, 0.0, 0.0));
+        let c = mesh.add_vertex((1.0, 1.0, 0.0));
+        let d = mesh.add_vertex((0.0, 1.0, 0.0));
+        mesh.add_face(&[a, b, c, d])
+    }
+
+    fn live_faces(mesh: &Mesh) -> Vec<usize> {
+        (0..mesh.faces.len())
+            .filter(|f| !mesh.faces[*f].deleted)
+            .collect()
+    }
+
+    #[test]
+    fn twins_are_linked() {
+        let mut mesh = Mesh::new();
+        let face = make_square(&mut mesh);
+        let reversed = mesh.add_face(&[3, 2, 1, 0]);
+
+        for e in mesh.face_edge_iter(face) {
+            let twin = mesh.half_edges[e].twin.expect("edge should have a twin");
+            assert_eq!(mesh.half_edges[twin].face, Some(reversed));
+            assert_eq!(mesh.half_edges[twin].twin, Some(e));
+        }
+
+        // Deleting a face leaves the remaining face open
+        mesh.delete_face(reversed);
+        for e in mesh.face_edge_iter(face) {
+            assert_eq!(mesh.half_edges[e].twin, None);
+        }
+    }
+
+    #[test]
+    fn inset_square() {
+        let mut mesh = Mesh::new();
+        let face = make_square(&mut mesh);
+        let inner = mesh.inset(face, 0.5).unwrap();
+
+        // the original face is replaced by a ring of 4 quads + the inner face
+        assert!(mesh.faces[face].deleted);
+        assert_eq!(live_faces(&mesh).len(), 5);
+        assert_eq!(mesh.get_face_positions(inner), vec![
+            (0.25, 0.25, 0.0),
+            (0.75, 0.25, 0.0),
+            (0.75, 0.75, 0.0),
+            (0.25, 0.75, 0.0),
+        ]);
+
+        // The ring quads are stitched to the inner face
+        for e in mesh.face_edge_iter(inner) {
+            assert!(mesh.half_edges[e].twin.is_some());
+        }
+    }
+
+    #[test]
+    fn bevel_extruded_square() {
+        let mut mesh = Mesh::new();
+        let face = make_square(&mut mesh);
+        mesh.add_face(&[3, 2, 1, 0]);
+        mesh.compute_face_normals().unwrap();
+        let top = mesh.extrude(face, 1.0);
+        mesh.compute_face_normals().unwrap();
+
+        let inner = mesh.bevel(top, 0.5, 0.25).unwrap();
+        mesh.compute_face_normals().unwrap();
+
+        // The inner face stays at the original height
+        for (_, _, z) in mesh.get_face_positions(inner) {
+            assert_eq!(z, 1.0);
+        }
+
+        // while the old rim is pushed down
+        let rim: Vec<Vec3> = mesh.get_face_positions(top);
+        for (_, _, z) in rim {
+            assert_eq!(z, 0.75);
+        }
+
+        // base + 4 sides + 4 chamfer quads + top
+        assert_eq!(live_faces(&mesh).len(), 10);
+        assert_eq!(mesh.faces[inner].normal, Some((0.0, 0.0, 1.0)));
+    }
+
+    #[test]
+    fn inset_amount_must_be_a_fraction() {
+        for amount in [0.0, -0.5, 1.0, 1.5, f64::NAN] {
+            let mut mesh = Mesh::new();
+            let face = make_square(&mut mesh);
+            assert!(matches!(mesh.inset(face, amount), Err(MeshError::InsetAmount(_))));
+            // The face is left alone
+            assert!(!mesh.faces[face].deleted);
+        }
+    }
+
+    #[test]
+    fn rotated_polygons_are_congruent() {
+        let triangle = vec![(1.0, 0.0, 0.0), (-0.5, 1.0, 0.0), (-0.5, -1.0, 0.0)];
+        let (sin, cos) = 2.0f64.sin_cos();
+        let mut rotated: Vec<Vec3> = triangle.iter()
+            .map(|&(x, y, z)| (cos * x - sin * y, sin * x + cos * y, z))
+            .collect();
+        rotated.rotate_left(1);
+
+        let rotation = Mesh::find_rotation(&triangle, &rotated).unwrap();
+        assert!((rotation.rem_euclid(2.0 * PI) - 2.0).abs() < 1e-12);
+
+        // Mirror images don't count
+        let mirrored: Vec<Vec3> = triangle.iter().map(|&(x, y, z)| (x, -y, z)).collect();
+        assert!(Mesh::find_rotation(&triangle, &mirrored).is_none());
+    }
+
+    #[test]
+    fn subdivide_quad() {
+        let mut mesh = Mesh::new();
+        make_square(&mut mesh);
+        let subdivided = mesh.subdivide();
+
+        // 1 face point, 4 edge points, 4 vertex points
+        assert_eq!(subdivided.vertices.len(), 9);
+        assert_eq!(subdivided.faces.len(), 4);
+        assert_eq!(subdivided.vertices[0].position, (0.5, 0.5, 0.0));
+
+        // Boundary corners only have two boundary neighbors, so
+        // they're pulled inwards along the boundary
+        let corners: Vec<Vec3> = subdivided.vertices[5..].iter()
+            .map(|v| v.position)
+            .collect();
+        assert!(corners.contains(&(0.125, 0.125, 0.0)));
+        assert!(corners.contains(&(0.875, 0.875, 0.0)));
+    }
+
+    #[test]
+    fn subdivide_cube() {
+        let mut mesh = Mesh::new();
+        let face = make_square(&mut mesh);
+        mesh.add_face(&[3, 2, 1, 0]);
+        mesh.compute_face_normals().unwrap();
+        mesh.extrude(face, 1.0);
+
+        let subdivided = mesh.subdivide();
+
+        // 6 face points + 12 edge points + 8 vertex points
+        assert_eq!(subdivided.vertices.len(), 26);
+        assert_eq!(subdivided.faces.len(), 24);
+
+        // The result is closed, so every half edge has a twin
+        for edge in subdivided.half_edges.iter() {
+            assert!(edge.twin.is_some());
+        }
+
+        // The corner at the origin has F = (1/3, 1/3, 1/3),
+        // R = (1/6, 1/6, 1/6) and n = 3 so it moves to (F + 2R) / 3
+        let expected = 2.0 / 9.0;
+        let corner = subdivided.vertices.iter()
+            .map(|v| v.position)
+            .find(|(x, y, z)| {
+                (x - expected).abs() < 1e-12 &&
+                (y - expected).abs() < 1e-12 &&
+                (z - expected).abs() < 1e-12
+            });
+        assert!(corner.is_some());
+    }
+
+    #[test]
+    fn vertices_point_to_their_own_half_edges() {
+        let mut mesh = Mesh::new();
+        for x in 0..4 {
+            mesh.add_vertex((x as f64, 0.0, 0.0));
+        }
+        mesh.add_face(&[3, 1, 2, 0]);
+
+        for (i, vertex) in mesh.vertices.iter().enumerate() {
+            let edge = vertex.half_edge.expect("vertex should have a half edge");
+            assert_eq!(mesh.half_edges[edge].from_vertex, i);
+        }
+    }
+
+    fn triangle_area(points: &[(f64, f64)], triangle: &[usize]) -> f64 {
+        let (ax, ay) = points[triangle[0]];
+        let (bx, by) = points[triangle[1]];
+        let (cx, cy) = points[triangle[2]];
+        0.5 * ((bx - ax) * (cy - ay) - (by - ay) * (cx - ax))
+    }
+
+    #[test]
+    fn triangulate_convex_polygon() {
+        let square = [
+            (0.0, 0.0, 0.0),
+            (1.0, 0.0, 0.0),
+            (1.0, 1.0, 0.0),
+            (0.0, 1.0, 0.0),
+        ];
+        let triangles = Mesh::triangulate_polygon(&square, (0.0, 0.0, 1.0));
+        assert_eq!(triangles.len(), 6);
+    }
+
+    #[test]
+    fn triangulate_concave_polygon() {
+        // An arrow pointing in the +x direction. The fan from vertex 0
+        // would cover the notch at vertex 5.
+        let arrow = [
+            (0.0, 0.0, 0.0),
+            (2.0, 0.0, 0.0),
+            (3.0, 1.0, 0.0),
+            (2.0, 2.0, 0.0),
+            (0.0, 2.0, 0.0),
+            (1.0, 1.0, 0.0),
+        ];
+        let triangles = Mesh::triangulate_polygon(&arrow, (0.0, 0.0, 1.0));
+        assert_eq!(triangles.len(), 12);
+
+        // Every triangle is counterclockwise like the input, and together
+        // they cover exactly the area of the polygon
+        let points: Vec<(f64, f64)> = arrow.iter().map(|&(x, y, _)| (x, y)).collect();
+        let mut total_area = 0.0;
+        for triangle in triangles.chunks(3) {
+            let area = triangle_area(&points, triangle);
+            assert!(area > 0.0);
+            total_area += area;
+        }
+        assert!((total_area - 4.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn triangulate_clockwise_vertical_polygon() {
+        // A concave polygon in the xz-plane seen from -y
+        let polygon = [
+            (0.0, 0.0, 0.0),
+            (0.0, 0.0, 2.0),
+            (1.0, 0.0, 1.0),
+            (2.0, 0.0, 2.0),
+            (2.0, 0.0, 0.0),
+        ];
+        let triangles = Mesh::triangulate_polygon(&polygon, (0.0, -1.0, 0.0));
+        assert_eq!(triangles.len(), 9);
+
+        let points = Mesh::project_to_plane(&polygon, (0.0, -1.0, 0.0));
+        let total_area: f64 = triangles.chunks(3)
+            .map(|t| triangle_area(&points, t))
+            .sum();
+        // The notch at (1, 1) removes a quarter of the 2x2 square
+        assert!((total_area.abs() - 3.0).abs() < 1e-12);
+        for triangle in triangles.chunks(3) {
+            assert!(triangle_area(&points, triangle) * total_area > 0.0);
+        }
+    }
+
+    #[test]
+    fn triangulate_collinear_vertices() {
+        // Square with extra vertices in the middle of two sides
+        let polygon = [
+            (0.0, 0.0, 0.0),
+            (0.5, 0.0, 0.0),
+            (1.0, 0.0, 0.0),
+            (1.0, 1.0, 0.0),
+            (0.5, 1.0, 0.0),
+            (0.0, 1.0, 0.0),
+        ];
+        let triangles = Mesh::triangulate_polygon(&polygon, (0.0, 0.0, 1.0));
+        assert_eq!(triangles.len(), 12);
+
+        let points = Mesh::project_to_plane(&polygon, (0.0, 0.0, 1.0));
+        let mut total_area = 0.0;
+        for triangle in triangles.chunks(3) {
+            let area = triangle_area(&points, triangle);
+            assert!(area > 0.0);
+            total_area += area;
+        }
+        assert!((total_area - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn polygon_without_ears_has_no_flipped_triangles() {
+        // This polygon crosses itself, and every convex corner has another
+        // vertex inside its triangle
+        let polygon = vec![
+            (0.0, 3.0, 0.0),
+            (1.0, 3.0, 0.0),
+            (2.0, 1.0, 0.0),
+            (0.0, 4.0, 0.0),
+            (0.0, 2.0, 0.0),
+            (2.0, 3.0, 0.0),
+        ];
+        let triangles = Mesh::triangulate_polygon(&polygon, (0.0, 0.0, 1.0));
+        assert!(!triangles.is_empty());
+
+        let points = Mesh::project_to_plane(&polygon, (0.0, 0.0, 1.0));
+        for triangle in triangles.chunks(3) {
+            assert!(triangle_area(&points, triangle) >= 0.0, "{:?} is flipped", triangle);
+        }
+    }
+
+    fn make_cube() -> Mesh {
+        let mut mesh = Mesh::new();
+        let face = make_square(&mut mesh);
+        mesh.add_face(&[3, 2, 1, 0]);
+        mesh.compute_face_normals().unwrap();
+        mesh.extrude(face, 1.0);
+        mesh.compute_face_normals().unwrap();
+        mesh
+    }
+
+    #[test]
+    fn flat_shading_duplicates_corners() {
+        let (_, triangles) = make_cube().triangulate_by_material(Shading::Flat, 0, None).remove(0);
+
+        // 6 faces * 4 corners, each with the face normal
+        assert_eq!(triangles.positions.len(), 24);
+        assert_eq!(triangles.indices.len(), 36);
+        for (i, normal) in triangles.normals.iter().enumerate() {
+            let count = triangles.normals.iter()
+                .filter(|n| *n == normal)
+                .count();
+            assert_eq!(count, 4, "normal {} is shared by one face", i);
+        }
+    }
+
+    #[test]
+    fn smooth_shading_respects_crease_angle() {
+        let cube = make_cube();
+
+        // The cube's edges are 90 degrees, so a 45 degree crease keeps
+        // every edge sharp
+        let (_, sharp) = cube.triangulate_by_material(
+            Shading::Smooth { crease_angle: 45.0 }, 0, None).remove(0);
+        assert_eq!(sharp.positions.len(), 24);
+
+        // With a wider crease angle, the corners are shared and point
+        // diagonally outwards
+        let (_, smooth) = cube.triangulate_by_material(
+            Shading::Smooth { crease_angle: 100.0 }, 0, None).remove(0);
+        assert_eq!(smooth.positions.len(), 8);
+        assert_eq!(smooth.indices.len(), 36);
+        let diagonal = 1.0 / 3.0f64.sqrt();
+        for (position, normal) in smooth.positions.iter().zip(smooth.normals.iter()) {
+            let outwards = vec3::sub(*position, (0.5, 0.5, 0.5));
+            let expected = vec3::normalize(outwards);
+            assert!(vec3::length(vec3::sub(*normal, expected)) < 1e-12);
+            assert!((normal.0.abs() - diagonal).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn normal_with_collinear_first_vertices() {
+        let mut mesh = Mesh::new();
+        let a = mesh.add_vertex((0.0, 0.0, 0.0));
+        let b = mesh.add_vertex((1.0, 0.0, 0.0));
+        let c = mesh.add_vertex((2.0, 0.0, 0.0));
+        let d = mesh.add_vertex((2.0, 1.0, 0.0));
+        let e = mesh.add_vertex((0.0, 1.0, 0.0));
+        let face = mesh.add_face(&[a, b, c, d, e]);
+        mesh.compute_face_normals().unwrap();
+
+        assert_eq!(mesh.faces[face].normal, Some((0.0, 0.0, 1.0)));
+    }
+
+    #[test]
+    fn degenerate_face_is_an_error() {
+        let mut mesh = Mesh::new();
+        make_square(&mut mesh);
+        let a = mesh.add_vertex((0.0, 0.0, 1.0));
+        let b = mesh.add_vertex((1.0, 0.0, 1.0));
+        let c = mesh.add_vertex((2.0, 0.0, 1.0));
+        let degenerate = mesh.add_face(&[a, b, c]);
+
+        match mesh.compute_face_normals() {
+            Err(MeshError::DegenerateFace(face)) => assert_eq!(face, degenerate),
+            _ => panic!("collinear face should not have a normal")
+        }
+        assert!(mesh.faces[degenerate].normal.is_none());
+    }
+
+    fn make_box_top() -> (Mesh, usize) {
+        let mut mesh = Mesh::new();
+        let face = make_square(&mut mesh);
+        mesh.add_face(&[3, 2, 1, 0]);
+        mesh.compute_face_normals().unwrap();
+        let top = mesh.extrude(face, 1.0);
+        mesh.compute_face_normals().unwrap();
+        (mesh, top)
+    }
+
+    /// Extrude a polygon in the xy-plane by 1 and get the top face
+    fn make_prism(points: &[(f64, f64)]) -> (Mesh, usize) {
+        let mut mesh = Mesh::new();
+        let base: Vec<usize> = points.iter()
+            .map(|&(x, y)| mesh.add_vertex((x, y, 0.0)))
+            .collect();
+        let reversed: Vec<usize> = base.iter().rev().copied().collect();
+        mesh.add_face(&reversed);
+        let top = mesh.add_face(&base);
+        mesh.compute_face_normals().unwrap();
+        let top = mesh.extrude(top, 1.0);
+        mesh.compute_face_normals().unwrap();
+        (mesh, top)
+    }
+
+    #[test]
+    fn caps() {
+        let (mut flat, top) = make_box_top();
+        flat.add_cap(top, CapStyle::Flat).unwrap();
+        assert_eq!(live_faces(&flat).len(), 6);
+
+        let (mut open, top) = make_box_top();
+        open.add_cap(top, CapStyle::Open).unwrap();
+        assert_eq!(live_faces(&open).len(), 5);
+
+        let (mut pyramid, top) = make_box_top();
+        pyramid.add_cap(top, CapStyle::Pyramid { height: 0.5 }).unwrap();
+        pyramid.compute_face_normals().unwrap();
+        assert_eq!(live_faces(&pyramid).len(), 9);
+        assert_eq!(pyramid.vertices.last().unwrap().position, (0.5, 0.5, 1.5));
+
+        // A 45 degree hip roof over a square is a pyramid with height equal
+        // to the inradius
+        let (mut hip, top) = make_box_top();
+        hip.add_cap(top, CapStyle::Hip { pitch: 45.0 }).unwrap();
+        hip.compute_face_normals().unwrap();
+        assert_eq!(live_faces(&hip).len(), 9);
+        let (x, y, z) = hip.vertices.last().unwrap().position;
+        assert!((x - 0.5).abs() < 1e-9 && (y - 0.5).abs() < 1e-9 && (z - 1.5).abs() < 1e-9);
+
+        let (mut dome, top) = make_box_top();
+        dome.add_cap(top, CapStyle::Dome { height: 1.0, rings: 3 }).unwrap();
+        dome.compute_face_normals().unwrap();
+        // 2 rings of quads + 1 ring of triangles
+        assert_eq!(live_faces(&dome).len(), 5 + 3 * 4);
+
+        // Hip roofs over non-convex faces fall back to a pyramid with its
+        // apex over the kernel of the face. For this L shape, that's the
+        // unit square at the corner.
+        let (mut concave, top) = make_prism(&[
+            (0.0, 0.0), (2.0, 0.0), (2.0, 1.0), (1.0, 1.0), (1.0, 2.0), (0.0, 2.0)
+        ]);
+        concave.add_cap(top, CapStyle::Hip { pitch: 45.0 }).unwrap();
+        assert_eq!(live_faces(&concave).len(), 1 + 6 + 6);
+        let apex = concave.vertices.len() - 1;
+        let (x, y, z) = concave.vertices[apex].position;
+        assert!((x - 0.5).abs() < 1e-9 && (y - 0.5).abs() < 1e-9 && (z - 1.5).abs() < 1e-9);
+
+        // The roof doesn't intersect itself: seen from above, its
+        // triangles don't overlap and cover the L exactly
+        let mut roof_area = 0.0;
+        for face in live_faces(&concave) {
+            let vertices: Vec<usize> = concave.face_edge_iter(face)
+                .map(|e| concave.half_edges[e].from_vertex)
+                .collect();
+            if !vertices.contains(&apex) {
+                continue;
+            }
+            let points = Mesh::project_to_plane(&concave.get_face_positions(face), (0.0, 0.0, 1.0));
+            let area = triangle_area(&points, &[0, 1, 2]);
+            assert!(area > 0.0);
+            roof_area += area;
+        }
+        assert!((roof_area - 3.0).abs() < 1e-9);
+
+        // A U shape has no point that sees both arms
+        let (mut u_shape, top) = make_prism(&[
+            (0.0, 0.0), (3.0, 0.0), (3.0, 3.0), (2.0, 3.0), (2.0, 1.0), (1.0, 1.0), (1.0, 3.0), (0.0, 3.0)
+        ]);
+        match u_shape.add_cap(top, CapStyle::Hip { pitch: 45.0 }) {
+            Err(MeshError::NotStarShaped(face)) => assert_eq!(face, top),
+            _ => panic!("a U shape has no kernel")
+        }
+
+        // The caps are all closed
+        for mesh in [&pyramid, &hip, &dome, &concave] {
+            for edge in mesh.half_edges.iter().filter(|e| e.face.is_some()) {
+                assert!(edge.twin.is_some());
+            }
+        }
+    }
+
+    #[test]
+    fn triangulate_by_material_groups_faces() {
+        let mut mesh = Mesh::new();
+        let face = make_square(&mut mesh);
+        mesh.add_face(&[3, 2, 1, 0]);
+        mesh.compute_face_normals().unwrap();
+        mesh.material = Some(2);
+        mesh.extrude(face, 1.0);
+        mesh.compute_face_normals().unwrap();
+
+        // The bottom face uses the default material
+        let groups = mesh.triangulate_by_material(Shading::Flat, 5, None);
+        let materials: Vec<usize> = groups.iter().map(|(m, _)| *m).collect();
+        assert_eq!(materials, vec![2, 5]);
+        assert_eq!(groups[0].1.indices.len(), 5 * 6);
+        assert_eq!(groups[1].1.indices.len(), 6);
+
+        // Subdivision keeps the materials
+        let mut subdivided = mesh.subdivide();
+        subdivided.compute_face_normals().unwrap();
+        let groups = subdivided.triangulate_by_material(Shading::Flat, 5, None);
+        assert_eq!(groups[0].1.indices.len(), 4 * 5 * 6);
+        assert_eq!(groups[1].1.indices.len(), 4 * 6);
+    }
+
+    #[test]
+    fn append_offsets_indices() {
+        let mut mesh = Mesh::new();
+        make_square(&mut mesh);
+        mesh.compute_face_normals().unwrap();
+        let (_, square) = mesh.triangulate_by_material(Shading::Flat, 0, None).remove(0);
+
+        let mut merged = Triangles::new();
+        merged.append(square);
+        let (_, square) = mesh.triangulate_by_material(Shading::Flat, 0, None).remove(0);
+        let first_indices = square.indices.clone();
+        merged.append(square);
+
+        assert_eq!(merged.positions.len(), 8);
+        assert_eq!(merged.normals.len(), 8);
+        assert_eq!(&merged.indices[..6], &first_indices[..]);
+        let shifted: Vec<u32> = first_indices.iter().map(|i| i + 4).collect();
+        assert_eq!(&merged.indices[6..], &shifted[..]);
+    }
+
+    #[test]
+    fn generated_uvs() {
+        // A box centered on the z-axis
+        let mut mesh = Mesh::new();
+        let a = mesh.add_vertex((-1.0, -1.0, 0.0));
+        let b = mesh.add_vertex((1.0, -1.0, 0.0));
+        let c = mesh.add_vertex((1.0, 1.0, 0.0));
+        let d = mesh.add_vertex((-1.0, 1.0, 0.0));
+        let face = mesh.add_face(&[a, b, c, d]);
+        mesh.add_face(&[d, c, b, a]);
+        mesh.compute_face_normals().unwrap();
+        let top = mesh.extrude(face, 2.0);
+        mesh.compute_face_normals().unwrap();
+
+        let mapping = UvMapping {
+            scale: 0.5,
+            center: (0.0, 0.0, 0.0),
+            axis: (0.0, 0.0, 1.0)
+        };
+        let uvs = mesh.corner_uvs(mapping);
+        // The top is projected straight down
+        let top_positions = mesh.get_face_positions(top);
+        for ((x, y, _), (u, v)) in top_positions.iter().zip(uvs[&top].iter()) {
+            assert_eq!((*u, *v), (x / 0.5, -y / 0.5));
+        }
+
+        // Each wall spans a quarter turn, even the one facing -x that
+        // crosses the seam
+        let quarter_turn = 0.5 * PI * 2.0f64.sqrt() / 0.5;
+        let walls: Vec<usize> = live_faces(&mesh).into_iter()
+            .filter(|f| mesh.faces[*f].normal.unwrap().2.abs() < 0.5)
+            .collect();
+        assert_eq!(walls.len(), 4);
+        for wall in walls {
+            let us: Vec<f64> = uvs[&wall].iter().map(|(u, _)| *u).collect();
+            let span = us.iter().cloned().fold(-f64::INFINITY, f64::max)
+                - us.iter().cloned().fold(f64::INFINITY, f64::min);
+            assert!((span - quarter_turn).abs() < 1e-12);
+            for (v, (_, _, z)) in uvs[&wall].iter().map(|(_, v)| v).zip(mesh.get_face_positions(wall)) {
+                assert_eq!(*v, -z / 0.5);
+            }
+        }
+
+        // Every output vertex gets texture coordinates
+        let (_, triangles) = mesh.triangulate_by_material(Shading::Flat, 0, Some(mapping)).remove(0);
+        assert_eq!(triangles.uvs.len(), triangles.positions.len());
+
+        // The apex of a cap doesn't change the radius used for the walls
+        mesh.add_cap(top, CapStyle::Pyramid { height: 1.0 }).unwrap();
+        mesh.compute_face_normals().unwrap();
+        let capped = mesh.corner_uvs(mapping);
+        for wall in live_faces(&mesh).into_iter().filter(|f| uvs.contains_key(f)) {
+            if mesh.faces[wall].normal.unwrap().2.abs() < 0.5 {
+                for ((u1, v1), (u2, v2)) in capped[&wall].iter().zip(uvs[&wall].iter()) {
+                    assert!((u1 - u2).abs() < 1e-12 && (v1 - v2).abs() < 1e-12);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn uvs_follow_the_axis() {
+        // The same box lying on its side along x, away from the origin
+        let mut mesh = Mesh::new();
+        let a = mesh.add_vertex((5.0, -1.0, -1.0));
+        let b = mesh.add_vertex((5.0, 1.0, -1.0));
+        let c = mesh.add_vertex((5.0, 1.0, 1.0));
+        let d = mesh.add_vertex((5.0, -1.0, 1.0));
+        let face = mesh.add_face(&[a, b, c, d]);
+        mesh.add_face(&[d, c, b, a]);
+        mesh.compute_face_normals().unwrap();
+        mesh.extrude(face, 2.0);
+        mesh.compute_face_normals().unwrap();
+
+        let uvs = mesh.corner_uvs(UvMapping {
+            scale: 1.0,
+            center: (5.0, 0.0, 0.0),
+            axis: (1.0, 0.0, 0.0)
+        });
+        let quarter_turn = 0.5 * PI * 2.0f64.sqrt();
+        for wall in live_faces(&mesh) {
+            if mesh.faces[wall].normal.unwrap().0.abs() >= 0.5 {
+                continue;
+            }
+            let us: Vec<f64> = uvs[&wall].iter().map(|(u, _)| *u).collect();
+            let span = us.iter().cloned().fold(-f64::INFINITY, f64::max)
+                - us.iter().cloned().fold(f64::INFINITY, f64::min);
+            assert!((span - quarter_turn).abs() < 1e-12);
+            for (v, (x, _, _)) in uvs[&wall].iter().map(|(_, v)| v).zip(mesh.get_face_positions(wall)) {
+                assert!((v + (x - 5.0)).abs() < 1e-12);
+            }
+        }
+    }
+}
@@ -0,0 +1,316 @@
+use std::f64::consts::PI;
+
+use serde::Deserialize;
+
+/// A point in profile coordinates (center, normal). center is measured
+/// inwards towards the centroid of the face, normal is the height above
+/// the face. Both are in units of profile steps.
+pub type ProfilePoint = (f64, f64);
+
+const DEFAULT_RESOLUTION: usize = 8;
+
+pub const DEFAULT_CENTER_STEP: f64 = 1.0 / 8.0;
+pub const DEFAULT_NORMAL_STEP: f64 = 0.1;
+pub const DEFAULT_BASE_HEIGHT: f64 = 0.2;
+
+/// How the center coordinate of a profile moves the walls inwards
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+pub enum InsetMode {
+    /// Each vertex moves towards the centroid, center * center_step is
+    /// the fraction of the way there. Walls only stay parallel for
+    /// regular polygons.
+    #[default]
+    Proportional,
+    /// Each wall moves inwards parallel to itself like the straight
+    /// skeleton, center * center_step is the distance it moves. This
+    /// keeps a constant wall thickness on any polygon.
+    Skeleton
+}
+
+/// Sizes used to convert a profile to world units
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ProfileScale {
+    /// Size of a center step, its meaning depends on inset_mode
+    pub center_step: f64,
+    /// Height per normal step
+    pub normal_step: f64,
+    /// Height of the plain extrusion below the profile
+    pub base_height: f64,
+    pub inset_mode: InsetMode
+}
+
+/// Materials for different parts of a tower. Anything not listed uses
+/// the material of the tiling face.
+#[derive(Deserialize, Default)]
+pub struct LayerMaterials {
+    /// The bottom face and the plain extrusion below the profile
+    pub base: Option<usize>,
+    /// Materials for ranges of profile steps
+    #[serde(default)]
+    pub steps: Vec<StepMaterial>,
+    /// The cap at the top of the tower
+    pub cap: Option<usize>
+}
+
+/// A material for the walls of profile steps from..to (not including to).
+/// A step is one offset of an offset profile, or one segment of a curve
+/// profile however finely it is sampled.
+#[derive(Deserialize)]
+pub struct StepMaterial {
+    pub from: usize,
+    pub to: usize,
+    pub material: usize
+}
+
+impl LayerMaterials {
+    /// Get the material for the wall leading up to each sampled point,
+    /// given the step each point belongs to. See ProfileShape::steps()
+    pub fn step_materials(&self, steps: &[usize]) -> Vec<Option<usize>> {
+        steps.iter()
+            .map(|step| {
+                // Later ranges take priority
+                self.steps.iter()
+                    .rev()
+                    .find(|range| (range.from..range.to).contains(step))
+                    .map(|range| range.material)
+            })
+            .collect()
+    }
+}
+
+/// The shape of a tower profile. Profiles start at (0, 0), the vertices
+/// of the tower base.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum ProfileShape {
+    /// Integer (center, normal) steps, each relative to the previous one
+    Offsets {
+        offsets: Vec<(i32, i32)>
+    },
+    /// A sequence of curve segments, each starting where the previous
+    /// one ended. Curved segments are sampled at resolution points.
+    Curve {
+        curve: Vec<CurveSegment>,
+        resolution: Option<usize>
+    }
+}
+
+#[derive(Deserialize)]
+pub enum CurveSegment {
+    /// Straight lines through each point
+    Polyline(Vec<ProfilePoint>),
+    /// Bézier curve from the current point using the given control
+    /// points. The last control point is the end of the segment. Two
+    /// control points make a quadratic curve, three make a cubic curve.
+    Bezier(Vec<ProfilePoint>),
+    /// Circular arc around center, sweeping the given angle in degrees.
+    /// Positive angles are counterclockwise in the (center, normal) plane.
+    Arc {
+        center: ProfilePoint,
+        angle: f64
+    },
+    /// A straight line to the given point, with a sine wave added to
+    /// the center coordinate. This is useful for ribbed walls.
+    Sine {
+        to: ProfilePoint,
+        amplitude: f64,
+        periods: f64
+    }
+}
+
+impl CurveSegment {
+    /// Sample the segment starting at start. The start point itself is
+    /// not included in the result.
+    fn sample(&self, start: ProfilePoint, resolution: usize) -> Vec<ProfilePoint> {
+        let parameters = (1..=resolution).map(|i| i as f64 / resolution as f64);
+        match self {
+            CurveSegment::Polyline(points) => points.clone(),
+            CurveSegment::Bezier(controls) => {
+                let mut points = vec![start];
+                points.extend_from_slice(controls);
+                parameters
+                    .map(|t| de_casteljau(&points, t))
+                    .collect()
+            },
+            CurveSegment::Arc { center, angle } => {
+                let (cx, cy) = center;
+                let (sx, sy) = start;
+                let radius = (sx - cx).hypot(sy - cy);
+                let start_angle = (sy - cy).atan2(sx - cx);
+                let sweep = angle.to_radians();
+                parameters
+                    .map(|t| {
+                        let theta = start_angle + t * sweep;
+                        (cx + radius * theta.cos(), cy + radius * theta.sin())
+                    })
+                    .collect()
+            },
+            CurveSegment::Sine { to, amplitude, periods } => {
+                let (sx, sy) = start;
+                let (ex, ey) = to;
+                parameters
+                    .map(|t| {
+                        let wave = amplitude * (2.0 * PI * periods * t).sin();
+                        (sx + t * (ex - sx) + wave, sy + t * (ey - sy))
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+/// Evaluate a Bézier curve with the given control points at t
+fn de_casteljau(points: &[ProfilePoint], t: f64) -> ProfilePoint {
+    let mut points = points.to_vec();
+    while points.len() > 1 {
+        points = points.windows(2)
+            .map(|pair| {
+                let (ax, ay) = pair[0];
+                let (bx, by) = pair[1];
+                (ax + t * (bx - ax), ay + t * (by - ay))
+            })
+            .collect();
+    }
+    points[0]
+}
+
+impl ProfileShape {
+    /// Get the points of the profile in absolute (center, normal)
+    /// coordinates, not including the starting point (0, 0).
+    pub fn sample(&self) -> Vec<ProfilePoint> {
+        self.sample_steps().into_iter()
+            .map(|(_, point)| point)
+            .collect()
+    }
+
+    /// Get the index of the offset or curve segment that each point from
+    /// sample() belongs to.
+    pub fn steps(&self) -> Vec<usize> {
+        self.sample_steps().into_iter()
+            .map(|(step, _)| step)
+            .collect()
+    }
+
+    fn sample_steps(&self) -> Vec<(usize, ProfilePoint)> {
+        let mut result = Vec::new();
+        match self {
+            ProfileShape::Offsets { offsets } => {
+                let mut center = 0;
+                let mut normal = 0;
+                for (step, (center_offset, normal_offset)) in offsets.iter().enumerate() {
+                    // A zero offset would make zero-area faces
+                    if (*center_offset, *normal_offset) == (0, 0) {
+                        continue;
+                    }
+                    center += center_offset;
+                    normal += normal_offset;
+                    result.push((step, (center as f64, normal as f64)));
+                }
+            },
+            ProfileShape::Curve { curve, resolution } => {
+                let resolution = resolution.unwrap_or(DEFAULT_RESOLUTION).max(1);
+                let mut current = (0.0, 0.0);
+                for (step, segment) in curve.iter().enumerate() {
+                    for point in segment.sample(current, resolution) {
+                        // Repeated points would make zero-area faces
+                        if point != current {
+                            result.push((step, point));
+                            current = point;
+                        }
+                    }
+                }
+            }
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: ProfilePoint, expected: ProfilePoint) {
+        let (ax, ay) = actual;
+        let (ex, ey) = expected;
+        assert!(
+            (ax - ex).abs() < 1e-12 && (ay - ey).abs() < 1e-12,
+            "{:?} != {:?}", actual, expected
+        );
+    }
+
+    #[test]
+    fn integer_offsets_are_cumulative() {
+        let shape: ProfileShape = serde_json::from_str(
+            r#"{"offsets": [[2, 0], [0, 3], [-1, 1]]}"#
+        ).unwrap();
+        assert_eq!(shape.sample(), vec![(2.0, 0.0), (2.0, 3.0), (1.0, 4.0)]);
+    }
+
+    #[test]
+    fn zero_offsets_are_skipped() {
+        let shape: ProfileShape = serde_json::from_str(
+            r#"{"offsets": [[2, 0], [0, 0], [0, 3]]}"#
+        ).unwrap();
+        assert_eq!(shape.sample(), vec![(2.0, 0.0), (2.0, 3.0)]);
+        assert_eq!(shape.steps(), vec![0, 2]);
+    }
+
+    #[test]
+    fn curve_segments_are_chained() {
+        let shape: ProfileShape = serde_json::from_str(r#"{
+            "curve": [
+                {"Polyline": [[1, 0], [1, 2]]},
+                {"Arc": {"center": [1, 3], "angle": 180}},
+                {"Bezier": [[3, 4], [3, 6]]}
+            ],
+            "resolution": 2
+        }"#).unwrap();
+        let points = shape.sample();
+        assert_eq!(points.len(), 6);
+        assert_close(points[1], (1.0, 2.0));
+        // half way around the arc, from below the center to the right
+        assert_close(points[2], (2.0, 3.0));
+        assert_close(points[3], (1.0, 4.0));
+        // Quadratic Bézier from (1, 4) through (3, 4) to (3, 6)
+        assert_close(points[4], (2.5, 4.5));
+        assert_close(points[5], (3.0, 6.0));
+    }
+
+    #[test]
+    fn sine_returns_to_line() {
+        let shape: ProfileShape = serde_json::from_str(r#"{
+            "curve": [
+                {"Sine": {"to": [0, 4], "amplitude": 0.5, "periods": 1}}
+            ],
+            "resolution": 4
+        }"#).unwrap();
+        let points = shape.sample();
+        assert_eq!(points.len(), 4);
+        assert_close(points[0], (0.5, 1.0));
+        assert_close(points[1], (0.0, 2.0));
+        assert_close(points[2], (-0.5, 3.0));
+        assert_close(points[3], (0.0, 4.0));
+    }
+
+    #[test]
+    fn step_materials_follow_curve_segments() {
+        let materials: LayerMaterials = serde_json::from_str(
+            r#"{"steps": [{"from": 1, "to": 2, "material": 3}]}"#
+        ).unwrap();
+        for resolution in [2, 5] {
+            let shape: ProfileShape = serde_json::from_value(serde_json::json!({
+                "curve": [
+                    {"Polyline": [[1, 0]]},
+                    {"Arc": {"center": [1, 1], "angle": 180}},
+                    {"Polyline": [[1, 3]]}
+                ],
+                "resolution": resolution
+            })).unwrap();
+            let mut expected = vec![None];
+            expected.extend(vec![Some(3); resolution]);
+            expected.push(None);
+            assert_eq!(materials.step_materials(&shape.steps()), expected);
+        }
+    }
+}
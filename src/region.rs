@@ -0,0 +1,437 @@
+use std::f64::consts::PI;
+
+use serde::Deserialize;
+
+use crate::mesh::{Mesh, Triangles};
+use crate::vec3::{self, Vec3};
+
+/// Number of sides used to approximate a circle when cutting
+const CIRCLE_SEGMENTS: usize = 64;
+
+/// A footprint in the xy-plane
+#[derive(Deserialize)]
+pub enum RegionShape {
+    Circle {
+        center: (f64, f64),
+        radius: f64
+    },
+    Rectangle {
+        min: (f64, f64),
+        max: (f64, f64)
+    },
+    /// A simple polygon, either winding order
+    Polygon(Vec<(f64, f64)>)
+}
+
+/// What to do with towers that cross the boundary of the region
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+pub enum ClipMode {
+    /// Only keep towers that are completely inside
+    #[default]
+    Drop,
+    /// Cut towers along the boundary. The cut sides are left open.
+    Cut
+}
+
+/// Only keep the copies of the towers inside a region instead of a
+/// parallelogram of copies
+#[derive(Deserialize)]
+pub struct Region {
+    pub shape: RegionShape,
+    #[serde(default)]
+    pub mode: ClipMode
+}
+
+/// How much of a tower is inside the region
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Coverage {
+    Inside,
+    Outside,
+    Partial
+}
+
+impl Region {
+    pub fn contains(&self, x: f64, y: f64) -> bool {
+        match &self.shape {
+            RegionShape::Circle { center, radius } => {
+                let (cx, cy) = center;
+                (x - cx).hypot(y - cy) <= *radius
+            },
+            RegionShape::Rectangle { min, max } => {
+                let (min_x, min_y) = min;
+                let (max_x, max_y) = max;
+                (*min_x..=*max_x).contains(&x) && (*min_y..=*max_y).contains(&y)
+            },
+            RegionShape::Polygon(points) => {
+                // Even-odd rule
+                let mut inside = false;
+                let n = points.len();
+                for i in 0..n {
+                    let (ax, ay) = points[i];
+                    let (bx, by) = points[(i + 1) % n];
+                    if (ay > y) != (by > y) && x < ax + (y - ay) / (by - ay) * (bx - ax) {
+                        inside = !inside;
+                    }
+                }
+                inside
+            }
+        }
+    }
+
+    /// Get the (min, max) corners of the bounding box of the region
+    pub fn bounds(&self) -> ((f64, f64), (f64, f64)) {
+        match &self.shape {
+            RegionShape::Circle { center, radius } => {
+                let (cx, cy) = center;
+                ((cx - radius, cy - radius), (cx + radius, cy + radius))
+            },
+            RegionShape::Rectangle { min, max } => (*min, *max),
+            RegionShape::Polygon(points) => points.iter().fold(
+                ((f64::INFINITY, f64::INFINITY), (-f64::INFINITY, -f64::INFINITY)),
+                |((min_x, min_y), (max_x, max_y)), &(x, y)| {
+                    ((min_x.min(x), min_y.min(y)), (max_x.max(x), max_y.max(y)))
+                }
+            )
+        }
+    }
+
+    /// Find how much of a tower, given as triangles, is inside the
+    /// region. Vertices alone aren't enough: a tower can have every
+    /// vertex inside a non-convex region while its edges cross a notch,
+    /// or every vertex outside a region smaller than the tower. So the
+    /// edges of the triangles are also tested against the boundary, and
+    /// the corners of the boundary against the triangles, all in the
+    /// xy-plane.
+    pub fn coverage(&self, triangles: &[&Triangles]) -> Coverage {
+        let positions = triangles.iter()
+            .flat_map(|triangles| triangles.positions.iter());
+        let count = positions.clone().count();
+        let inside = positions
+            .filter(|(x, y, _)| self.contains(*x, *y))
+            .count();
+        if inside != 0 && inside != count {
+            return Coverage::Partial;
+        }
+
+        let corners: Vec<[(f64, f64); 3]> = triangles.iter()
+            .flat_map(|triangles| triangles.indices.chunks(3).map(|triangle| {
+                [0, 1, 2].map(|i| {
+                    let (x, y, _) = triangles.positions[triangle[i] as usize];
+                    (x, y)
+                })
+            }))
+            .collect();
+        let boundary = self.boundary();
+        let n = boundary.len();
+        let crosses_boundary = corners.iter().any(|triangle| {
+            (0..3).any(|i| {
+                let (a, b) = (triangle[i], triangle[(i + 1) % 3]);
+                (0..n).any(|j| segments_cross(a, b, boundary[j], boundary[(j + 1) % n]))
+            })
+        });
+        let covers_boundary = boundary.iter()
+            .any(|point| corners.iter().any(|triangle| triangle_contains(triangle, *point)));
+
+        if crosses_boundary || covers_boundary {
+            Coverage::Partial
+        } else if inside == count {
+            Coverage::Inside
+        } else {
+            Coverage::Outside
+        }
+    }
+
+    /// The boundary as a counterclockwise polygon
+    fn boundary(&self) -> Vec<(f64, f64)> {
+        let mut points = match &self.shape {
+            RegionShape::Circle { center, radius } => {
+                let (cx, cy) = center;
+                (0..CIRCLE_SEGMENTS)
+                    .map(|i| {
+                        let angle = 2.0 * PI * i as f64 / CIRCLE_SEGMENTS as f64;
+                        (cx + radius * angle.cos(), cy + radius * angle.sin())
+                    })
+                    .collect()
+            },
+            RegionShape::Rectangle { min: (min_x, min_y), max: (max_x, max_y) } => vec![
+                (*min_x, *min_y),
+                (*max_x, *min_y),
+                (*max_x, *max_y),
+                (*min_x, *max_y)
+            ],
+            RegionShape::Polygon(points) => points.clone()
+        };
+
+        let n = points.len();
+        let double_area: f64 = (0..n)
+            .map(|i| {
+                let (ax, ay) = points[i];
+                let (bx, by) = points[(i + 1) % n];
+                ax * by - bx * ay
+            })
+            .sum();
+        if double_area < 0.0 {
+            points.reverse();
+        }
+        points
+    }
+
+    /// Split the region into convex pieces. Non-convex polygons are
+    /// split into triangles.
+    fn convex_pieces(&self) -> Vec<Vec<(f64, f64)>> {
+        let boundary = self.boundary();
+        let n = boundary.len();
+        let is_convex = (0..n).all(|i| {
+            let (ax, ay) = boundary[i];
+            let (bx, by) = boundary[(i + 1) % n];
+            let (cx, cy) = boundary[(i + 2) % n];
+            (bx - ax) * (cy - by) - (by - ay) * (cx - bx) >= 0.0
+        });
+        if is_convex {
+            return vec![boundary];
+        }
+
+        let positions: Vec<Vec3> = boundary.iter()
+            .map(|&(x, y)| (x, y, 0.0))
+            .collect();
+        Mesh::triangulate_polygon(&positions, (0.0, 0.0, 1.0))
+            .chunks(3)
+            .map(|triangle| triangle.iter().map(|&i| boundary[i]).collect())
+            .collect()
+    }
+
+    /// Cut triangles along the boundary of the region, keeping the parts
+    /// inside.
+    pub fn clip(&self, triangles: &Triangles) -> Triangles {
+        let mut result = Triangles::new();
+        for piece in self.convex_pieces() {
+            for triangle in triangles.indices.chunks(3) {
+                // Texture coordinates are interpolated as a Vec3 with z = 0
+                let corners = triangle.iter()
+                    .map(|&i| {
+                        let i = i as usize;
+                        let (u, v) = triangles.uvs.get(i).copied().unwrap_or((0.0, 0.0));
+                        (triangles.positions[i], triangles.normals[i], (u, v, 0.0))
+                    })
+                    .collect();
+                let polygon = clip_convex(corners, &piece);
+                if polygon.len() < 3 {
+                    continue;
+                }
+
+                // The clipped polygon is convex, so a fan works
+                let offset = result.positions.len() as u32;
+                for (position, normal, (u, v, _)) in polygon.iter() {
+                    result.positions.push(*position);
+                    result.normals.push(vec3::normalize(*normal));
+                    if !triangles.uvs.is_empty() {
+                        result.uvs.push((*u, *v));
+                    }
+                }
+                // Every vertex of a triangle belongs to the same tower, so
+                // feature IDs and colors are the same at every corner
+                if let Some(feature_id) = triangles.feature_ids.get(triangle[0] as usize) {
+                    result.feature_ids.extend(std::iter::repeat_n(*feature_id, polygon.len()));
+                }
+                if let Some(color) = triangles.colors.get(triangle[0] as usize) {
+                    result.colors.extend(std::iter::repeat_n(*color, polygon.len()));
+                }
+                for i in 1..(polygon.len() - 1) as u32 {
+                    result.indices.extend_from_slice(&[offset, offset + i, offset + i + 1]);
+                }
+            }
+        }
+        result
+    }
+}
+
+/// Twice the signed area of the triangle abc, positive if it turns
+/// counterclockwise
+fn orientation(a: (f64, f64), b: (f64, f64), c: (f64, f64)) -> f64 {
+    let (ax, ay) = a;
+    let (bx, by) = b;
+    let (cx, cy) = c;
+    (bx - ax) * (cy - ay) - (by - ay) * (cx - ax)
+}
+
+/// Whether the segments ab and cd cross at a point inside both.
+/// Touching at an end doesn't count.
+fn segments_cross(a: (f64, f64), b: (f64, f64), c: (f64, f64), d: (f64, f64)) -> bool {
+    orientation(a, b, c) * orientation(a, b, d) < 0.0
+        && orientation(c, d, a) * orientation(c, d, b) < 0.0
+}
+
+/// Whether the point is strictly inside the triangle, either winding
+fn triangle_contains(triangle: &[(f64, f64); 3], point: (f64, f64)) -> bool {
+    let [a, b, c] = *triangle;
+    let sides = [orientation(a, b, point), orientation(b, c, point), orientation(c, a, point)];
+    sides.iter().all(|side| *side > 0.0) || sides.iter().all(|side| *side < 0.0)
+}
+
+/// Sutherland-Hodgman clipping of a polygon of (position, normal, uv)
+/// corners against a counterclockwise convex polygon in the xy-plane
+fn clip_convex(
+    mut polygon: Vec<(Vec3, Vec3, Vec3)>,
+    convex: &[(f64, f64)]
+) -> Vec<(Vec3, Vec3, Vec3)> {
+    let n = convex.len();
+    for i in 0..n {
+        if polygon.is_empty() {
+            break;
+        }
+
+        let (ax, ay) = convex[i];
+        let (bx, by) = convex[(i + 1) % n];
+        // Positive to the left of the edge, which is inside
+        let side = |(x, y, _): Vec3| (bx - ax) * (y - ay) - (by - ay) * (x - ax);
+
+        let m = polygon.len();
+        let mut clipped = Vec::new();
+        for j in 0..m {
+            let current = polygon[j];
+            let next = polygon[(j + 1) % m];
+            let current_side = side(current.0);
+            let next_side = side(next.0);
+            if current_side >= 0.0 {
+                clipped.push(current);
+            }
+            if (current_side >= 0.0) != (next_side >= 0.0) {
+                let t = current_side / (current_side - next_side);
+                clipped.push((
+                    vec3::lerp(current.0, next.0, t),
+                    vec3::lerp(current.1, next.1, t),
+                    vec3::lerp(current.2, next.2, t)
+                ));
+            }
+        }
+        polygon = clipped;
+    }
+    polygon
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn moved(triangles: &Triangles, offset: Vec3) -> Triangles {
+        Triangles {
+            positions: triangles.positions.iter().map(|&p| vec3::add(p, offset)).collect(),
+            ..triangles.clone()
+        }
+    }
+
+    fn area(triangles: &Triangles) -> f64 {
+        triangles.indices.chunks(3)
+            .map(|triangle| {
+                let a = triangles.positions[triangle[0] as usize];
+                let b = triangles.positions[triangle[1] as usize];
+                let c = triangles.positions[triangle[2] as usize];
+                0.5 * vec3::length(vec3::cross(vec3::sub(b, a), vec3::sub(c, a)))
+            })
+            .sum()
+    }
+
+    fn unit_square() -> Triangles {
+        Triangles {
+            positions: vec![
+                (0.0, 0.0, 0.0),
+                (1.0, 0.0, 0.0),
+                (1.0, 1.0, 0.0),
+                (0.0, 1.0, 0.0)
+            ],
+            normals: vec![(0.0, 0.0, 1.0); 4],
+            indices: vec![0, 1, 2, 0, 2, 3],
+            uvs: vec![(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)],
+            feature_ids: vec![7; 4],
+            colors: vec![(1.0, 0.5, 0.0); 4]
+        }
+    }
+
+    #[test]
+    fn coverage_of_circle() {
+        let region = Region {
+            shape: RegionShape::Circle { center: (0.0, 0.0), radius: 2.0 },
+            mode: ClipMode::Drop
+        };
+        let square = unit_square();
+        assert_eq!(region.coverage(&[&square]), Coverage::Inside);
+        let shifted = moved(&square, (1.5, 0.0, 0.0));
+        assert_eq!(region.coverage(&[&shifted]), Coverage::Partial);
+        let far = moved(&square, (5.0, 0.0, 0.0));
+        assert_eq!(region.coverage(&[&far]), Coverage::Outside);
+    }
+
+    #[test]
+    fn coverage_without_vertices_inside() {
+        // A region smaller than the tower
+        let small = Region {
+            shape: RegionShape::Circle { center: (0.5, 0.5), radius: 0.1 },
+            mode: ClipMode::Drop
+        };
+        assert_eq!(small.coverage(&[&unit_square()]), Coverage::Partial);
+
+        // A thin strip through the middle of the tower
+        let strip = Region {
+            shape: RegionShape::Rectangle { min: (-1.0, 0.4), max: (2.0, 0.6) },
+            mode: ClipMode::Drop
+        };
+        assert_eq!(strip.coverage(&[&unit_square()]), Coverage::Partial);
+
+        // A notch in a non-convex region cuts between the vertices
+        let notched = Region {
+            shape: RegionShape::Polygon(vec![
+                (-1.0, -1.0),
+                (2.0, -1.0),
+                (2.0, 2.0),
+                (0.6, 2.0),
+                (0.5, 0.5),
+                (0.4, 2.0),
+                (-1.0, 2.0)
+            ]),
+            mode: ClipMode::Drop
+        };
+        let square = unit_square();
+        assert!(square.positions.iter().all(|(x, y, _)| notched.contains(*x, *y)));
+        assert_eq!(notched.coverage(&[&square]), Coverage::Partial);
+    }
+
+    #[test]
+    fn clip_by_rectangle() {
+        let region = Region {
+            shape: RegionShape::Rectangle { min: (0.5, -1.0), max: (2.0, 0.25) },
+            mode: ClipMode::Cut
+        };
+        let clipped = region.clip(&unit_square());
+        assert!((area(&clipped) - 0.125).abs() < 1e-12);
+        for (x, y, _) in clipped.positions.iter() {
+            assert!(region.contains(*x, *y));
+        }
+        assert!(clipped.normals.iter().all(|n| *n == (0.0, 0.0, 1.0)));
+        assert_eq!(clipped.feature_ids, vec![7; clipped.positions.len()]);
+        assert_eq!(clipped.colors, vec![(1.0, 0.5, 0.0); clipped.positions.len()]);
+        // The square's texture coordinates match its positions
+        for ((x, y, _), (u, v)) in clipped.positions.iter().zip(clipped.uvs.iter()) {
+            assert!((x - u).abs() < 1e-12 && (y - v).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn clip_by_nonconvex_polygon() {
+        // L-shape covering 3/4 of the unit square, clockwise
+        let region = Region {
+            shape: RegionShape::Polygon(vec![
+                (0.0, 0.0),
+                (0.0, 1.0),
+                (0.5, 1.0),
+                (0.5, 0.5),
+                (1.0, 0.5),
+                (1.0, 0.0)
+            ]),
+            mode: ClipMode::Cut
+        };
+        assert!(region.contains(0.25, 0.75));
+        assert!(!region.contains(0.75, 0.75));
+        let clipped = region.clip(&unit_square());
+        assert!((area(&clipped) - 0.75).abs() < 1e-12);
+    }
+}
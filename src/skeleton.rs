@@ -0,0 +1,449 @@
+//! Polygon offsetting based on the straight skeleton. Each edge of the
+//! polygon moves inwards at unit speed while staying parallel to itself,
+//! and the vertices slide along the angle bisectors.
+//!
+//! first_event() finds both edge events (an edge shrinking to nothing)
+//! and split events (a reflex vertex running into another edge), so
+//! offsets work on any simple polygon up to that point. hip_roof() only
+//! follows edge events, so the full skeleton is only computed for convex
+//! polygons. This is enough for tilings by regular polygons.
+
+use crate::vec3::{self, Vec3};
+
+/// Lengths below this are treated as zero
+const EPSILON: f64 = 1e-9;
+
+/// For each edge, the unit normal pointing into the polygon. normal is the
+/// normal of the polygon, the polygon must be counterclockwise around it.
+fn inward_normals(positions: &[Vec3], normal: Vec3) -> Vec<Vec3> {
+    let n = positions.len();
+    (0..n)
+        .map(|i| {
+            let edge = vec3::sub(positions[(i + 1) % n], positions[i]);
+            vec3::normalize(vec3::cross(normal, edge))
+        })
+        .collect()
+}
+
+/// Velocity of a vertex between two edges with the given inward normals.
+/// The vertex must move one unit along both normals so it lies on both
+/// offset edges. v = (n0 + n1) / (1 + n0 . n1) satisfies
+/// v . n0 = v . n1 = 1. If the edges are antiparallel, the vertex is the
+/// end of a ridge and doesn't move.
+fn bisector_velocity(previous: Vec3, next: Vec3) -> Vec3 {
+    let denominator = 1.0 + vec3::dot(previous, next);
+    if denominator < EPSILON {
+        return (0.0, 0.0, 0.0);
+    }
+    vec3::scale(vec3::add(previous, next), 1.0 / denominator)
+}
+
+/// For each vertex, the direction it moves in when the edges move inwards
+/// by one unit.
+fn vertex_velocities(positions: &[Vec3], normal: Vec3) -> Vec<Vec3> {
+    let n = positions.len();
+    let normals = inward_normals(positions, normal);
+    (0..n)
+        .map(|i| bisector_velocity(normals[(i + n - 1) % n], normals[i]))
+        .collect()
+}
+
+/// Time until the edge from a to b shrinks to zero length, if it is
+/// shrinking at all.
+fn collapse_time(a: Vec3, velocity_a: Vec3, b: Vec3, velocity_b: Vec3) -> Option<f64> {
+    let edge = vec3::sub(b, a);
+    let length = vec3::length(edge);
+    let relative_velocity = vec3::sub(velocity_b, velocity_a);
+    if length < EPSILON {
+        // Already collapsed
+        return Some(0.0);
+    }
+
+    let growth = vec3::dot(relative_velocity, vec3::scale(edge, 1.0 / length));
+    if growth < 0.0 {
+        Some(-length / growth)
+    } else {
+        None
+    }
+}
+
+/// A vertex of the shrinking polygon
+struct WavefrontVertex {
+    position: Vec3,
+    velocity: Vec3,
+    /// The skeleton node (see Roof) where this vertex started
+    node: usize
+}
+
+/// A hip roof over a convex polygon, built from its straight skeleton.
+pub struct Roof {
+    /// The skeleton nodes, already raised to the height of the roof
+    pub nodes: Vec<Vec3>,
+    /// One face per edge of the polygon. Indices less than the number
+    /// of polygon vertices refer to the polygon, the rest refer to
+    /// nodes[i - n]
+    pub faces: Vec<Vec<usize>>
+}
+
+/// Build a hip roof over a convex polygon. Every face of the roof rises
+/// from one edge of the polygon with the given slope (rise over run).
+/// Non-convex polygons need split events, which aren't handled, so they
+/// return None.
+pub fn hip_roof(positions: &[Vec3], normal: Vec3, slope: f64) -> Option<Roof> {
+    let n = positions.len();
+    if !is_convex(positions, normal) {
+        return None;
+    }
+
+    let normals = inward_normals(positions, normal);
+    let velocities = vertex_velocities(positions, normal);
+
+    // The wavefront starts as the polygon itself. Wavefront edge i goes
+    // from vertex i to vertex i + 1, and edges[i] is the original polygon
+    // edge it came from.
+    let mut vertices: Vec<WavefrontVertex> = (0..n)
+        .map(|i| WavefrontVertex {
+            position: positions[i],
+            velocity: velocities[i],
+            node: i
+        })
+        .collect();
+    let mut edges: Vec<usize> = (0..n).collect();
+
+    // Each roof face is the polygon edge, the skeleton nodes visited by
+    // the vertex at the end of the edge (right), then the nodes visited by
+    // the vertex at the start of the edge (left) in reverse.
+    let mut right: Vec<Vec<usize>> = vec![Vec::new(); n];
+    let mut left: Vec<Vec<usize>> = vec![Vec::new(); n];
+
+    let mut nodes: Vec<Vec3> = Vec::new();
+    let mut time = 0.0;
+
+    // Add a node at the given position in the wavefront, reusing nodes at
+    // the same point. This happens when several edges collapse at once.
+    let add_node = |nodes: &mut Vec<Vec3>, position: Vec3, time: f64| {
+        let raised = vec3::add(position, vec3::scale(normal, time * slope));
+        let existing = nodes.iter()
+            .position(|node| vec3::length(vec3::sub(*node, raised)) < EPSILON);
+        existing.unwrap_or_else(|| {
+            nodes.push(raised);
+            nodes.len() - 1
+        }) + n
+    };
+
+    while vertices.len() > 2 {
+        let m = vertices.len();
+
+        // Find the next edge to collapse
+        let event = (0..m)
+            .filter_map(|i| {
+                let a = &vertices[i];
+                let b = &vertices[(i + 1) % m];
+                collapse_time(a.position, a.velocity, b.position, b.velocity)
+                    .map(|dt| (dt, i))
+            })
+            .min_by(|(a, _), (b, _)| a.total_cmp(b));
+        // If nothing is shrinking, the roof can't be closed
+        let (dt, i) = event?;
+
+        time += dt;
+        for vertex in vertices.iter_mut() {
+            vertex.position = vec3::add(vertex.position, vec3::scale(vertex.velocity, dt));
+        }
+
+        // The two ends of the edge merge into a new vertex
+        let j = (i + 1) % m;
+        let previous_edge = edges[(i + m - 1) % m];
+        let collapsed_edge = edges[i];
+        let next_edge = edges[j];
+        let node = add_node(&mut nodes, vertices[i].position, time);
+        right[collapsed_edge].push(node);
+        right[previous_edge].push(node);
+        left[next_edge].push(node);
+
+        // Keep the list in order when the collapsed edge wraps around
+        let (keep, remove) = if j == 0 { (0, i) } else { (i, j) };
+        vertices[keep] = WavefrontVertex {
+            position: vertices[i].position,
+            velocity: bisector_velocity(normals[previous_edge], normals[next_edge]),
+            node
+        };
+        vertices.remove(remove);
+        edges.remove(i);
+    }
+
+    // Any remaining vertices that moved since their last node end at one
+    // more node.
+    let m = vertices.len();
+    for i in 0..m {
+        let position = vec3::add(vertices[i].position, vec3::scale(normal, time * slope));
+        let node_position = if vertices[i].node < n {
+            positions[vertices[i].node]
+        } else {
+            nodes[vertices[i].node - n]
+        };
+        if vec3::length(vec3::sub(position, node_position)) < EPSILON {
+            continue;
+        }
+
+        let node = add_node(&mut nodes, vertices[i].position, time);
+        right[edges[(i + m - 1) % m]].push(node);
+        left[edges[i]].push(node);
+    }
+
+    let faces = (0..n)
+        .map(|i| {
+            let mut face = vec![i, (i + 1) % n];
+            face.extend(right[i].iter());
+            face.extend(left[i].iter().rev());
+
+            // Remove repeated nodes where the two sides of the face meet
+            face.dedup();
+            while face.len() > 1 && face.first() == face.last() {
+                face.pop();
+            }
+            face
+        })
+        .collect();
+
+    Some(Roof {
+        nodes,
+        faces
+    })
+}
+
+/// Move every edge of a polygon inwards by distance, keeping the edges
+/// parallel to the originals. This matches the straight skeleton
+/// wavefront as long as distance is less than first_event().
+pub fn offset_polygon(positions: &[Vec3], normal: Vec3, distance: f64) -> Vec<Vec3> {
+    vertex_velocities(positions, normal).iter()
+        .zip(positions.iter())
+        .map(|(velocity, position)| {
+            vec3::add(*position, vec3::scale(*velocity, distance))
+        })
+        .collect()
+}
+
+/// Time until a reflex vertex hits the offset copy of edge k, if it
+/// ever does. The offset edge is the line dot(p - a, normal) = t, so
+/// solve dot(v + w t - a, normal) = t, then make sure the hit is between
+/// the moving ends of the edge.
+fn split_time(
+    positions: &[Vec3],
+    velocities: &[Vec3],
+    normals: &[Vec3],
+    vertex: usize,
+    k: usize
+) -> Option<f64> {
+    let n = positions.len();
+    let a = positions[k];
+    let b = positions[(k + 1) % n];
+    let v = positions[vertex];
+    let w = velocities[vertex];
+    let denominator = 1.0 - vec3::dot(w, normals[k]);
+    if denominator < EPSILON {
+        return None;
+    }
+    let time = vec3::dot(vec3::sub(v, a), normals[k]) / denominator;
+    if time <= 0.0 {
+        return None;
+    }
+
+    let hit = vec3::add(v, vec3::scale(w, time));
+    let start = vec3::add(a, vec3::scale(velocities[k], time));
+    let end = vec3::add(b, vec3::scale(velocities[(k + 1) % n], time));
+    let edge = vec3::sub(end, start);
+    let length_squared = vec3::dot(edge, edge);
+    let along = vec3::dot(vec3::sub(hit, start), edge);
+    if (0.0..=length_squared).contains(&along) {
+        Some(time)
+    } else {
+        None
+    }
+}
+
+/// Whether the polygon turns the wrong way at a vertex, bending into
+/// the polygon
+fn is_reflex(positions: &[Vec3], normal: Vec3, vertex: usize) -> bool {
+    let n = positions.len();
+    let previous = positions[(vertex + n - 1) % n];
+    let next = positions[(vertex + 1) % n];
+    let incoming = vec3::sub(positions[vertex], previous);
+    let outgoing = vec3::sub(next, positions[vertex]);
+    vec3::dot(vec3::cross(incoming, outgoing), normal) < -EPSILON
+}
+
+/// Whether every vertex of the polygon is convex
+pub fn is_convex(positions: &[Vec3], normal: Vec3) -> bool {
+    (0..positions.len()).all(|i| !is_reflex(positions, normal, i))
+}
+
+/// Compute the offset distance of the first skeleton event, where an
+/// edge of the polygon shrinks to zero length or a reflex vertex hits
+/// another edge. Past this point, offset_polygon() would turn the
+/// polygon inside-out or make it cross itself. For regular polygons
+/// this is the inradius.
+pub fn first_event(positions: &[Vec3], normal: Vec3) -> f64 {
+    let velocities = vertex_velocities(positions, normal);
+    let normals = inward_normals(positions, normal);
+    let n = positions.len();
+    let edge_events = (0..n).filter_map(|i| {
+        let j = (i + 1) % n;
+        collapse_time(positions[i], velocities[i], positions[j], velocities[j])
+    });
+
+    // The edges next to a reflex vertex can't be hit by it
+    let split_events = (0..n)
+        .filter(|&i| is_reflex(positions, normal, i))
+        .flat_map(|i| {
+            (0..n)
+                .filter(move |&k| k != i && (k + 1) % n != i)
+                .map(move |k| (i, k))
+        })
+        .filter_map(|(i, k)| split_time(positions, &velocities, &normals, i, k));
+
+    edge_events.chain(split_events).fold(f64::INFINITY, f64::min)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UP: Vec3 = (0.0, 0.0, 1.0);
+
+    fn assert_close(actual: Vec3, expected: Vec3) {
+        assert!(
+            vec3::length(vec3::sub(actual, expected)) < 1e-12,
+            "{:?} != {:?}", actual, expected
+        );
+    }
+
+    #[test]
+    fn offset_keeps_edges_parallel() {
+        // For a rectangle, a proportional inset would move the long sides
+        // further than the short sides. The skeleton offset moves all
+        // sides by the same distance
+        let rectangle = [
+            (0.0, 0.0, 0.0),
+            (4.0, 0.0, 0.0),
+            (4.0, 2.0, 0.0),
+            (0.0, 2.0, 0.0),
+        ];
+        let offset = offset_polygon(&rectangle, UP, 0.5);
+        assert_close(offset[0], (0.5, 0.5, 0.0));
+        assert_close(offset[1], (3.5, 0.5, 0.0));
+        assert_close(offset[2], (3.5, 1.5, 0.0));
+        assert_close(offset[3], (0.5, 1.5, 0.0));
+
+        // The short sides meet first, when the rectangle becomes a line
+        assert!((first_event(&rectangle, UP) - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn regular_polygon_event_is_inradius() {
+        let n = 6;
+        let hexagon: Vec<Vec3> = (0..n)
+            .map(|i| {
+                let angle = 2.0 * std::f64::consts::PI * (i as f64) / (n as f64);
+                (angle.cos(), angle.sin(), 0.0)
+            })
+            .collect();
+        let inradius = (std::f64::consts::PI / 6.0).cos();
+        assert!((first_event(&hexagon, UP) - inradius).abs() < 1e-12);
+    }
+
+    #[test]
+    fn split_event_comes_first_in_a_notch() {
+        // A V-shaped notch in the top of a rectangle. The tip of the
+        // notch moves down at sqrt(2) and the bottom edge moves up at 1,
+        // so they meet before any edge collapses.
+        let notched = [
+            (0.0, 0.0, 0.0),
+            (6.0, 0.0, 0.0),
+            (6.0, 4.0, 0.0),
+            (4.0, 4.0, 0.0),
+            (3.0, 3.0, 0.0),
+            (2.0, 4.0, 0.0),
+            (0.0, 4.0, 0.0),
+        ];
+        let expected = 3.0 / (1.0 + 2f64.sqrt());
+        assert!((first_event(&notched, UP) - expected).abs() < 1e-12);
+        assert!(!is_convex(&notched, UP));
+        assert!(hip_roof(&notched, UP, 1.0).is_none());
+
+        let offset = offset_polygon(&notched, UP, expected);
+        assert_close(offset[4], (3.0, expected, 0.0));
+    }
+
+    #[test]
+    fn hip_roof_over_rectangle() {
+        let rectangle = [
+            (0.0, 0.0, 0.0),
+            (4.0, 0.0, 0.0),
+            (4.0, 2.0, 0.0),
+            (0.0, 2.0, 0.0),
+        ];
+        let roof = hip_roof(&rectangle, UP, 0.5).unwrap();
+
+        // The ridge runs along the middle at height 0.5
+        assert_eq!(roof.nodes.len(), 2);
+        assert!(roof.nodes.contains(&(3.0, 1.0, 0.5)));
+        assert!(roof.nodes.contains(&(1.0, 1.0, 0.5)));
+
+        // Long sides are trapezoids, short sides are triangles
+        let sizes: Vec<usize> = roof.faces.iter().map(|f| f.len()).collect();
+        assert_eq!(sizes, vec![4, 3, 4, 3]);
+        let ridge_end = |index: usize| roof.nodes[index - 4];
+        assert_eq!(ridge_end(roof.faces[0][2]), (3.0, 1.0, 0.5));
+        assert_eq!(ridge_end(roof.faces[0][3]), (1.0, 1.0, 0.5));
+    }
+
+    #[test]
+    fn hip_roof_over_regular_polygon_is_a_pyramid() {
+        let triangle: Vec<Vec3> = (0..3)
+            .map(|i| {
+                let angle = 2.0 * std::f64::consts::PI * (i as f64) / 3.0;
+                (angle.cos(), angle.sin(), 0.0)
+            })
+            .collect();
+        let roof = hip_roof(&triangle, UP, 1.0).unwrap();
+
+        // Everything meets at the center, at a height of the inradius
+        assert_eq!(roof.nodes.len(), 1);
+        assert_close(roof.nodes[0], (0.0, 0.0, 0.5));
+        assert_eq!(roof.faces, vec![vec![0, 1, 3], vec![1, 2, 3], vec![2, 0, 3]]);
+    }
+
+    #[test]
+    fn hip_roof_over_irregular_hexagon() {
+        // A hexagon stretched in x, so the skeleton has a ridge
+        let hexagon = [
+            (0.0, 0.0, 0.0),
+            (3.0, 0.0, 0.0),
+            (4.0, 1.0, 0.0),
+            (3.0, 2.0, 0.0),
+            (0.0, 2.0, 0.0),
+            (-1.0, 1.0, 0.0),
+        ];
+        let roof = hip_roof(&hexagon, UP, 1.0).unwrap();
+
+        // Every roof face starts with its edge of the hexagon, and every
+        // node is shared by at least 3 faces
+        assert_eq!(roof.faces.len(), 6);
+        for (i, face) in roof.faces.iter().enumerate() {
+            assert_eq!(face[0..2], [i, (i + 1) % 6]);
+        }
+        for node in 6..(6 + roof.nodes.len()) {
+            let count = roof.faces.iter()
+                .filter(|face| face.contains(&node))
+                .count();
+            assert!(count >= 3);
+        }
+
+        // The ridge is at height 1, the distance to the long sides
+        for (_, y, z) in roof.nodes.iter() {
+            assert!((y - 1.0).abs() < 1e-9);
+            assert!((z - 1.0).abs() < 1e-9);
+        }
+    }
+}
@@ -3,15 +3,31 @@ use std::f64::consts::PI;
 use serde::Deserialize;
 use serde_json::json;
 
+use crate::coloring::AutoMaterials;
+use crate::gltf::ExportOptions;
+use crate::height::HeightModulation;
+use crate::mesh::{CapStyle, Shading};
+use crate::profile::{
+    InsetMode,
+    LayerMaterials,
+    ProfileScale,
+    ProfileShape,
+    DEFAULT_BASE_HEIGHT,
+    DEFAULT_CENTER_STEP,
+    DEFAULT_NORMAL_STEP
+};
+use crate::region::Region;
+use crate::towers::IntersectionPolicy;
+use crate::variation::InstanceVariation;
 use crate::vec3::Vec3;
 
 pub type TilingVector = (i32, i32, i32, i32);
 
 fn make_twelfth_root_basis() -> [Vec3; 12] {
     let mut result = [(0.0, 0.0, 0.0); 12];
-    for i in 0..12 {
+    for (i, direction) in result.iter_mut().enumerate() {
         let angle = (i as f64) * PI / 6.0;
-        result[i] = (angle.cos(), angle.sin(), 0.0);
+        *direction = (angle.cos(), angle.sin(), 0.0);
     }
     result
 }
@@ -68,7 +84,42 @@ impl Basis {
 #[derive(Deserialize)]
 pub struct Profile {
     pub name: Option<String>,
-    pub offsets: Vec<(i32, i32)>
+    /// Either integer offsets or a curve, see ProfileShape
+    #[serde(flatten)]
+    pub shape: ProfileShape,
+    /// Chamfer the top of the tower with (amount, depth), see Mesh::bevel()
+    pub bevel: Option<(f64, f64)>,
+    /// Number of Catmull-Clark subdivision steps to round off the tower
+    pub subdivisions: Option<usize>,
+    /// Override the shading for towers with this profile
+    pub shading: Option<Shading>,
+    /// Override the tiling's center_step for this profile
+    pub center_step: Option<f64>,
+    /// Override the tiling's normal_step for this profile
+    pub normal_step: Option<f64>,
+    /// Override the tiling's base_height for this profile
+    pub base_height: Option<f64>,
+    /// Override the tiling's inset_mode for this profile
+    pub inset_mode: Option<InsetMode>,
+    /// Use different materials for parts of the tower
+    pub layer_materials: Option<LayerMaterials>
+}
+
+/// How the alpha channel of a material is used
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum AlphaMode {
+    Opaque,
+    /// Fully transparent below the cutoff, opaque otherwise
+    Mask {
+        cutoff: f64
+    },
+    Blend
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Clearcoat {
+    pub factor: f64,
+    pub roughness: f64
 }
 
 /// Simplified material that can be converted to a glTF
@@ -77,20 +128,141 @@ pub struct Profile {
 pub struct Material {
     pub base_color: Vec3,
     pub metallic: f64,
-    pub roughness: f64
+    pub roughness: f64,
+    /// Alpha of the base color
+    #[serde(default = "default_opacity")]
+    pub opacity: f64,
+    /// Defaults to Blend if the opacity is less than 1, Opaque otherwise
+    pub alpha_mode: Option<AlphaMode>,
+    #[serde(default)]
+    pub emissive: Vec3,
+    /// Scale the emissive color past 1 with
+    /// KHR_materials_emissive_strength
+    pub emissive_strength: Option<f64>,
+    #[serde(default)]
+    pub double_sided: bool,
+    /// Fraction of light transmitted through the surface, with
+    /// KHR_materials_transmission
+    pub transmission: Option<f64>,
+    /// A clear layer on top of the material, with
+    /// KHR_materials_clearcoat
+    pub clearcoat: Option<Clearcoat>,
+    /// PNG or JPEG image files embedded in the GLB. Textures are
+    /// multiplied by the factors above.
+    pub base_color_texture: Option<String>,
+    pub normal_texture: Option<String>,
+    /// Occlusion in the red channel, roughness in green and metallic in
+    /// blue, as glTF expects
+    pub orm_texture: Option<String>
+}
+
+fn default_opacity() -> f64 {
+    1.0
 }
 
 impl Material {
-    pub fn to_json(&self) -> serde_json::Value {
+    /// glTF extensions needed by this material
+    pub fn extensions_used(&self) -> Vec<&'static str> {
+        let mut result = Vec::new();
+        if self.emissive_strength.is_some() {
+            result.push("KHR_materials_emissive_strength");
+        }
+        if self.transmission.is_some() {
+            result.push("KHR_materials_transmission");
+        }
+        if self.clearcoat.is_some() {
+            result.push("KHR_materials_clearcoat");
+        }
+        result
+    }
+
+    pub fn texture_paths(&self) -> impl Iterator<Item = &String> {
+        self.base_color_texture.iter()
+            .chain(self.normal_texture.iter())
+            .chain(self.orm_texture.iter())
+    }
+
+    pub fn has_textures(&self) -> bool {
+        self.texture_paths().next().is_some()
+    }
+
+    /// Convert to glTF JSON. texture_index looks up the glTF texture for
+    /// an image path.
+    pub fn to_json(&self, texture_index: impl Fn(&str) -> usize) -> serde_json::Value {
         let (r, g, b) = self.base_color;
+        let (er, eg, eb) = self.emissive;
 
-        json!({
+        let mut result = json!({
             "pbrMetallicRoughness": {
-                "baseColorFactor": [r, g, b, 1.0],
+                "baseColorFactor": [r, g, b, self.opacity],
                 "metallicFactor": self.metallic,
                 "roughnessFactor": self.roughness
-            }
-        })
+            },
+            "emissiveFactor": [er, eg, eb],
+            "doubleSided": self.double_sided
+        });
+
+        let alpha_mode = self.alpha_mode.unwrap_or(if self.opacity < 1.0 {
+            AlphaMode::Blend
+        } else {
+            AlphaMode::Opaque
+        });
+        match alpha_mode {
+            AlphaMode::Opaque => result["alphaMode"] = json!("OPAQUE"),
+            AlphaMode::Mask { cutoff } => {
+                result["alphaMode"] = json!("MASK");
+                result["alphaCutoff"] = json!(cutoff);
+            },
+            AlphaMode::Blend => result["alphaMode"] = json!("BLEND")
+        }
+
+        let mut extensions = serde_json::Map::new();
+        if let Some(strength) = self.emissive_strength {
+            extensions.insert(
+                String::from("KHR_materials_emissive_strength"),
+                json!({ "emissiveStrength": strength })
+            );
+        }
+        if let Some(transmission) = self.transmission {
+            extensions.insert(
+                String::from("KHR_materials_transmission"),
+                json!({ "transmissionFactor": transmission })
+            );
+        }
+        if let Some(Clearcoat { factor, roughness }) = self.clearcoat {
+            extensions.insert(
+                String::from("KHR_materials_clearcoat"),
+                json!({
+                    "clearcoatFactor": factor,
+                    "clearcoatRoughnessFactor": roughness
+                })
+            );
+        }
+        if !extensions.is_empty() {
+            result["extensions"] = json!(extensions);
+        }
+
+        if let Some(path) = &self.base_color_texture {
+            result["pbrMetallicRoughness"]["baseColorTexture"] = json!({
+                "index": texture_index(path)
+            });
+        }
+        if let Some(path) = &self.normal_texture {
+            result["normalTexture"] = json!({
+                "index": texture_index(path)
+            });
+        }
+        if let Some(path) = &self.orm_texture {
+            let index = texture_index(path);
+            result["pbrMetallicRoughness"]["metallicRoughnessTexture"] = json!({
+                "index": index
+            });
+            result["occlusionTexture"] = json!({
+                "index": index
+            });
+        }
+
+        result
     }
 }
 
@@ -98,9 +270,13 @@ impl Material {
 #[derive(Deserialize)]
 pub struct TilingFace {
     // Redundant, but helpful for debugging
+    #[allow(dead_code)]
     pub sides: usize,
     pub profile: Option<usize>,
-    pub material: usize
+    pub material: usize,
+    /// How to close off the top of the tower
+    #[serde(default)]
+    pub cap: CapStyle
 }
 
 #[derive(Deserialize)]
@@ -116,4 +292,120 @@ pub struct IntegerTiling {
     pub seeds: Vec<Seed>,
     pub profiles: Vec<Profile>,
     pub materials: Vec<Material>,
-}
\ No newline at end of file
+    /// Choose the materials of the faces automatically
+    pub auto_materials: Option<AutoMaterials>,
+    #[serde(default)]
+    pub shading: Shading,
+    /// Size of each profile center step, see InsetMode
+    #[serde(default = "default_center_step")]
+    pub center_step: f64,
+    /// Height per profile normal step
+    #[serde(default = "default_normal_step")]
+    pub normal_step: f64,
+    /// Height of the extrusion below each profile
+    #[serde(default = "default_base_height")]
+    pub base_height: f64,
+    /// How profiles move the walls of the towers inwards
+    #[serde(default)]
+    pub inset_mode: InsetMode,
+    /// What to do when a profile would turn a tower inside-out
+    #[serde(default)]
+    pub self_intersection: IntersectionPolicy,
+    /// Vary the height of the towers over the plane. Every copy of the
+    /// tiling then gets its own towers, sampled where they end up.
+    pub height: Option<HeightModulation>,
+    /// Vary the repeated copies of the tiling in the glTF output
+    pub instance_variation: Option<InstanceVariation>,
+    /// Fill a region with copies of the tiling instead of a
+    /// parallelogram
+    pub region: Option<Region>,
+    /// Options for the glTF output
+    #[serde(default)]
+    pub export: ExportOptions
+}
+
+fn default_center_step() -> f64 {
+    DEFAULT_CENTER_STEP
+}
+
+fn default_normal_step() -> f64 {
+    DEFAULT_NORMAL_STEP
+}
+
+fn default_base_height() -> f64 {
+    DEFAULT_BASE_HEIGHT
+}
+
+impl IntegerTiling {
+    /// Get the step sizes for a profile, using the tiling-wide values
+    /// unless the profile overrides them.
+    pub fn profile_scale(&self, profile: Option<&Profile>) -> ProfileScale {
+        let center_step = profile.and_then(|p| p.center_step);
+        let normal_step = profile.and_then(|p| p.normal_step);
+        let base_height = profile.and_then(|p| p.base_height);
+        let inset_mode = profile.and_then(|p| p.inset_mode);
+        ProfileScale {
+            center_step: center_step.unwrap_or(self.center_step),
+            normal_step: normal_step.unwrap_or(self.normal_step),
+            base_height: base_height.unwrap_or(self.base_height),
+            inset_mode: inset_mode.unwrap_or(self.inset_mode)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn no_textures(_: &str) -> usize {
+        panic!("no textures expected")
+    }
+
+    #[test]
+    fn simple_material_is_opaque() {
+        let material: Material = serde_json::from_str(
+            r#"{"base_color": [1, 0, 0], "metallic": 0, "roughness": 0.5}"#
+        ).unwrap();
+        let json = material.to_json(no_textures);
+        assert_eq!(json["pbrMetallicRoughness"]["baseColorFactor"], json!([1.0, 0.0, 0.0, 1.0]));
+        assert_eq!(json["alphaMode"], "OPAQUE");
+        assert_eq!(json["doubleSided"], false);
+        assert!(json.get("extensions").is_none());
+        assert!(material.extensions_used().is_empty());
+    }
+
+    #[test]
+    fn full_pbr_material() {
+        let material: Material = serde_json::from_str(r#"{
+            "base_color": [1, 1, 1],
+            "metallic": 0,
+            "roughness": 0.1,
+            "opacity": 0.5,
+            "emissive": [0, 0, 1],
+            "emissive_strength": 4,
+            "double_sided": true,
+            "transmission": 0.9,
+            "clearcoat": {"factor": 1, "roughness": 0.05}
+        }"#).unwrap();
+        let json = material.to_json(no_textures);
+        assert_eq!(json["pbrMetallicRoughness"]["baseColorFactor"][3], 0.5);
+        assert_eq!(json["alphaMode"], "BLEND");
+        assert_eq!(json["emissiveFactor"], json!([0.0, 0.0, 1.0]));
+        assert_eq!(json["doubleSided"], true);
+        let extensions = &json["extensions"];
+        assert_eq!(extensions["KHR_materials_emissive_strength"]["emissiveStrength"], 4.0);
+        assert_eq!(extensions["KHR_materials_transmission"]["transmissionFactor"], 0.9);
+        assert_eq!(extensions["KHR_materials_clearcoat"]["clearcoatRoughnessFactor"], 0.05);
+        assert_eq!(material.extensions_used().len(), 3);
+
+        let masked: Material = serde_json::from_str(r#"{
+            "base_color": [1, 1, 1],
+            "metallic": 0,
+            "roughness": 1,
+            "alpha_mode": {"Mask": {"cutoff": 0.25}}
+        }"#).unwrap();
+        let json = masked.to_json(no_textures);
+        assert_eq!(json["alphaMode"], "MASK");
+        assert_eq!(json["alphaCutoff"], 0.25);
+    }
+}
@@ -1,8 +1,11 @@
 use std::collections::HashMap;
 
+use crate::coloring;
 use crate::tiling::{IntegerTiling, TilingVector};
-use crate::mesh::Mesh;
-use crate::towers::TowerTiling;
+use crate::gltf::GltfError;
+use crate::mesh::{CapStyle, Mesh};
+use crate::towers::{TowerError, TowerSpec, TowerTiling};
+use crate::variation::Instance;
 use crate::vec3::Vec3;
 
 #[derive(Debug)]
@@ -20,7 +23,9 @@ pub struct TilingMesh {
     // tiling.seeds[seed_index].faces?[face_index] if this exists.
     // this table is a map from global_face_index -> (seed_index, face_index)
     anchored_faces: Vec<(usize, usize)>,
-    towers: TowerTiling
+    towers: TowerTiling,
+    // Copies of the tiling to export
+    instances: Vec<Instance>
 }
 
 impl TilingMesh {
@@ -32,7 +37,8 @@ impl TilingMesh {
             tiling,
             basis_coefficients,
             anchored_faces: Vec::new(),
-            towers: TowerTiling::new()
+            towers: TowerTiling::new(),
+            instances: Vec::new()
         }
     }
 
@@ -91,8 +97,7 @@ impl TilingMesh {
             return;
         }
 
-        for i in 0..(n - 1) {
-            let first_direction = star_directions[i];
+        for (i, &first_direction) in star_directions[..(n - 1)].iter().enumerate() {
             self.generate_face(seed, first_direction);
             self.anchored_faces.push((seed, i));
         }
@@ -171,35 +176,98 @@ impl TilingMesh {
         )
     }
 
-    pub fn make_towers(&mut self) {
+    /// Build the towers and the copies of the tiling to place them in,
+    /// and get any warnings about the towers. Without a region, the
+    /// copies form a parallelogram that extends instancing_radius copies
+    /// in each direction.
+    pub fn make_towers(&mut self, instancing_radius: isize) -> Result<Vec<String>, TowerError> {
+        self.instances = self.make_instances(instancing_radius);
+        let height_sampler = match &self.tiling.height {
+            Some(height) => Some(height.sampler()?),
+            None => None
+        };
+
+        let mut warnings = Vec::new();
+        let faces: Vec<Vec<Vec3>> = (0..self.mesh.faces.len())
+            .map(|face| self.mesh.get_face_positions(face))
+            .collect();
+        let [a, b] = self.tiling.translations;
+        let translations = [self.to_world(a), self.to_world(b)];
+        let orbits = coloring::orbit_classes(&faces, translations);
+        let auto_materials = self.tiling.auto_materials.as_ref().map(|auto| {
+            let (materials, auto_warnings) = auto.assign(&faces, translations, self.tiling.materials.len());
+            warnings.extend(auto_warnings);
+            materials
+        });
+
+        // A height field makes every copy of a tower different, so each
+        // copy is built separately and only placed in its own instance
+        let copies: Vec<Option<usize>> = match height_sampler {
+            Some(_) => (0..self.instances.len()).map(Some).collect(),
+            None => vec![None]
+        };
+
         // TODO: I shouldn't be able to do this.
         let n = self.mesh.faces.len();
-        for face in 0..n {
-            let (seed, anchored_face) = self.anchored_faces[face];
+        for instance in copies {
+            for face in 0..n {
+                let (seed, anchored_face) = self.anchored_faces[face];
+
+                let mut profile_index = None;
+                let mut profile = None;
+                let mut material = 0;
+                let mut cap = CapStyle::Flat;
+
+                if let Some(faces) = &self.tiling.seeds[seed].faces {
+                    material = faces[anchored_face].material;
+                    cap = faces[anchored_face].cap;
+                    profile_index = faces[anchored_face].profile;
+                    profile = profile_index.map(|i| &self.tiling.profiles[i]);
+                }
+                if let Some(materials) = &auto_materials {
+                    material = materials[face];
+                }
 
-            let empty = Vec::new();
-            let mut profile = &empty;
-            let mut material = 0;
+                let shading = profile
+                    .and_then(|p| p.shading)
+                    .unwrap_or(self.tiling.shading);
 
-            if let Some(faces) = &self.tiling.seeds[seed].faces {
-                material = faces[anchored_face].material;
-                if let Some(profile_index) = faces[anchored_face].profile {
-                    profile = &self.tiling.profiles[profile_index].offsets;
+                let base = self.mesh.get_face_positions(face);
+                let mut scale = self.tiling.profile_scale(profile);
+                if let (Some(sampler), Some(k)) = (&height_sampler, instance) {
+                    let centroid = Mesh::compute_centroid(&base);
+                    let world_centroid = self.instances[k].transform_point(centroid);
+                    scale.normal_step *= sampler.factor(world_centroid);
                 }
-            }
 
-            let base = self.mesh.get_face_positions(face);
-            self.towers.add_tower(&base, profile, material);
+                let spec = TowerSpec {
+                    seed,
+                    face: anchored_face,
+                    base,
+                    profile_index,
+                    profile,
+                    material,
+                    shading,
+                    scale,
+                    cap,
+                    instance,
+                    orbit: orbits[face]
+                };
+                warnings.extend(self.towers.add_tower(&spec, self.tiling.self_intersection)?);
+            }
         }
+
+        Ok(warnings)
     }
 
-    pub fn save_towers(&self, fname: &str, instancing_radius: isize) {
-        let offsets = self.make_instance_offsets(instancing_radius);
-        self.towers.save_glb(fname, &self.tiling, offsets);
+    /// Save the towers, and get any warnings about the export
+    pub fn save_towers(&self, fname: &str) -> Result<Vec<String>, GltfError> {
+        let warnings = self.towers.save_gltf(fname, &self.tiling, &self.instances)?;
         self.towers.save_obj("output/debug");
+        Ok(warnings)
     }
 
-    fn make_instance_offsets(&self, radius: isize) -> Vec<Vec3> {
+    fn make_instances(&self, radius: isize) -> Vec<Instance> {
         let [a, b] = self.tiling.translations;
         let a = self.to_world(a);
         let b = self.to_world(b);
@@ -207,18 +275,59 @@ impl TilingMesh {
         let (ax, ay, az) = a;
         let (bx, by, bz) = b;
 
+        let ((i_min, i_max), (j_min, j_max)) = match &self.tiling.region {
+            Some(region) => Self::lattice_bounds(region.bounds(), a, b),
+            None => ((-radius, radius), (-radius, radius))
+        };
+
         let mut result = Vec::new();
-        for i in -radius..=radius {
+        for i in i_min..=i_max {
             let i_f64 = i as f64;
-            for j in -radius..=radius {
+            for j in j_min..=j_max {
                 let j_f64 = j as f64;
                 let x = i_f64 * ax + j_f64 * bx;
                 let y = i_f64 * ay + j_f64 * by;
                 let z = i_f64 * az + j_f64 * bz;
-                result.push((x, y, z));
+                let instance = match &self.tiling.instance_variation {
+                    Some(variation) => variation.make_instance(i, j, (x, y, z)),
+                    None => Instance::at((x, y, z))
+                };
+                result.push(instance);
             }
         }
 
         result
     }
-}
\ No newline at end of file
+
+    /// Find the ranges of (i, j) lattice coordinates for the translations
+    /// a and b that cover the bounding box of a region. A margin of
+    /// a couple copies makes sure towers that stick out of the
+    /// fundamental domain are included.
+    fn lattice_bounds(
+        bounds: ((f64, f64), (f64, f64)),
+        a: Vec3,
+        b: Vec3
+    ) -> ((isize, isize), (isize, isize)) {
+        const MARGIN: f64 = 2.0;
+        let ((min_x, min_y), (max_x, max_y)) = bounds;
+        let (ax, ay, _) = a;
+        let (bx, by, _) = b;
+        let determinant = ax * by - bx * ay;
+
+        let corners = [(min_x, min_y), (max_x, min_y), (max_x, max_y), (min_x, max_y)];
+        let mut i_range = (f64::INFINITY, -f64::INFINITY);
+        let mut j_range = (f64::INFINITY, -f64::INFINITY);
+        for (x, y) in corners {
+            // Invert the 2x2 matrix [a b]
+            let i = (by * x - bx * y) / determinant;
+            let j = (ax * y - ay * x) / determinant;
+            i_range = (i_range.0.min(i), i_range.1.max(i));
+            j_range = (j_range.0.min(j), j_range.1.max(j));
+        }
+
+        (
+            ((i_range.0 - MARGIN).floor() as isize, (i_range.1 + MARGIN).ceil() as isize),
+            ((j_range.0 - MARGIN).floor() as isize, (j_range.1 + MARGIN).ceil() as isize)
+        )
+    }
+}
@@ -1,11 +1,305 @@
-use crate::gltf::Gltf;
-use crate::mesh::Mesh;
-use crate::tiling::IntegerTiling;
-use crate::vec3::Vec3;
+use std::fmt;
 
+use serde::Deserialize;
+
+use std::collections::{HashMap, HashSet};
+
+use crate::coloring::ColorAttribute;
+use crate::gltf::{Gltf, GltfError, InstancingMode, NodeLayout, PropertyValues};
+use crate::height::HeightFieldError;
+use crate::mesh::{CapStyle, Mesh, MeshError, Shading, Triangles, UvMapping};
+use crate::profile::{InsetMode, ProfilePoint, ProfileScale};
+use crate::region::{ClipMode, Coverage, Region};
+use crate::skeleton;
+use crate::tiling::{IntegerTiling, Profile};
+use crate::variation::Instance;
+use crate::vec3::{self, Vec3};
+
+/// Keep clamped walls this fraction of the limit away from where they
+/// meet so the top of the tower doesn't collapse
+const CLAMP_MARGIN: f64 = 0.01;
+
+/// Bisection steps when searching for the largest inset a face allows
+const LIMIT_ITERATIONS: usize = 50;
+
+/// (inset, limit) for a profile that insets the walls further than its
+/// face allows, see TowerSpec::find_limit()
+type InsetLimit = (f64, f64);
+
+/// What to do when a profile would inset the walls of a tower so far
+/// that they cross
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+pub enum IntersectionPolicy {
+    /// Stop with an error
+    #[default]
+    Error,
+    /// Limit the inset to just inside the furthest it can go
+    Clamp,
+    /// Build the tower anyway and return a warning
+    Report
+}
+
+#[derive(Debug)]
+pub enum TowerError {
+    /// The tower geometry had a degenerate face
+    Mesh(MeshError),
+    /// The profile insets the walls of the tower so far that a layer
+    /// crosses itself or turns inside-out.
+    SelfIntersection {
+        seed: usize,
+        face: usize,
+        profile: Option<String>,
+        /// How far the walls move inwards
+        inset: f64,
+        /// How far the walls can move inwards before they meet
+        limit: f64
+    },
+    /// The base of the tower must be extruded upwards
+    NegativeBaseHeight(f64),
+    /// The height field could not be loaded
+    HeightField(HeightFieldError)
+}
+
+impl From<MeshError> for TowerError {
+    fn from(error: MeshError) -> Self {
+        TowerError::Mesh(error)
+    }
+}
+
+impl From<HeightFieldError> for TowerError {
+    fn from(error: HeightFieldError) -> Self {
+        TowerError::HeightField(error)
+    }
+}
+
+impl fmt::Display for TowerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TowerError::Mesh(error) => write!(f, "{}", error),
+            TowerError::SelfIntersection { seed, face, profile, inset, limit } => write!(
+                f,
+                "profile {} on seed {} face {} insets the walls by {:.3} but they meet at {:.3}",
+                profile.as_deref().unwrap_or("(unnamed)"),
+                seed,
+                face,
+                inset,
+                limit
+            ),
+            TowerError::NegativeBaseHeight(height) => 
+                write!(f, "base_height must not be negative, got {}", height),
+            TowerError::HeightField(error) => write!(f, "{}", error)
+        }
+    }
+}
+
+/// Everything needed to build the tower for one face of the tiling
+pub struct TowerSpec<'a> {
+    /// Seed and face index within the seed in the tiling description
+    pub seed: usize,
+    pub face: usize,
+    pub base: Vec<Vec3>,
+    /// Index into the tiling's profiles, used to detect identical towers
+    pub profile_index: Option<usize>,
+    pub profile: Option<&'a Profile>,
+    pub material: usize,
+    pub shading: Shading,
+    pub scale: ProfileScale,
+    pub cap: CapStyle,
+    /// The only copy of the tiling the tower is placed in, for towers
+    /// that differ between copies. None places it in every copy.
+    pub instance: Option<usize>,
+    /// Orbit of the face under the symmetries of the tiling, see
+    /// coloring::orbit_classes()
+    pub orbit: usize
+}
+
+impl<'a> TowerSpec<'a> {
+    fn profile_name(&self) -> Option<String> {
+        self.profile.and_then(|p| p.name.clone())
+    }
+
+    /// Sample the profile and make sure it doesn't turn the tower
+    /// inside-out, see find_limit(). The (inset, limit) is returned too
+    /// so congruent towers can be checked without sampling again.
+    fn check_profile(
+        &self,
+        policy: IntersectionPolicy
+    ) -> Result<(Vec<ProfilePoint>, Option<InsetLimit>), TowerError> {
+        let points = self.profile
+            .map(|p| p.shape.sample())
+            .unwrap_or_default();
+        match self.find_limit(&points)? {
+            Some(limit) => Ok((self.apply_policy(points, limit, policy)?, Some(limit))),
+            None => Ok((points, None))
+        }
+    }
+
+    /// Check the profile against the base, and get (inset, limit) if it
+    /// goes too far.
+    ///
+    /// Every layer of the profile is inset from the base as
+    /// extrude_profile() would, and must stay a simple polygon winding
+    /// the same way as the base without flipping any edges. If a layer
+    /// fails, the limit is the largest inset that still works. In
+    /// proportional mode, this is the fraction of the way to the centroid
+    /// and is found by bisection. In skeleton mode, it is the distance to
+    /// the first skeleton event.
+    fn find_limit(&self, points: &[ProfilePoint]) -> Result<Option<InsetLimit>, TowerError> {
+        let normal = Mesh::compute_polygon_normal(&self.base)
+            .ok_or(MeshError::DegenerateFace(self.face))?;
+        let keeps_shape = |amount: f64| {
+            let inset = Mesh::inset_positions(&self.base, normal, self.scale.inset_mode, amount);
+            Mesh::inset_keeps_shape(&self.base, &inset, normal)
+        };
+
+        let first_failure = points.iter()
+            .map(|(center, _)| center * self.scale.center_step)
+            .find(|amount| !keeps_shape(*amount));
+        let failure = match first_failure {
+            Some(failure) => failure,
+            None => return Ok(None)
+        };
+
+        let limit = match self.scale.inset_mode {
+            InsetMode::Proportional => {
+                let mut limit = 0.0;
+                let mut outside = failure;
+                for _ in 0..LIMIT_ITERATIONS {
+                    let middle = 0.5 * (limit + outside);
+                    if keeps_shape(middle) {
+                        limit = middle;
+                    } else {
+                        outside = middle;
+                    }
+                }
+                limit
+            },
+            InsetMode::Skeleton => skeleton::first_event(&self.base, normal)
+        };
+
+        let max_center = points.iter()
+            .map(|(center, _)| *center)
+            .fold(0.0, f64::max);
+        Ok(Some((max_center * self.scale.center_step, limit)))
+    }
+
+    /// Handle a profile that goes past the limit for this tower
+    fn apply_policy(
+        &self,
+        points: Vec<ProfilePoint>,
+        (inset, limit): InsetLimit,
+        policy: IntersectionPolicy
+    ) -> Result<Vec<ProfilePoint>, TowerError> {
+        match policy {
+            IntersectionPolicy::Error => Err(self.intersection_error((inset, limit))),
+            IntersectionPolicy::Report => Ok(points),
+            IntersectionPolicy::Clamp => {
+                // Convert the limit back to profile units
+                let max_center = limit / self.scale.center_step * (1.0 - CLAMP_MARGIN);
+                Ok(points.iter()
+                    .map(|&(center, normal)| (center.min(max_center), normal))
+                    .collect())
+            }
+        }
+    }
+
+    fn intersection_error(&self, (inset, limit): InsetLimit) -> TowerError {
+        TowerError::SelfIntersection {
+            seed: self.seed,
+            face: self.face,
+            profile: self.profile_name(),
+            inset,
+            limit
+        }
+    }
+
+    /// Warnings for a profile that goes past its limit with the Report
+    /// policy
+    fn report(&self, limit: Option<InsetLimit>, policy: IntersectionPolicy) -> Vec<String> {
+        match limit {
+            Some(limit) if policy == IntersectionPolicy::Report => {
+                vec![self.intersection_error(limit).to_string()]
+            },
+            _ => Vec::new()
+        }
+    }
+}
+
+/// Where a tower came from in the tiling description
+struct TowerInfo {
+    seed: usize,
+    face: usize,
+    sides: usize,
+    profile: Option<String>,
+    instance: Option<usize>,
+    orbit: usize
+}
+
+impl TowerInfo {
+    fn new(spec: &TowerSpec) -> Self {
+        Self {
+            seed: spec.seed,
+            face: spec.face,
+            sides: spec.base.len(),
+            profile: spec.profile_name(),
+            instance: spec.instance,
+            orbit: spec.orbit
+        }
+    }
+
+    /// Name for the tower's node in the glTF output
+    fn name(&self) -> String {
+        let mut name = format!("Seed {} Face {}", self.seed, self.face);
+        if let Some(instance) = self.instance {
+            name = format!("{} Copy {}", name, instance);
+        }
+        match &self.profile {
+            Some(profile) => format!("{} ({})", name, profile),
+            None => name
+        }
+    }
+}
+
+/// Everything besides the shape of the base that determines the
+/// geometry of a tower
+#[derive(PartialEq)]
+struct TowerKey {
+    profile: Option<usize>,
+    material: usize,
+    shading: Shading,
+    scale: ProfileScale,
+    cap: CapStyle
+}
+
+impl TowerKey {
+    fn new(spec: &TowerSpec) -> Self {
+        Self {
+            profile: spec.profile_index,
+            material: spec.material,
+            shading: spec.shading,
+            scale: spec.scale,
+            cap: spec.cap
+        }
+    }
+}
+
+/// Towers are stored as prototypes, one for each group of congruent
+/// towers. Each tower places a prototype in world space.
 pub struct TowerTiling {
+    // Prototype meshes, centered on the centroid of their bases
     meshes: Vec<Mesh>,
-    materials: Vec<usize>
+    materials: Vec<usize>,
+    shading: Vec<Shading>,
+    keys: Vec<TowerKey>,
+    // Base of each prototype relative to its centroid
+    bases: Vec<Vec<Vec3>>,
+    // Set for prototypes whose profile goes too far
+    limits: Vec<Option<InsetLimit>>,
+
+    info: Vec<TowerInfo>,
+    // (prototype, transform) for each tower. The transform is a rotation
+    // about z and a translation to the centroid of the tower's base.
+    placements: Vec<(usize, Instance)>
 }
 
 impl TowerTiling {
@@ -13,62 +307,824 @@ impl TowerTiling {
         Self {
             meshes: Vec::new(),
             materials: Vec::new(),
+            shading: Vec::new(),
+            keys: Vec::new(),
+            bases: Vec::new(),
+            limits: Vec::new(),
+            info: Vec::new(),
+            placements: Vec::new()
         }
     }
 
-    pub fn add_tower(&mut self, base: &[Vec3], profile: &[(i32, i32)], material: usize) {
+    /// Find an existing prototype that is congruent to the tower, and
+    /// the rotation that maps it onto the tower
+    fn find_prototype(&self, key: &TowerKey, base: &[Vec3]) -> Option<(usize, f64)> {
+        self.keys.iter()
+            .zip(self.bases.iter())
+            .enumerate()
+            .filter(|(_, (prototype_key, _))| *prototype_key == key)
+            .find_map(|(i, (_, prototype_base))| {
+                Mesh::find_rotation(prototype_base, base).map(|rotation| (i, rotation))
+            })
+    }
+
+    /// Add a tower, and get any warnings about it
+    pub fn add_tower(
+        &mut self,
+        spec: &TowerSpec,
+        policy: IntersectionPolicy
+    ) -> Result<Vec<String>, TowerError> {
+        let scale = &spec.scale;
+        if scale.base_height < 0.0 {
+            return Err(TowerError::NegativeBaseHeight(scale.base_height));
+        }
+
+        let centroid = Mesh::compute_centroid(&spec.base);
+        let base: Vec<Vec3> = spec.base.iter()
+            .map(|position| vec3::sub(*position, centroid))
+            .collect();
+        let key = TowerKey::new(spec);
+        self.info.push(TowerInfo::new(spec));
+
+        // Congruent towers reuse the same mesh. They have the same limit,
+        // but each one reports its own seed and face.
+        if let Some((prototype, rotation)) = self.find_prototype(&key, &base) {
+            if let Some(limit) = self.limits[prototype] {
+                spec.apply_policy(Vec::new(), limit, policy)?;
+            }
+            let placement = Instance {
+                rotation,
+                ..Instance::at(centroid)
+            };
+            self.placements.push((prototype, placement));
+            return Ok(spec.report(self.limits[prototype], policy));
+        }
+
+        let (points, limit) = spec.check_profile(policy)?;
+        let warnings = spec.report(limit, policy);
+        let layer_materials = spec.profile.and_then(|p| p.layer_materials.as_ref());
+        let base_material = layer_materials.and_then(|m| m.base);
+        let step_materials = layer_materials
+            .zip(spec.profile)
+            .map(|(m, p)| m.step_materials(&p.shape.steps()))
+            .unwrap_or_default();
+        let cap_material = layer_materials.and_then(|m| m.cap);
+
         let mut mesh = Mesh::new();
+        mesh.material = base_material;
 
-        // TODO: for glTF exporting, consider centering on
-        // the centroid and using a matrix transform.
         let vertices: Vec<usize> = base.iter()
             .map(|position| mesh.add_vertex(*position))
             .collect();
 
         let vertices_reversed: Vec<usize> = vertices.iter()
             .rev()
-            .map(|x| *x)
+            .copied()
             .collect();
         let _ = mesh.add_face(&vertices_reversed);
         let top_face = mesh.add_face(&vertices);
 
         // must be called before extrude()
-        mesh.compute_face_normals();
-        let top_face = mesh.extrude(top_face, 0.2);
+        mesh.compute_face_normals()?;
+        let mut top_face = top_face;
+        if scale.base_height > 0.0 {
+            top_face = mesh.extrude(top_face, scale.base_height);
 
-        // Compute normals for the new faces
-        mesh.compute_face_normals();
+            // Compute normals for the new faces
+            mesh.compute_face_normals()?;
+        }
+
+        mesh.material = None;
+        if let Some(profile) = spec.profile {
+            if !points.is_empty() {
+                top_face = mesh.extrude_profile(top_face, &points, scale, &step_materials);
+                mesh.compute_face_normals()?;
+            }
 
-        if profile.len() > 0 {
-            mesh.extrude_profile(top_face, profile);
+            if let Some((amount, depth)) = profile.bevel {
+                top_face = mesh.bevel(top_face, amount, depth)?;
+                mesh.compute_face_normals()?;
+            }
+        }
+
+        mesh.material = cap_material;
+        mesh.faces[top_face].material = cap_material;
+        mesh.add_cap(top_face, spec.cap)?;
+
+        if let Some(profile) = spec.profile {
+            for _ in 0..profile.subdivisions.unwrap_or(0) {
+                mesh = mesh.subdivide();
+            }
         }
 
         // Make sure normals exist for export purposes
-        mesh.compute_face_normals();
+        mesh.compute_face_normals()?;
 
+        self.placements.push((self.meshes.len(), Instance::at(centroid)));
         self.meshes.push(mesh);
-        self.materials.push(material);
+        self.materials.push(spec.material);
+        self.shading.push(spec.shading);
+        self.keys.push(key);
+        self.bases.push(base);
+        self.limits.push(limit);
+
+        Ok(warnings)
     }
 
+    /// Save each prototype mesh as an OBJ file for debugging. Prototypes
+    /// of towers built for a single copy of the tiling are skipped, since
+    /// a height field makes one for every tower in every copy.
     #[allow(dead_code)]
     pub fn save_obj(&self, fname_prefix: &str) {
+        let shared: HashSet<usize> = self.placements.iter()
+            .zip(self.info.iter())
+            .filter(|(_, info)| info.instance.is_none())
+            .map(|((prototype, _), _)| *prototype)
+            .collect();
         for (i, mesh) in self.meshes.iter().enumerate() {
+            if !shared.contains(&i) {
+                continue;
+            }
             let fname = format!("{}_{}.obj", fname_prefix, i);
             mesh.save_obj(&fname);
         }
     }
 
-    pub fn save_glb(&self, fname: &str, tiling: &IntegerTiling, instance_offsets: Vec<Vec3>) {
+    /// Copies of the tiling a tower is placed in
+    fn tower_copies(&self, tower: usize, instance_count: usize) -> Vec<usize> {
+        match self.info[tower].instance {
+            Some(k) => vec![k],
+            None => (0..instance_count).collect()
+        }
+    }
+
+    /// Save the towers as glTF in the format from the export options, and
+    /// get any warnings about the export
+    pub fn save_gltf(
+        &self,
+        fname: &str,
+        tiling: &IntegerTiling,
+        instances: &[Instance]
+    ) -> Result<Vec<String>, GltfError> {
+        let options = &tiling.export;
+        let mut warnings = Vec::new();
         let mut gltf = Gltf::new();
-        gltf.add_materials(tiling.materials.clone());
+        gltf.set_quantization(options.quantize);
+        gltf.add_materials(tiling.materials.clone())?;
 
-        gltf.add_instances(instance_offsets);
+        let merge = options.merge_materials;
 
-        for (i, mesh) in self.meshes.iter().enumerate() {
-            let material = self.materials[i];
-            gltf.add_primitive(mesh, material);
+        // Walls are unwrapped around the axis through the centroid of
+        // each prototype's base
+        let needs_uvs = options.uvs || tiling.materials.iter().any(|m| m.has_textures());
+        let prototype_primitives: Vec<Vec<(usize, Triangles)>> = self.meshes.iter()
+            .enumerate()
+            .map(|(i, mesh)| {
+                let base = &self.bases[i];
+                let uvs = Mesh::compute_polygon_normal(base)
+                    .filter(|_| needs_uvs)
+                    .map(|axis| UvMapping {
+                        scale: options.uv_scale,
+                        center: Mesh::compute_centroid(base),
+                        axis
+                    });
+                mesh.triangulate_by_material(self.shading[i], self.materials[i], uvs)
+            })
+            .collect();
+        let mut tower_primitives: Vec<Vec<(usize, Triangles)>> = self.placements.iter()
+            .map(|(prototype, placement)| {
+                prototype_primitives[*prototype].iter()
+                    .map(|(material, triangles)| (*material, placement.transform(triangles)))
+                    .collect()
+            })
+            .collect();
+
+        if options.metadata {
+            // One feature per material of each tower, in order
+            let mut feature_id = 0;
+            for primitives in tower_primitives.iter_mut() {
+                for (_, triangles) in primitives.iter_mut() {
+                    triangles.feature_ids = vec![feature_id; triangles.positions.len()];
+                    feature_id += 1;
+                }
+            }
+            self.add_property_table(&mut gltf, &tower_primitives);
+        }
+
+        // Color of each copy of each tower. Towers start with the color of
+        // their first copy, and flattened copies get their own colors.
+        let mut copy_colors: HashMap<(usize, usize), Vec3> = HashMap::new();
+        if let Some(vertex_colors) = &options.vertex_colors {
+            let values = self.attribute_values(vertex_colors.attribute, &tower_primitives, instances);
+            let colors = vertex_colors.colors(&values.iter().map(|(_, value)| *value).collect::<Vec<f64>>());
+            copy_colors = values.into_iter().map(|(copy, _)| copy).zip(colors).collect();
+
+            let shared_copies = (0..self.info.len())
+                .any(|tower| self.tower_copies(tower, instances.len()).len() > 1);
+            if vertex_colors.attribute == ColorAttribute::Distance
+                && options.instancing == InstancingMode::Gpu
+                && shared_copies
+            {
+                warnings.push(String::from(
+                    "distance colors differ between copies of the tiling, which \
+                    needs flattened instancing, so vertex colors were left out"
+                ));
+                copy_colors.clear();
+            }
+
+            for (tower, primitives) in tower_primitives.iter_mut().enumerate() {
+                let first_copy = self.tower_copies(tower, instances.len()).first().copied();
+                let color = first_copy.and_then(|k| copy_colors.get(&(tower, k)));
+                for (_, triangles) in primitives.iter_mut() {
+                    triangles.colors = color
+                        .map(|color| vec![*color; triangles.positions.len()])
+                        .unwrap_or_default();
+                }
+            }
+        }
+        let place_copy = |tower: usize, k: usize, instance: &Instance, triangles: &Triangles| {
+            let mut placed = instance.transform(triangles);
+            if let Some(color) = copy_colors.get(&(tower, k)) {
+                placed.colors = vec![*color; placed.positions.len()];
+            }
+            placed
+        };
+
+        // Towers that are kept in the same instances can share a node.
+        // Without a region, every tower is kept in every instance it is
+        // placed in.
+        let per_tower = options.nodes == NodeLayout::PerTower;
+        let mut groups: Vec<(Vec<usize>, Vec<usize>)> = Vec::new();
+        // Towers cut by the region, already in world space
+        let mut cut_primitives: Vec<(usize, Triangles)> = Vec::new();
+        for (tower, primitives) in tower_primitives.iter().enumerate() {
+            let copies = self.tower_copies(tower, instances.len());
+            let kept = match &tiling.region {
+                Some(region) => place_in_region(
+                    primitives,
+                    copies,
+                    region,
+                    &mut cut_primitives,
+                    merge,
+                    |k, triangles| place_copy(tower, k, &instances[k], triangles)
+                ),
+                None => copies
+            };
+            if kept.is_empty() {
+                continue;
+            }
+
+            let existing = groups.iter_mut()
+                .find(|(group_instances, _)| !per_tower && *group_instances == kept);
+            match existing {
+                Some((_, towers)) => towers.push(tower),
+                None => groups.push((kept, vec![tower]))
+            }
+        }
+
+        // Sharing prototype meshes needs every instance to commute with
+        // the rotation of the placements. Feature IDs and colors are per
+        // tower, so they can't be shared either.
+        let deduplicate = options.deduplicate
+            && options.instancing == InstancingMode::Gpu
+            && !options.metadata
+            && options.vertex_colors.is_none()
+            && instances.iter().all(|instance| instance.is_uniform_xy());
+        if options.deduplicate && !deduplicate {
+            warnings.push(String::from(
+                "towers can only be deduplicated with GPU instancing, \
+                a uniform xy scale, no metadata and no vertex colors"
+            ));
         }
 
-        gltf.save(fname);
+        match (options.instancing, options.nodes) {
+            (InstancingMode::Gpu, NodeLayout::Merged) if deduplicate => {
+                // One node per prototype, instanced once per tower per copy
+                let mut prototype_instances: Vec<Vec<Instance>> = vec![Vec::new(); self.meshes.len()];
+                for (kept, towers) in groups {
+                    for tower in towers {
+                        let (prototype, placement) = &self.placements[tower];
+                        for k in kept.iter() {
+                            prototype_instances[*prototype].push(instances[*k].compose(placement));
+                        }
+                    }
+                }
+
+                let primitives = prototype_primitives.into_iter().zip(prototype_instances);
+                for (i, (primitives, tower_instances)) in primitives.enumerate() {
+                    if tower_instances.is_empty() {
+                        continue;
+                    }
+
+                    let mesh = gltf.add_mesh(primitives);
+                    let name = format!("Tower Tiling {}", i);
+                    gltf.add_node(&name, mesh, None, Some(tower_instances));
+                }
+            },
+            (InstancingMode::Gpu, NodeLayout::Merged) => {
+                let group_count = groups.len();
+                for (i, (kept, towers)) in groups.into_iter().enumerate() {
+                    let mut primitives = Vec::new();
+                    for tower in towers {
+                        for (material, triangles) in std::mem::take(&mut tower_primitives[tower]) {
+                            add_primitive(&mut primitives, material, triangles, merge);
+                        }
+                    }
+
+                    let name = if group_count == 1 {
+                        String::from("Tower Tiling")
+                    } else {
+                        format!("Tower Tiling {}", i)
+                    };
+                    let mesh = gltf.add_mesh(primitives);
+                    let group_instances = kept.iter()
+                        .map(|k| instances[*k].clone())
+                        .collect();
+                    gltf.add_node(&name, mesh, None, Some(group_instances));
+                }
+            },
+            (InstancingMode::Gpu, NodeLayout::PerTower) => {
+                // Meshes added so far for each prototype when deduplicating
+                let mut prototype_meshes: Vec<Option<usize>> = vec![None; self.meshes.len()];
+                for (kept, towers) in groups {
+                    let tower = towers[0];
+                    let (prototype, placement) = &self.placements[tower];
+                    let centroid = placement.translation;
+
+                    let (mesh, node, tower_instances) = if deduplicate {
+                        let mesh = *prototype_meshes[*prototype].get_or_insert_with(|| {
+                            gltf.add_mesh(prototype_primitives[*prototype].clone())
+                        });
+                        let tower_instances = kept.iter()
+                            .map(|k| instances[*k].compose(placement).under_node(placement))
+                            .collect();
+                        (mesh, placement.clone(), tower_instances)
+                    } else {
+                        let to_local = Instance::at(vec3::scale(centroid, -1.0));
+                        let primitives = tower_primitives[tower].iter()
+                            .map(|(material, triangles)| (*material, to_local.transform(triangles)))
+                            .collect();
+                        let tower_instances = kept.iter()
+                            .map(|k| instances[*k].relative_to(centroid))
+                            .collect();
+                        (gltf.add_mesh(primitives), Instance::at(centroid), tower_instances)
+                    };
+
+                    gltf.add_node(&self.info[tower].name(), mesh, Some(node), Some(tower_instances));
+                }
+            },
+            (InstancingMode::Flattened, NodeLayout::Merged) => {
+                let mut primitives = Vec::new();
+                for (kept, towers) in groups {
+                    for tower in towers {
+                        for (material, triangles) in tower_primitives[tower].iter() {
+                            let mut baked = Triangles::new();
+                            for k in kept.iter() {
+                                baked.append(place_copy(tower, *k, &instances[*k], triangles));
+                            }
+                            add_primitive(&mut primitives, *material, baked, merge);
+                        }
+                    }
+                }
+                for (material, triangles) in std::mem::take(&mut cut_primitives) {
+                    add_primitive(&mut primitives, material, triangles, merge);
+                }
+
+                let mesh = gltf.add_mesh(primitives);
+                gltf.add_node("Tower Tiling", mesh, None, None);
+            },
+            (InstancingMode::Flattened, NodeLayout::PerTower) => {
+                for (kept, towers) in groups {
+                    let tower = towers[0];
+                    let centroid = self.placements[tower].1.translation;
+                    let mut primitives = Vec::new();
+                    for (material, triangles) in tower_primitives[tower].iter() {
+                        let mut baked = Triangles::new();
+                        for k in kept.iter() {
+                            // Bake in world space, then move the origin
+                            // to the centroid
+                            let mut instance = instances[*k].clone();
+                            instance.translation = vec3::sub(instance.translation, centroid);
+                            baked.append(place_copy(tower, *k, &instance, triangles));
+                        }
+                        add_primitive(&mut primitives, *material, baked, merge);
+                    }
+
+                    let mesh = gltf.add_mesh(primitives);
+                    gltf.add_node(&self.info[tower].name(), mesh, Some(Instance::at(centroid)), None);
+                }
+            }
+        }
+
+        if !cut_primitives.is_empty() {
+            let mesh = gltf.add_mesh(cut_primitives);
+            gltf.add_node("Tower Tiling (cut)", mesh, None, None);
+        }
+
+        gltf.save_as(fname, options.format)?;
+        Ok(warnings)
     }
-}
\ No newline at end of file
+
+    /// Describe each tower in a property table, one row per feature ID.
+    /// A tower has a feature for each of its materials, so the rows
+    /// repeat the tower's properties with a different material.
+    fn add_property_table(&self, gltf: &mut Gltf, tower_primitives: &[Vec<(usize, Triangles)>]) {
+        let heights = tower_heights(tower_primitives);
+        let rows: Vec<(&TowerInfo, usize, f64)> = self.info.iter()
+            .zip(tower_primitives)
+            .zip(heights)
+            .flat_map(|((info, primitives), height)| {
+                primitives.iter().map(move |(material, _)| (info, *material, height))
+            })
+            .collect();
+
+        gltf.add_property_table("tower", vec![
+            ("seed", PropertyValues::UInt32(rows.iter().map(|x| x.0.seed as u32).collect())),
+            ("face", PropertyValues::UInt32(rows.iter().map(|x| x.0.face as u32).collect())),
+            ("sides", PropertyValues::UInt32(rows.iter().map(|x| x.0.sides as u32).collect())),
+            ("profile", PropertyValues::String(
+                rows.iter().map(|x| x.0.profile.clone().unwrap_or_default()).collect()
+            )),
+            ("height", PropertyValues::Float32(rows.iter().map(|x| x.2 as f32).collect())),
+            ("material", PropertyValues::UInt32(rows.iter().map(|x| x.1 as u32).collect()))
+        ]);
+    }
+
+    /// Get the value of an attribute for each copy of each tower, as
+    /// ((tower, copy), value). Only distance differs between copies.
+    fn attribute_values(
+        &self,
+        attribute: ColorAttribute,
+        tower_primitives: &[Vec<(usize, Triangles)>],
+        instances: &[Instance]
+    ) -> Vec<((usize, usize), f64)> {
+        let heights = match attribute {
+            ColorAttribute::Height => tower_heights(tower_primitives),
+            _ => Vec::new()
+        };
+        let mut result = Vec::new();
+        for (tower, (prototype, placement)) in self.placements.iter().enumerate() {
+            for k in self.tower_copies(tower, instances.len()) {
+                let value = match attribute {
+                    ColorAttribute::Size => Mesh::compute_area(&self.bases[*prototype]),
+                    ColorAttribute::Height => heights[tower],
+                    ColorAttribute::Seed => self.info[tower].seed as f64,
+                    ColorAttribute::Distance => {
+                        let (x, y, _) = instances[k].transform_point(placement.translation);
+                        x.hypot(y)
+                    },
+                    ColorAttribute::Orbit => self.info[tower].orbit as f64
+                };
+                result.push(((tower, k), value));
+            }
+        }
+        result
+    }
+}
+
+/// Height of each tower from the bottom of its base to its highest point
+fn tower_heights(tower_primitives: &[Vec<(usize, Triangles)>]) -> Vec<f64> {
+    tower_primitives.iter()
+        .map(|primitives| {
+            let heights = primitives.iter()
+                .flat_map(|(_, triangles)| triangles.positions.iter())
+                .map(|(_, _, z)| *z);
+            let top = heights.clone().fold(-f64::INFINITY, f64::max);
+            let bottom = heights.fold(f64::INFINITY, f64::min);
+            top - bottom
+        })
+        .collect()
+}
+
+/// Add triangles to a list of (material, triangles) primitives, merging
+/// them into an existing primitive with the same material if requested
+fn add_primitive(
+    primitives: &mut Vec<(usize, Triangles)>,
+    material: usize,
+    triangles: Triangles,
+    merge: bool
+) {
+    if triangles.indices.is_empty() {
+        return;
+    }
+
+    let existing = primitives.iter_mut()
+        .find(|(existing_material, _)| *existing_material == material);
+    match existing {
+        Some((_, merged)) if merge => merged.append(triangles),
+        _ => primitives.push((material, triangles))
+    }
+}
+
+/// Find the copies where a tower is completely inside the region. If
+/// the region cuts towers, the parts of towers crossing the boundary
+/// are added to cut_primitives. place_copy moves triangles into a copy.
+fn place_in_region(
+    primitives: &[(usize, Triangles)],
+    copies: Vec<usize>,
+    region: &Region,
+    cut_primitives: &mut Vec<(usize, Triangles)>,
+    merge: bool,
+    place_copy: impl Fn(usize, &Triangles) -> Triangles
+) -> Vec<usize> {
+    let mut kept = Vec::new();
+    for k in copies {
+        let placed: Vec<(usize, Triangles)> = primitives.iter()
+            .map(|(material, triangles)| (*material, place_copy(k, triangles)))
+            .collect();
+        let tower: Vec<&Triangles> = placed.iter()
+            .map(|(_, triangles)| triangles)
+            .collect();
+
+        match region.coverage(&tower) {
+            Coverage::Inside => kept.push(k),
+            Coverage::Partial if region.mode == ClipMode::Cut => {
+                for (material, triangles) in placed {
+                    add_primitive(cut_primitives, material, region.clip(&triangles), merge);
+                }
+            },
+            _ => {}
+        }
+    }
+    kept
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::height::HeightModulation;
+
+    fn make_spec(profile: &Profile) -> TowerSpec<'_> {
+        TowerSpec {
+            seed: 0,
+            face: 1,
+            base: vec![
+                (0.0, 0.0, 0.0),
+                (2.0, 0.0, 0.0),
+                (2.0, 2.0, 0.0),
+                (0.0, 2.0, 0.0),
+            ],
+            profile_index: Some(0),
+            profile: Some(profile),
+            material: 0,
+            shading: Shading::Flat,
+            scale: ProfileScale {
+                center_step: 0.125,
+                normal_step: 0.1,
+                base_height: 0.2,
+                inset_mode: InsetMode::Proportional
+            },
+            cap: CapStyle::Flat,
+            instance: None,
+            orbit: 0
+        }
+    }
+
+    #[test]
+    fn profile_past_centroid() {
+        let profile: Profile = serde_json::from_str(
+            r#"{"name": "too-far", "offsets": [[4, 1], [6, 1]]}"#
+        ).unwrap();
+        let spec = make_spec(&profile);
+
+        match spec.check_profile(IntersectionPolicy::Error) {
+            Err(TowerError::SelfIntersection { seed, face, profile, inset, limit }) => {
+                assert_eq!((seed, face), (0, 1));
+                assert_eq!(profile.as_deref(), Some("too-far"));
+                assert!((limit - 1.0).abs() < 1e-6);
+                assert_eq!(inset, 1.25);
+            },
+            _ => panic!("expected a self-intersection error")
+        }
+
+        let reported = spec.check_profile(IntersectionPolicy::Report).unwrap().0;
+        assert_eq!(reported, vec![(4.0, 1.0), (10.0, 2.0)]);
+
+        let clamped = spec.check_profile(IntersectionPolicy::Clamp).unwrap().0;
+        assert_eq!(clamped[0], (4.0, 1.0));
+        assert!((clamped[1].0 - 0.99 / 0.125).abs() < 1e-6);
+    }
+
+    #[test]
+    fn profile_on_non_convex_base() {
+        let profile: Profile = serde_json::from_str(
+            r#"{"name": "setback", "offsets": [[4, 1], [3, 1]]}"#
+        ).unwrap();
+        let mut spec = make_spec(&profile);
+        // An L shape, its centroid is close to the reflex corner
+        spec.base = vec![
+            (0.0, 0.0, 0.0),
+            (4.0, 0.0, 0.0),
+            (4.0, 1.0, 0.0),
+            (1.0, 1.0, 0.0),
+            (1.0, 4.0, 0.0),
+            (0.0, 4.0, 0.0),
+        ];
+        let points = spec.check_profile(IntersectionPolicy::Error).unwrap().0;
+        assert_eq!(points, vec![(4.0, 1.0), (7.0, 2.0)]);
+
+        spec.scale.center_step = 0.2;
+        match spec.check_profile(IntersectionPolicy::Error) {
+            Err(TowerError::SelfIntersection { inset, limit, .. }) => {
+                assert!((inset - 1.4).abs() < 1e-12);
+                assert!((limit - 1.0).abs() < 1e-6);
+            },
+            _ => panic!("expected a self-intersection error")
+        }
+    }
+
+    #[test]
+    fn profile_inside_centroid() {
+        let profile: Profile = serde_json::from_str(
+            r#"{"offsets": [[7, 1], [-3, 1]]}"#
+        ).unwrap();
+        let spec = make_spec(&profile);
+
+        let points = spec.check_profile(IntersectionPolicy::Error).unwrap().0;
+        assert_eq!(points, vec![(7.0, 1.0), (4.0, 2.0)]);
+    }
+
+    #[test]
+    fn skeleton_profile_limits() {
+        let profile: Profile = serde_json::from_str(
+            r#"{"name": "setback", "offsets": [[4, 1], [6, 1]]}"#
+        ).unwrap();
+        let mut spec = make_spec(&profile);
+        spec.scale.inset_mode = InsetMode::Skeleton;
+
+        // The walls of the 2x2 square meet 1 unit in
+        match spec.check_profile(IntersectionPolicy::Error) {
+            Err(TowerError::SelfIntersection { inset, limit, .. }) => {
+                assert_eq!(inset, 1.25);
+                assert!((limit - 1.0).abs() < 1e-12);
+            },
+            _ => panic!("expected a self-intersection error")
+        }
+
+        // A notch in the top of a wider rectangle splits it before
+        // any wall shrinks away
+        spec.base = vec![
+            (0.0, 0.0, 0.0),
+            (6.0, 0.0, 0.0),
+            (6.0, 4.0, 0.0),
+            (4.0, 4.0, 0.0),
+            (3.0, 3.0, 0.0),
+            (2.0, 4.0, 0.0),
+            (0.0, 4.0, 0.0),
+        ];
+        let split = 3.0 / (1.0 + 2f64.sqrt());
+        match spec.check_profile(IntersectionPolicy::Error) {
+            Err(TowerError::SelfIntersection { limit, .. }) =>
+                assert!((limit - split).abs() < 1e-12),
+            _ => panic!("expected a self-intersection error")
+        }
+
+        spec.scale.center_step = 0.1;
+        let points = spec.check_profile(IntersectionPolicy::Error).unwrap().0;
+        assert_eq!(points, vec![(4.0, 1.0), (10.0, 2.0)]);
+    }
+
+    #[test]
+    fn tower_outside_height_field() {
+        let modulation: HeightModulation = serde_json::from_str(
+            r#"{"field": {"Radial": {"center": [100, 100], "radius": 1}}}"#
+        ).unwrap();
+        let sampler = modulation.sampler().unwrap();
+
+        let profile: Profile = serde_json::from_str(
+            r#"{"offsets": [[0, 1], [1, 1]]}"#
+        ).unwrap();
+        let mut spec = make_spec(&profile);
+        spec.scale.normal_step *= sampler.factor(Mesh::compute_centroid(&spec.base));
+        assert_eq!(spec.scale.normal_step, 0.025);
+
+        let mut towers = TowerTiling::new();
+        towers.add_tower(&spec, IntersectionPolicy::Error).unwrap();
+    }
+
+    #[test]
+    fn congruent_towers_share_a_prototype() {
+        let profile: Profile = serde_json::from_str(
+            r#"{"offsets": [[2, 1], [1, 2]]}"#
+        ).unwrap();
+        let mut towers = TowerTiling::new();
+        let spec = make_spec(&profile);
+        towers.add_tower(&spec, IntersectionPolicy::Error).unwrap();
+
+        // Same square, moved and starting from a different corner
+        let mut moved = make_spec(&profile);
+        moved.base = vec![
+            (5.0, 7.0, 0.0),
+            (5.0, 9.0, 0.0),
+            (3.0, 9.0, 0.0),
+            (3.0, 7.0, 0.0),
+        ];
+        towers.add_tower(&moved, IntersectionPolicy::Error).unwrap();
+
+        // A different material needs its own mesh
+        let mut recolored = make_spec(&profile);
+        recolored.material = 1;
+        towers.add_tower(&recolored, IntersectionPolicy::Error).unwrap();
+
+        assert_eq!(towers.meshes.len(), 2);
+        let prototypes: Vec<usize> = towers.placements.iter().map(|(p, _)| *p).collect();
+        assert_eq!(prototypes, vec![0, 0, 1]);
+        assert_eq!(towers.placements[1].1.translation, (4.0, 8.0, 0.0));
+    }
+
+    #[test]
+    fn congruent_towers_are_checked_separately() {
+        let profile: Profile = serde_json::from_str(
+            r#"{"offsets": [[4, 1], [6, 1]]}"#
+        ).unwrap();
+        let mut towers = TowerTiling::new();
+        let warnings = towers.add_tower(&make_spec(&profile), IntersectionPolicy::Report).unwrap();
+        assert!(towers.limits[0].is_some());
+        assert_eq!(warnings.len(), 1);
+
+        // The copy shares the prototype but still fails with its own
+        // seed and face
+        let mut copy = make_spec(&profile);
+        copy.seed = 3;
+        copy.face = 4;
+        match towers.add_tower(&copy, IntersectionPolicy::Error) {
+            Err(TowerError::SelfIntersection { seed, face, .. }) => assert_eq!((seed, face), (3, 4)),
+            _ => panic!("expected a self-intersection error")
+        }
+    }
+
+    #[test]
+    fn property_table_has_a_row_per_material() {
+        let profile: Profile = serde_json::from_str(
+            r#"{"offsets": [[2, 1], [1, 2]], "layer_materials": {"cap": 1}}"#
+        ).unwrap();
+        let mut towers = TowerTiling::new();
+        towers.add_tower(&make_spec(&profile), IntersectionPolicy::Error).unwrap();
+        let mut plain = make_spec(&profile);
+        plain.profile_index = None;
+        plain.profile = None;
+        towers.add_tower(&plain, IntersectionPolicy::Error).unwrap();
+
+        let tower_primitives: Vec<Vec<(usize, Triangles)>> = towers.placements.iter()
+            .map(|(i, _)| {
+                towers.meshes[*i].triangulate_by_material(towers.shading[*i], towers.materials[*i], None)
+            })
+            .collect();
+        let materials: Vec<usize> = tower_primitives.iter()
+            .flatten()
+            .map(|(material, _)| *material)
+            .collect();
+        assert_eq!(materials, vec![0, 1, 0]);
+
+        let mut gltf = Gltf::new();
+        towers.add_property_table(&mut gltf, &tower_primitives);
+        let json = gltf.to_json();
+        let metadata = &json["extensions"]["EXT_structural_metadata"];
+        assert_eq!(metadata["propertyTables"][0]["count"], 3);
+        assert!(metadata["schema"]["classes"]["tower"]["properties"]["material"].is_object());
+    }
+
+    #[test]
+    fn color_attributes() {
+        let profile: Profile = serde_json::from_str(
+            r#"{"offsets": [[2, 1], [1, 2]]}"#
+        ).unwrap();
+        let mut towers = TowerTiling::new();
+        towers.add_tower(&make_spec(&profile), IntersectionPolicy::Error).unwrap();
+
+        // Congruent, but with a different material
+        let mut recolored = make_spec(&profile);
+        recolored.seed = 2;
+        recolored.material = 1;
+        towers.add_tower(&recolored, IntersectionPolicy::Error).unwrap();
+
+        let mut triangle = make_spec(&profile);
+        triangle.base = vec![(0.0, 0.0, 0.0), (4.0, 0.0, 0.0), (0.0, 3.0, 0.0)];
+        triangle.orbit = 1;
+        triangle.instance = Some(1);
+        towers.add_tower(&triangle, IntersectionPolicy::Error).unwrap();
+
+        let instances = vec![Instance::at((0.0, 0.0, 0.0)), Instance::at((10.0, 0.0, 0.0))];
+        let no_primitives = vec![Vec::new(); 3];
+        let values = |attribute: ColorAttribute| -> Vec<f64> {
+            towers.attribute_values(attribute, &no_primitives, &instances).into_iter()
+                .map(|(_, value)| value)
+                .collect()
+        };
+        assert_eq!(values(ColorAttribute::Size), vec![4.0, 4.0, 4.0, 4.0, 6.0]);
+        assert_eq!(values(ColorAttribute::Seed), vec![0.0, 0.0, 2.0, 2.0, 0.0]);
+        assert_eq!(values(ColorAttribute::Orbit), vec![0.0, 0.0, 0.0, 0.0, 1.0]);
+
+        // Each copy has its own distance
+        let copies: Vec<(usize, usize)> = towers.attribute_values(ColorAttribute::Distance, &no_primitives, &instances)
+            .into_iter()
+            .map(|(copy, _)| copy)
+            .collect();
+        assert_eq!(copies, vec![(0, 0), (0, 1), (1, 0), (1, 1), (2, 1)]);
+        let distances = values(ColorAttribute::Distance);
+        assert!((distances[0] - 2f64.sqrt()).abs() < 1e-12);
+        assert!((distances[1] - 11f64.hypot(1.0)).abs() < 1e-12);
+        let (x, y, _) = Mesh::compute_centroid(&triangle.base);
+        assert!((distances[4] - (x + 10.0).hypot(y)).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,361 @@
+use serde::{de, Deserialize, Deserializer};
+
+use crate::mesh::Triangles;
+use crate::vec3::{self, Vec3};
+
+/// How to choose a variant for the copy of the tiling at lattice
+/// position (i, j)
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum VariationRule {
+    /// Variants cycle along diagonals, (i + j) mod n. With two variants
+    /// this is a checkerboard.
+    Checker,
+    /// Every copy in a row along the first translation uses the same
+    /// variant, j mod n
+    Rows,
+    /// Pseudo-random but repeatable choice
+    Random {
+        #[serde(default)]
+        seed: u32
+    }
+}
+
+impl VariationRule {
+    pub fn choose(&self, i: isize, j: isize, count: usize) -> usize {
+        let count = count as isize;
+        match self {
+            VariationRule::Checker => (i + j).rem_euclid(count) as usize,
+            VariationRule::Rows => j.rem_euclid(count) as usize,
+            VariationRule::Random { seed } => {
+                let mut h = (i as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15)
+                    ^ (j as u64).wrapping_mul(0xC2B2_AE3D_27D4_EB4F)
+                    ^ (*seed as u64);
+                h ^= h >> 33;
+                h = h.wrapping_mul(0xFF51_AFD7_ED55_8CCD);
+                h ^= h >> 33;
+                (h % count as u64) as usize
+            }
+        }
+    }
+}
+
+/// One way to transform a copy of the tiling
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct InstanceVariant {
+    /// Rotation in degrees about the z-axis through the origin of the
+    /// tiling
+    #[serde(default)]
+    pub rotation: f64,
+    /// Non-uniform scale, applied before the rotation. Negative
+    /// components mirror the copy, zero is not allowed.
+    #[serde(default = "default_scale", deserialize_with = "nonzero_scale")]
+    pub scale: Vec3,
+    /// Feature ID for the copy so viewers can style or swap materials.
+    /// Defaults to the index of the variant.
+    pub feature_id: Option<u32>
+}
+
+fn default_scale() -> Vec3 {
+    (1.0, 1.0, 1.0)
+}
+
+/// A zero scale would flatten the copy, and normals can't be transformed
+/// by the inverse
+fn nonzero_scale<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec3, D::Error> {
+    let scale = Vec3::deserialize(deserializer)?;
+    let (x, y, z) = scale;
+    if x == 0.0 || y == 0.0 || z == 0.0 {
+        return Err(de::Error::custom(format!("scale must not be zero, got {:?}", scale)));
+    }
+    Ok(scale)
+}
+
+/// Vary the GPU instances of the tiling instead of repeating identical
+/// copies
+#[derive(Deserialize)]
+pub struct InstanceVariation {
+    pub rule: VariationRule,
+    pub variants: Vec<InstanceVariant>
+}
+
+/// Transform and feature ID of one copy of the tiling
+#[derive(Clone, Debug, PartialEq)]
+pub struct Instance {
+    pub translation: Vec3,
+    /// Angle in radians about the z-axis
+    pub rotation: f64,
+    pub scale: Vec3,
+    pub feature_id: Option<u32>
+}
+
+impl Instance {
+    pub fn at(translation: Vec3) -> Self {
+        Self {
+            translation,
+            rotation: 0.0,
+            scale: default_scale(),
+            feature_id: None
+        }
+    }
+
+    /// Rotation as an (x, y, z, w) quaternion, the order glTF uses
+    pub fn quaternion(&self) -> [f64; 4] {
+        let half_angle = 0.5 * self.rotation;
+        [0.0, 0.0, half_angle.sin(), half_angle.cos()]
+    }
+
+    fn rotate(&self, v: Vec3) -> Vec3 {
+        let (x, y, z) = v;
+        let (sin, cos) = self.rotation.sin_cos();
+        (cos * x - sin * y, sin * x + cos * y, z)
+    }
+
+    pub fn transform_point(&self, point: Vec3) -> Vec3 {
+        let (x, y, z) = point;
+        let (sx, sy, sz) = self.scale;
+        vec3::add(self.translation, self.rotate((sx * x, sy * y, sz * z)))
+    }
+
+    /// Get the instance to use under a node translated to origin so the
+    /// copy ends up in the same place in world space. The node's
+    /// transform is applied after the instance transform.
+    pub fn relative_to(&self, origin: Vec3) -> Instance {
+        Instance {
+            translation: vec3::sub(self.transform_point(origin), origin),
+            ..self.clone()
+        }
+    }
+
+    /// Whether the scale is the same in x and y, so the instance
+    /// commutes with rotations about z
+    pub fn is_uniform_xy(&self) -> bool {
+        let (sx, sy, _) = self.scale;
+        sx == sy
+    }
+
+    /// Combine with a placement that has a rotation about z and no scale,
+    /// applied first. The scale must be uniform in xy to commute with the
+    /// rotation.
+    pub fn compose(&self, placement: &Instance) -> Instance {
+        Instance {
+            translation: self.transform_point(placement.translation),
+            rotation: self.rotation + placement.rotation,
+            ..self.clone()
+        }
+    }
+
+    /// Get the instance to use under a node with the given transform
+    /// (rotation about z and translation only) so the copy ends up in the
+    /// same place in world space.
+    pub fn under_node(&self, node: &Instance) -> Instance {
+        let inverse_rotation = Instance {
+            rotation: -node.rotation,
+            ..Instance::at((0.0, 0.0, 0.0))
+        };
+        Instance {
+            translation: inverse_rotation.rotate(vec3::sub(self.translation, node.translation)),
+            rotation: self.rotation - node.rotation,
+            ..self.clone()
+        }
+    }
+
+    /// Apply the instance transform to the triangles, the same way a
+    /// viewer would for a GPU instance: scale, then rotate, then
+    /// translate. A mirroring scale reverses the triangles so they still
+    /// face outwards, which viewers do by themselves for instances.
+    pub fn transform(&self, triangles: &Triangles) -> Triangles {
+        let (sx, sy, sz) = self.scale;
+        let positions = triangles.positions.iter()
+            .map(|&point| self.transform_point(point))
+            .collect();
+        // Normals transform by the inverse transpose, which for a scale
+        // is dividing by each component
+        let normals = triangles.normals.iter()
+            .map(|&(x, y, z)| vec3::normalize(self.rotate((x / sx, y / sy, z / sz))))
+            .collect();
+        let indices = if sx * sy * sz < 0.0 {
+            triangles.indices.chunks(3)
+                .flat_map(|triangle| triangle.iter().rev().copied())
+                .collect()
+        } else {
+            triangles.indices.clone()
+        };
+
+        Triangles {
+            positions,
+            normals,
+            indices,
+            uvs: triangles.uvs.clone(),
+            feature_ids: triangles.feature_ids.clone(),
+            colors: triangles.colors.clone()
+        }
+    }
+}
+
+impl InstanceVariation {
+    /// Make the instance for the copy of the tiling at lattice
+    /// position (i, j)
+    pub fn make_instance(&self, i: isize, j: isize, translation: Vec3) -> Instance {
+        if self.variants.is_empty() {
+            return Instance::at(translation);
+        }
+
+        let index = self.rule.choose(i, j, self.variants.len());
+        let variant = &self.variants[index];
+        Instance {
+            translation,
+            rotation: variant.rotation.to_radians(),
+            scale: variant.scale,
+            feature_id: Some(variant.feature_id.unwrap_or(index as u32))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checker_alternates() {
+        let rule = VariationRule::Checker;
+        assert_eq!(rule.choose(0, 0, 2), 0);
+        assert_eq!(rule.choose(1, 0, 2), 1);
+        assert_eq!(rule.choose(-1, -1, 2), 0);
+        assert_eq!(rule.choose(-1, 0, 3), 2);
+    }
+
+    #[test]
+    fn random_is_repeatable_and_in_range() {
+        let rule = VariationRule::Random { seed: 7 };
+        for i in -5..5 {
+            for j in -5..5 {
+                let choice = rule.choose(i, j, 3);
+                assert!(choice < 3);
+                assert_eq!(choice, rule.choose(i, j, 3));
+            }
+        }
+    }
+
+    #[test]
+    fn variants_set_instance_attributes() {
+        let variation: InstanceVariation = serde_json::from_str(r#"{
+            "rule": "Rows",
+            "variants": [
+                {},
+                {"rotation": 180, "scale": [1, 1, 2], "feature_id": 5}
+            ]
+        }"#).unwrap();
+
+        let plain = variation.make_instance(3, 0, (1.0, 0.0, 0.0));
+        assert_eq!(plain.scale, (1.0, 1.0, 1.0));
+        assert_eq!(plain.feature_id, Some(0));
+        assert_eq!(plain.quaternion(), [0.0, 0.0, 0.0, 1.0]);
+
+        let varied = variation.make_instance(3, 1, (1.0, 0.0, 0.0));
+        assert_eq!(varied.scale, (1.0, 1.0, 2.0));
+        assert_eq!(varied.feature_id, Some(5));
+        let [x, y, z, w] = varied.quaternion();
+        assert_eq!((x, y), (0.0, 0.0));
+        assert!((z - 1.0).abs() < 1e-12 && w.abs() < 1e-12);
+    }
+
+    #[test]
+    fn transform_matches_instance() {
+        let triangles = Triangles {
+            positions: vec![(1.0, 0.0, 0.0), (0.0, 1.0, 0.0), (0.0, 0.0, 1.0)],
+            normals: vec![(1.0, 0.0, 0.0); 3],
+            indices: vec![0, 1, 2],
+            uvs: Vec::new(),
+            feature_ids: Vec::new(),
+            colors: Vec::new()
+        };
+        let instance = Instance {
+            translation: (10.0, 0.0, 0.0),
+            rotation: 90f64.to_radians(),
+            scale: (2.0, 1.0, 3.0),
+            feature_id: None
+        };
+
+        let result = instance.transform(&triangles);
+        let expected = [(10.0, 2.0, 0.0), (9.0, 0.0, 0.0), (10.0, 0.0, 3.0)];
+        for (actual, expected) in result.positions.iter().zip(expected) {
+            assert!(vec3::length(vec3::sub(*actual, expected)) < 1e-12);
+        }
+        assert!(vec3::length(vec3::sub(result.normals[0], (0.0, 1.0, 0.0))) < 1e-12);
+        assert_eq!(result.indices, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn mirrored_instance_keeps_winding() {
+        let triangles = Triangles {
+            positions: vec![(0.0, 0.0, 0.0), (1.0, 0.0, 0.0), (0.0, 1.0, 0.0)],
+            normals: vec![(0.0, 0.0, 1.0); 3],
+            indices: vec![0, 1, 2],
+            uvs: Vec::new(),
+            feature_ids: Vec::new(),
+            colors: Vec::new()
+        };
+        let instance = Instance {
+            scale: (-1.0, 1.0, 1.0),
+            ..Instance::at((0.0, 0.0, 0.0))
+        };
+
+        // The triangle still winds counterclockwise around its normal
+        let result = instance.transform(&triangles);
+        assert_eq!(result.indices, vec![2, 1, 0]);
+        let [a, b, c] = [0, 1, 2].map(|i| result.positions[result.indices[i] as usize]);
+        let winding = vec3::cross(vec3::sub(b, a), vec3::sub(c, a));
+        assert!(vec3::dot(winding, result.normals[0]) > 0.0);
+    }
+
+    #[test]
+    fn zero_scale_is_rejected() {
+        let result: Result<InstanceVariant, _> = serde_json::from_str(r#"{"scale": [1, 0, 1]}"#);
+        assert!(result.is_err());
+        let mirrored: InstanceVariant = serde_json::from_str(r#"{"scale": [-1, 1, 1]}"#).unwrap();
+        assert_eq!(mirrored.scale, (-1.0, 1.0, 1.0));
+    }
+
+    #[test]
+    fn relative_to_keeps_world_position() {
+        let instance = Instance {
+            translation: (3.0, 1.0, 0.0),
+            rotation: 90f64.to_radians(),
+            scale: (2.0, 2.0, 1.0),
+            feature_id: None
+        };
+        let origin = (1.0, 1.0, 0.0);
+        let relative = instance.relative_to(origin);
+
+        let world = (2.0, 1.5, 0.5);
+        let local = vec3::sub(world, origin);
+        let expected = instance.transform_point(world);
+        let actual = vec3::add(origin, relative.transform_point(local));
+        assert!(vec3::length(vec3::sub(actual, expected)) < 1e-12);
+    }
+
+    #[test]
+    fn compose_and_under_node_keep_world_position() {
+        let instance = Instance {
+            translation: (3.0, 1.0, 0.0),
+            rotation: 0.5,
+            scale: (2.0, 2.0, 1.5),
+            feature_id: Some(1)
+        };
+        let placement = Instance {
+            rotation: 1.2,
+            ..Instance::at((1.0, -2.0, 0.0))
+        };
+        let point = (0.3, 0.7, 0.2);
+        let expected = instance.transform_point(placement.transform_point(point));
+
+        let composed = instance.compose(&placement);
+        let actual = composed.transform_point(point);
+        assert!(vec3::length(vec3::sub(actual, expected)) < 1e-12);
+
+        let local = composed.under_node(&placement);
+        let actual = placement.transform_point(local.transform_point(point));
+        assert!(vec3::length(vec3::sub(actual, expected)) < 1e-12);
+        assert_eq!(local.feature_id, Some(1));
+    }
+}
@@ -1,2 +1,48 @@
 /// TODO: make this an actual struct
-pub type Vec3 = (f64, f64, f64);
\ No newline at end of file
+pub type Vec3 = (f64, f64, f64);
+
+pub fn add(a: Vec3, b: Vec3) -> Vec3 {
+    let (ax, ay, az) = a;
+    let (bx, by, bz) = b;
+    (ax + bx, ay + by, az + bz)
+}
+
+pub fn sub(a: Vec3, b: Vec3) -> Vec3 {
+    let (ax, ay, az) = a;
+    let (bx, by, bz) = b;
+    (ax - bx, ay - by, az - bz)
+}
+
+pub fn scale(a: Vec3, factor: f64) -> Vec3 {
+    let (x, y, z) = a;
+    (factor * x, factor * y, factor * z)
+}
+
+pub fn dot(a: Vec3, b: Vec3) -> f64 {
+    let (ax, ay, az) = a;
+    let (bx, by, bz) = b;
+    ax * bx + ay * by + az * bz
+}
+
+pub fn cross(a: Vec3, b: Vec3) -> Vec3 {
+    let (ax, ay, az) = a;
+    let (bx, by, bz) = b;
+    (
+        ay * bz - az * by,
+        az * bx - ax * bz,
+        ax * by - ay * bx
+    )
+}
+
+pub fn length(a: Vec3) -> f64 {
+    dot(a, a).sqrt()
+}
+
+pub fn normalize(a: Vec3) -> Vec3 {
+    scale(a, 1.0 / length(a))
+}
+
+/// Linear interpolation from a (t = 0) to b (t = 1)
+pub fn lerp(a: Vec3, b: Vec3, t: f64) -> Vec3 {
+    add(a, scale(sub(b, a), t))
+}